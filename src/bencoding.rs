@@ -34,6 +34,9 @@ impl fmt::Display for Error {
 
 pub enum BEncoding {
     Dict(BTreeMap<String, BEncoding>),
+    /// Dictionary with keys that aren't UTF-8, like the info hashes of a
+    /// scrape response or the pieces roots of `piece layers`
+    BinaryDict(BTreeMap<Vec<u8>, BEncoding>),
     List(Vec<BEncoding>),
     Int(i64),
     Str(Vec<u8>),
@@ -41,9 +44,9 @@ pub enum BEncoding {
 
 impl BEncoding {
     pub fn decode_file(file: &str) -> Option<BEncoding> {
-        let mut f = File::open(&file).unwrap();
+        let mut f = File::open(&file).ok()?;
         let mut buf = vec![];
-        f.read_to_end(&mut buf).unwrap();
+        f.read_to_end(&mut buf).ok()?;
         Self::decode(buf)
     }
 
@@ -52,24 +55,41 @@ impl BEncoding {
         decode_next_type(&mut iter)
     }
 
+    /// Decodes the value at the start of the buffer, returning it along with
+    /// the number of bytes it took. Used for messages with trailing data.
+    pub fn decode_prefix(buf: &[u8]) -> Option<(BEncoding, usize)> {
+        let mut iter = buf.iter().peekable();
+        let value = decode_next_type(&mut iter)?;
+        Some((value, buf.len() - iter.len()))
+    }
+
     pub fn encode(val: &BEncoding) -> Vec<u8> {
         encode_next_type(val)
     }
 
-    fn to_int(&self) -> Result<i64, Error> {
+    pub fn to_int(&self) -> Result<i64, Error> {
         match *self {
             BEncoding::Int(val) => Ok(val),
             _ => Err(Error::NotAInt),
         }
     }
 
-    fn to_dict(&self) -> Result<&BTreeMap<String, BEncoding>, Error> {
+    pub fn to_dict(&self) -> Result<&BTreeMap<String, BEncoding>, Error> {
         match *self {
             BEncoding::Dict(ref map) => Ok(map),
             _ => Err(Error::NotADict),
         }
     }
 
+    /// Dictionary with its keys as bytes, whichever form it was decoded to
+    pub fn to_binary_dict(&self) -> Result<BTreeMap<Vec<u8>, &BEncoding>, Error> {
+        match *self {
+            BEncoding::Dict(ref map) => Ok(map.iter().map(|(k, v)| (k.clone().into_bytes(), v)).collect()),
+            BEncoding::BinaryDict(ref map) => Ok(map.iter().map(|(k, v)| (k.clone(), v)).collect()),
+            _ => Err(Error::NotADict),
+        }
+    }
+
     pub fn to_list(&self) -> Result<&Vec<BEncoding>, Error> {
         match *self {
             BEncoding::List(ref list) => Ok(list),
@@ -79,12 +99,12 @@ impl BEncoding {
 
     pub fn to_str(&self) -> Result<String, Error> {
         match *self {
-            BEncoding::Str(ref val) => Ok(str::from_utf8(val).unwrap().to_string()),
+            BEncoding::Str(ref val) => str::from_utf8(val).map(|s| s.to_string()).map_err(|_| Error::NotAStr),
             _ => Err(Error::NotAStr),
         }
     }
 
-    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
         match *self {
             BEncoding::Str(ref val) => Ok(val.clone()),
             _ => Err(Error::NotAStr),
@@ -155,7 +175,7 @@ impl fmt::Display for BEncoding {
                         try!(write!(f, ", "));
                     }
                     try!(write!(f, "{} : ", key));
-                    if key == "pieces" {
+                    if key == "pieces" || key == "pieces root" {
                         try!(write!(f, "REDACTED"));
                     } else {
                         try!(write!(f, "{}", value));
@@ -163,6 +183,9 @@ impl fmt::Display for BEncoding {
                 }
                 try!(write!(f, "}}"));
             },
+            BEncoding::BinaryDict(ref map) => {
+                try!(write!(f, "{{{} binary keys}}", map.len()));
+            },
         }
         Ok(())
     }
@@ -179,28 +202,28 @@ const LIST_START:u8 = b'l';
 const INT_START :u8 = b'i';
 const TYPE_END  :u8 = b'e';
 
+// The decoders return None on malformed input, as the data comes from untrusted peers and trackers
+
 fn decode_int(iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
     iter.next();
     let mut num = vec![];
-    while TYPE_END != **iter.peek().unwrap() {
-        num.push(*iter.next().unwrap());
+    while TYPE_END != **iter.peek()? {
+        num.push(*iter.next()?);
     }
     iter.next();
 
     if num.is_empty() {
-        panic!("invalid integer");
+        return None;
     }
-    Some(BEncoding::Int(str::from_utf8(&num).unwrap().parse::<i64>().unwrap()))
+    let num = str::from_utf8(&num).ok()?.parse::<i64>().ok()?;
+    Some(BEncoding::Int(num))
 }
 
 fn decode_list(mut iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
     iter.next();
     let mut list = vec![];
-    while TYPE_END != **iter.peek().unwrap() {
-        match decode_next_type(&mut iter) {
-            Some(x) => list.push(x),
-            None => panic!("list is invalid!"),
-        };
+    while TYPE_END != **iter.peek()? {
+        list.push(decode_next_type(&mut iter)?);
     }
     iter.next();
     Some(BEncoding::List(list))
@@ -209,42 +232,46 @@ fn decode_list(mut iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
 fn decode_dict(mut iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
     iter.next();
 
-    let mut map = BTreeMap::new();
-
-    while TYPE_END != **iter.peek().unwrap() {
-        let key = match decode_str(&mut iter) {
-            Some(BEncoding::Str(val)) => str::from_utf8(&val).unwrap().to_string(),
-            Some(_) => panic!("Can't have other types as key"),
-            None => panic!("Can't have other types as key"),
+    let mut entries = vec![];
+    while TYPE_END != **iter.peek()? {
+        let key = match decode_str(&mut iter)? {
+            BEncoding::Str(val) => val,
+            _ => return None,
         };
-        let value = decode_next_type(&mut iter).unwrap();
-        map.insert(key, value);
+        let value = decode_next_type(&mut iter)?;
+        entries.push((key, value));
     }
     iter.next();
-    Some(BEncoding::Dict(map))
+
+    if entries.iter().all(|&(ref key, _)| str::from_utf8(key).is_ok()) {
+        let map = entries.into_iter().map(|(key, value)| (String::from_utf8(key).unwrap(), value)).collect();
+        Some(BEncoding::Dict(map))
+    } else {
+        Some(BEncoding::BinaryDict(entries.into_iter().collect()))
+    }
 }
 
 fn decode_str(iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
     let mut len = vec![];
-    while b':' != **iter.peek().unwrap() {
-        len.push(*iter.next().unwrap());
+    while b':' != **iter.peek()? {
+        len.push(*iter.next()?);
     }
     iter.next();
-    let mut len = str::from_utf8(&len).unwrap().parse::<u64>().unwrap();
-    let mut result = vec![];
-    while len > 0 {
-        result.push(*iter.next().unwrap());
-        len-=1;
+    let len = str::from_utf8(&len).ok()?.parse::<usize>().ok()?;
+    if len > iter.len() {
+        return None;
     }
+    let result: Vec<u8> = iter.take(len).cloned().collect();
     Some(BEncoding::Str(result))
 }
 
 fn decode_next_type(mut iter: &mut Peekable<Iter<u8>>) -> Option<BEncoding> {
-    match **iter.peek().unwrap() {
+    match **iter.peek()? {
         DICT_START => decode_dict(&mut iter),
         LIST_START => decode_list(&mut iter),
         INT_START => decode_int(&mut iter),
-        _ => decode_str(&mut iter),
+        b'0'..=b'9' => decode_str(&mut iter),
+        _ => None,
     }
 }
 
@@ -285,9 +312,20 @@ fn encode_dict(map: &BTreeMap<String, BEncoding>) -> Vec<u8> {
     data
 }
 
+fn encode_binary_dict(map: &BTreeMap<Vec<u8>, BEncoding>) -> Vec<u8> {
+    let mut data = vec![DICT_START];
+    for (key, value) in map {
+        data.extend_from_slice(&encode_bytes(key));
+        data.extend_from_slice(&encode_next_type(value));
+    }
+    data.push(TYPE_END);
+    data
+}
+
 fn encode_next_type(value: &BEncoding) -> Vec<u8> {
     match value {
         &BEncoding::Dict(ref map) => encode_dict(map),
+        &BEncoding::BinaryDict(ref map) => encode_binary_dict(map),
         &BEncoding::List(ref list) => encode_list(list),
         &BEncoding::Int(val) => encode_int(val),
         &BEncoding::Str(ref val) => encode_bytes(val),
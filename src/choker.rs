@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use peer::Peer;
+use utils::*;
+
+/// Number of peers unchoked on the basis of their transfer rate
+const REGULAR_SLOTS: usize = 3;
+
+/// Interval between regular unchoke rounds
+const ROUND_INTERVAL: u64 = 10;
+
+/// Interval between optimistic unchoke rotations
+const OPTIMISTIC_INTERVAL: u64 = 30;
+
+/// Decides which peers are allowed to download from us (tit-for-tat).
+pub struct Choker {
+    last_round: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    optimistic: Option<SocketAddr>,
+}
+
+impl Choker {
+    pub fn new() -> Choker {
+        Choker {
+            last_round: None,
+            last_optimistic: None,
+            optimistic: None,
+        }
+    }
+
+    /// Runs an unchoke round if one is due.
+    ///
+    /// While downloading, peers are ranked by the rate at which they upload to us;
+    /// once seeding, by the rate at which we upload to them.
+    pub fn run(&mut self, peers: &mut HashMap<SocketAddr, Peer>, is_seeding: bool) {
+        if let Some(last_round) = self.last_round {
+            if last_round.elapsed() < Duration::from_secs(ROUND_INTERVAL) {
+                return;
+            }
+        }
+        self.last_round = Some(Instant::now());
+
+        for peer in peers.values_mut() {
+            peer.update_rates();
+        }
+
+        let mut candidates: Vec<(SocketAddr, f64)> = peers
+            .iter()
+            .filter(|&(_, peer)| peer.is_handshake_received && peer.is_interested_received)
+            .map(|(addr, peer)| (*addr, if is_seeding { peer.upload_rate } else { peer.download_rate }))
+            .collect();
+        sort_by_rate(&mut candidates);
+
+        let unchoked: Vec<SocketAddr> = candidates.iter().take(REGULAR_SLOTS).map(|&(addr, _)| addr).collect();
+        let others: Vec<SocketAddr> = candidates.iter().skip(REGULAR_SLOTS).map(|&(addr, _)| addr).collect();
+        self.rotate_optimistic(&others);
+
+        for (addr, peer) in peers.iter_mut() {
+            if !peer.is_handshake_received {
+                continue;
+            }
+            if unchoked.contains(addr) || self.optimistic == Some(*addr) {
+                peer.send_unchoke();
+            } else {
+                peer.send_choke();
+            }
+        }
+    }
+
+    fn rotate_optimistic(&mut self, candidates: &[SocketAddr]) {
+        let is_due = match self.last_optimistic {
+            Some(last) => last.elapsed() >= Duration::from_secs(OPTIMISTIC_INTERVAL),
+            None => true,
+        };
+        let is_valid = match self.optimistic {
+            Some(ref addr) => candidates.contains(addr),
+            None => false,
+        };
+        if is_valid && !is_due {
+            return;
+        }
+
+        self.optimistic = if candidates.is_empty() {
+            None
+        } else {
+            Some(candidates[(random() % candidates.len() as u64) as usize])
+        };
+        self.last_optimistic = Some(Instant::now());
+        if let Some(addr) = self.optimistic {
+            println!("choker: optimistic unchoke of {}", addr);
+        }
+    }
+}
+
+/// Orders the peers from the fastest to the slowest, the ones with a rate
+/// that isn't a number count as stalled.
+pub fn sort_by_rate(candidates: &mut [(SocketAddr, f64)]) {
+    let rate = |rate: f64| if rate.is_nan() { 0.0 } else { rate };
+    candidates.sort_by(|a, b| rate(b.1).total_cmp(&rate(a.1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use config::Config;
+    use extension::ExtendedHandshake;
+    use peer::Message;
+    use torrent::Torrent;
+
+    /// Interested peers 10.0.0.1 to 10.0.0.n, along with the receivers
+    /// their messages are sent to.
+    fn peers(n: u8) -> (HashMap<SocketAddr, Peer>, Vec<Receiver<Message>>) {
+        let torrent = Torrent::in_memory(&[0; 10], 16384, &Config::default());
+
+        let mut peers = HashMap::new();
+        let mut receivers = vec![];
+        for i in 1..n + 1 {
+            let addr = SocketAddr::from(([10, 0, 0, i], 6881));
+            let (tx, rx) = channel();
+            let mut peer = Peer::new(addr, &torrent, ExtendedHandshake::default(), tx, channel().0);
+            peer.is_handshake_received = true;
+            peer.is_interested_received = true;
+            peers.insert(addr, peer);
+            receivers.push(rx);
+        }
+        (peers, receivers)
+    }
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    fn unchoked(peers: &HashMap<SocketAddr, Peer>) -> Vec<u8> {
+        let mut unchoked: Vec<u8> = peers.iter()
+            .filter(|&(_, peer)| !peer.is_choke_sent)
+            .map(|(addr, _)| match addr.ip() { ::std::net::IpAddr::V4(ip) => ip.octets()[3], _ => 0 })
+            .collect();
+        unchoked.sort();
+        unchoked
+    }
+
+    #[test]
+    fn unchokes_the_fastest_and_one_optimistic_peer() {
+        let (mut peers, _receivers) = peers(6);
+        for i in 3..7 {
+            peers.get_mut(&addr(i)).unwrap().downloaded = 1000;
+        }
+        // The sixth one doesn't want anything from us
+        peers.get_mut(&addr(6)).unwrap().is_interested_received = false;
+
+        let mut choker = Choker::new();
+        choker.run(&mut peers, false);
+        let unchoked = unchoked(&peers);
+        let optimistic = match choker.optimistic {
+            Some(SocketAddr::V4(addr)) => addr.ip().octets()[3],
+            _ => panic!("no optimistic unchoke"),
+        };
+        assert!(optimistic == 1 || optimistic == 2);
+        let mut expected = vec![optimistic, 3, 4, 5];
+        expected.sort();
+        assert_eq!(unchoked, expected);
+    }
+
+    #[test]
+    fn ranks_by_upload_rate_when_seeding() {
+        // The first one only uploads to us, we only upload to the others
+        let (mut peers, _receivers) = peers(4);
+        peers.get_mut(&addr(1)).unwrap().downloaded = 1000;
+        for i in 2..5 {
+            peers.get_mut(&addr(i)).unwrap().uploaded = 1000;
+        }
+        let mut choker = Choker::new();
+        choker.run(&mut peers, true);
+        assert_eq!(choker.optimistic, Some(addr(1)));
+        assert_eq!(unchoked(&peers), vec![1, 2, 3, 4]);
+
+        peers.get_mut(&addr(1)).unwrap().is_interested_received = false;
+        choker.run(&mut peers, true);
+        // Rounds only run every 10 seconds
+        assert_eq!(choker.optimistic, Some(addr(1)));
+        choker.last_round = None;
+        choker.run(&mut peers, true);
+        assert_eq!(choker.optimistic, None);
+        assert_eq!(unchoked(&peers), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn nan_rates_are_sorted() {
+        let mut candidates = vec![(addr(1), 10.0), (addr(2), ::std::f64::NAN), (addr(3), 30.0)];
+        sort_by_rate(&mut candidates);
+        let order: Vec<_> = candidates.iter().map(|&(addr, _)| addr).collect();
+        assert_eq!(order, vec![addr(3), addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn rotates_the_optimistic_unchoke() {
+        let mut choker = Choker::new();
+        choker.rotate_optimistic(&[addr(1), addr(2)]);
+        let first = choker.optimistic.unwrap();
+
+        // Kept until the 30 seconds are over
+        for _ in 0..10 {
+            choker.rotate_optimistic(&[addr(1), addr(2)]);
+            assert_eq!(choker.optimistic, Some(first));
+        }
+        // Replaced right away once it isn't a candidate anymore
+        choker.rotate_optimistic(&[addr(3)]);
+        assert_eq!(choker.optimistic, Some(addr(3)));
+
+        let since = Instant::now() - Duration::from_secs(OPTIMISTIC_INTERVAL);
+        choker.last_optimistic = Some(since);
+        choker.rotate_optimistic(&[addr(3)]);
+        assert!(choker.last_optimistic.unwrap() > since);
+    }
+}
@@ -1,60 +1,239 @@
-use std::sync::mpsc::{channel, Sender};
+use std::cmp;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
 use std::net::{SocketAddr, TcpListener};
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use torrent::*;
 use tracker::*;
 use peer::*;
-use utils::*;
+use choker::{sort_by_rate, Choker};
+use picker::{DownloadMode, PiecePicker, Priority};
+use config::Config;
+use extension::{self, ExtendedHandshake, Registry};
+use metadata::MetadataExtension;
+use pex::PexExtension;
+use dht::Dht;
+use lsd::Lsd;
+use disk::{DiskIo, DiskEvent};
+
+/// Interval between saves of the fast-resume file
+const RESUME_INTERVAL: u64 = 60;
+
+/// Time given to each block of the pieces ahead of the read cursor in
+/// streaming mode, in milliseconds, about a second for a 256 KiB piece
+const BLOCK_DEADLINE: u64 = 64;
+
+/// Commands sent to a running Client
+pub enum Command {
+    Shutdown,
+    SetFilePriority(usize, Priority),
+    SetReadCursor(usize, usize),
+}
+
+/// Controls a Client that is running on another thread
+#[derive(Clone)]
+pub struct ClientHandle {
+    commands: Sender<Command>,
+    tracker: Tracker,
+}
+
+impl ClientHandle {
+    /// Status of the trackers of the torrent.
+    pub fn trackers(&self) -> Vec<TrackerStatus> {
+        self.tracker.status()
+    }
+
+    /// Changes the priority of a file, numbered from 0 as listed at startup.
+    pub fn set_file_priority(&self, file: usize, priority: Priority) {
+        let _ = self.commands.send(Command::SetFilePriority(file, priority));
+    }
+
+    /// Moves the read cursor of streaming mode to an offset in a file, the
+    /// pieces after it are downloaded first.
+    pub fn set_read_cursor(&self, file: usize, offset: usize) {
+        let _ = self.commands.send(Command::SetReadCursor(file, offset));
+    }
+
+    /// Stops the client after saving the resume data.
+    pub fn shutdown(&self) {
+        let _ = self.commands.send(Command::Shutdown);
+    }
+}
 
 pub struct Client {
+    config: Config,
     torrent: Torrent,
+    choker: Choker,
+    picker: PiecePicker,
+    registry: Registry,
+    commands: Receiver<Command>,
+    handle: ClientHandle,
+    last_resume_save: Instant,
+    stats: Arc<Mutex<TransferStats>>,
+    removed_uploaded: usize,
+    removed_downloaded: usize,
+    disk: Option<DiskIo>,
+    /// Offset in the torrent the pieces get deadlines from in streaming mode
+    read_cursor: usize,
 }
 
 impl Client {
-    pub fn new(file: &str) -> Client {
+    /// Creates a client for a .torrent file or a magnet link.
+    pub fn new(file: &str, config: Config) -> Client {
+        let torrent = if file.starts_with("magnet:?") {
+            Torrent::from_magnet(&file, &config).unwrap()
+        } else {
+            Torrent::new(&file, &config).unwrap()
+        };
+        Self::with_torrent(torrent, config)
+    }
+
+    /// Creates a client for a torrent built by the caller, e.g. with its own storage.
+    pub fn with_torrent(torrent: Torrent, config: Config) -> Client {
+        let mut picker = PiecePicker::new(torrent.no_of_pieces);
+        picker.set_mode(config.download_mode);
+        let mut registry = Registry::new();
+        registry.register(Box::new(MetadataExtension::new(torrent.metadata.clone())));
+        let (tx, rx) = channel();
+        let handle = ClientHandle { commands: tx, tracker: torrent.tracker.clone() };
         Client {
-            torrent: Torrent::new(&file).unwrap()
+            config: config,
+            torrent: torrent,
+            choker: Choker::new(),
+            picker: picker,
+            registry: registry,
+            commands: rx,
+            handle: handle,
+            last_resume_save: Instant::now(),
+            stats: Arc::new(Mutex::new(TransferStats::default())),
+            removed_uploaded: 0,
+            removed_downloaded: 0,
+            disk: None,
+            read_cursor: 0,
         }
     }
 
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+
     pub fn start(&mut self) {
         let (tx, rx) = channel();
         let (tpieces, rpieces) = channel();
+        let (tdisk, rdisk) = channel();
+        self.disk = Some(DiskIo::new(self.torrent.storage.clone(), self.config.disk_threads, self.config.read_cache_size, tdisk));
         let event_loop_channel = self.spawn_event_loop(tx);
-        {
+        self.registry.register(Box::new(PexExtension::new(event_loop_channel.clone())));
+        self.update_stats();
+        let (tracker_events, tracker_thread) = {
             let event_loop_channel = event_loop_channel.clone();
             let tracker = self.torrent.tracker.clone();
-            self.spawn_tracker_update(event_loop_channel, tracker);
+            self.spawn_tracker_update(event_loop_channel, tracker)
+        };
+        self.spawn_dht(event_loop_channel.clone());
+        self.spawn_lsd(event_loop_channel.clone());
+
+        // Reconnect to the peers saved in the resume data
+        for addr in &self.torrent.known_peers {
+            event_loop_channel.send(Message::AddPeer(*addr)).unwrap();
         }
 
         loop {
+            match self.commands.try_recv() {
+                Ok(Command::Shutdown) => {
+                    println!("client: shutting down");
+                    // Finish the queued writes before saving the resume data
+                    if let Some(mut disk) = self.disk.take() {
+                        disk.shutdown();
+                    }
+                    while let Ok(event) = rdisk.try_recv() {
+                        self.process_disk_event(event);
+                    }
+                    // Keep the blocks of the pieces that are only partly downloaded
+                    self.torrent.flush_write_buffers();
+                    self.torrent.save_resume();
+                    self.update_stats();
+                    self.torrent.tracker.stop();
+                    let _ = tracker_events.send(Event::Stopped);
+                    let _ = tracker_thread.join();
+                    return;
+                },
+                Ok(Command::SetFilePriority(file, priority)) => {
+                    if !self.torrent.set_file_priority(file, priority) {
+                        println!("client: no file {} in the torrent", file);
+                    }
+                },
+                Ok(Command::SetReadCursor(file, offset)) => {
+                    match self.torrent.file_offset(file, offset) {
+                        Some(cursor) => {
+                            println!("client: read cursor moved to piece {}", cursor / self.torrent.piece_size);
+                            self.read_cursor = cursor;
+                            self.picker.clear_deadlines();
+                        },
+                        None => println!("client: no offset {} in file {}", offset, file),
+                    }
+                },
+                Err(_) => {},
+            }
+
             // Push data packets received from event loop to each Peer
             while let Ok(packet) = rx.try_recv() {
                 match packet {
                     Message::AddPeer(addr) => {
-                        if !self.torrent.peers.contains_key(&addr) {
+                        if self.torrent.peers.len() >= self.config.max_peers {
+                            println!("client: too many peers, rejecting {}", addr);
+                            event_loop_channel.send(Message::Disconnect(addr)).unwrap();
+                        } else if !self.torrent.peers.contains_key(&addr) {
                             let event_loop_channel = event_loop_channel.clone();
                             let tpieces = tpieces.clone();
-                            let peer = Peer::new(addr, &self.torrent, event_loop_channel, tpieces);
+                            let extensions = self.extended_handshake();
+                            let peer = Peer::new(addr, &self.torrent, extensions, event_loop_channel, tpieces);
                             self.torrent.peers.insert(addr, peer);
                         }
                     }
                     Message::Data(addr, data) => {
-                        self.torrent.peers.get_mut(&addr).unwrap().read(data);
+                        if let Some(peer) = self.torrent.peers.get_mut(&addr) {
+                            peer.read(data);
+                        }
+                    }
+                    Message::Disconnect(addr) => {
+                        self.remove_peer(&addr);
                     }
-                    Message::Disconnect(_addr) => {}
                 }
             }
 
             // Process Peers
             self.process_peers();
 
-            // Write received blocks/pieces to files
+            // Buffer the received blocks, complete pieces are written by the disk threads
             while let Ok(packet) = rpieces.try_recv() {
-                let (piece, begin, block) = packet;
-                self.torrent.write_block(piece, begin, block);
+                let (piece, block, data) = packet;
+                self.torrent.block_received(piece, block);
+                if let Some((data, verified)) = self.torrent.buffer_block(piece, block, data) {
+                    if verified == Some(false) {
+                        self.torrent.finish_piece(piece, verified);
+                    } else if let Some(ref disk) = self.disk {
+                        disk.write_piece(piece, piece * self.torrent.piece_size, data, verified);
+                    }
+                }
+            }
+
+            while let Ok(event) = rdisk.try_recv() {
+                let was_complete = self.torrent.is_complete();
+                self.process_disk_event(event);
+                if !was_complete && self.torrent.is_complete() {
+                    self.torrent.save_resume();
+                    self.update_stats();
+                    let _ = tracker_events.send(Event::Completed);
+                }
+            }
+            self.update_stats();
+
+            if self.last_resume_save.elapsed().as_secs() >= RESUME_INTERVAL {
+                self.torrent.save_resume();
+                self.last_resume_save = Instant::now();
             }
 
             // Process Downloads
@@ -68,9 +247,12 @@ impl Client {
         println!("client: spawning event loop thread");
 
         let (tx, rx) = channel();
+        let port = self.config.listen_port;
         thread::spawn(move || {
-            let address = "0.0.0.0:56789".parse::<SocketAddr>().unwrap();
-            let socket = TcpListener::bind(&address).unwrap();
+            // Dual-stack where IPv6 is available, IPv4 only otherwise
+            let socket = TcpListener::bind(SocketAddr::from(([0u16; 8], port)))
+                .or_else(|_| TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))))
+                .unwrap();
 
             let mut handler = Handler::new(socket, sender, rx);
             handler.run().unwrap();
@@ -78,29 +260,191 @@ impl Client {
         tx
     }
 
-    fn spawn_tracker_update(&self, event_loop_channel: Sender<Message>, tracker: Tracker) {
+    /// Announces to the trackers until the Stopped event is sent through the returned channel.
+    fn spawn_tracker_update(&self, event_loop_channel: Sender<Message>, tracker: Tracker) -> (Sender<Event>, JoinHandle<()>) {
         println!("client: spawning tracker thread");
 
+        let (tx, rx) = channel();
+        let stats = self.stats.clone();
+        let handle = thread::spawn(move || {
+            let mut event = Event::Started;
+            loop {
+                let current = *stats.lock().unwrap();
+                // The peers are handed to the client as each tracker answers
+                let response = tracker.announce_with(event, &current, |response| {
+                    for peer in &response.peers {
+                        let _ = event_loop_channel.send(Message::AddPeer(*peer));
+                    }
+                });
+                if event == Event::Stopped {
+                    return;
+                }
+                for status in tracker.status() {
+                    println!("tracker: {}", status);
+                }
+
+                let wait = match response {
+                    Some(response) => {
+                        if response.peers.is_empty() {
+                            println!("torrent: no peers found!");
+                        }
+                        event = Event::None;
+                        response.next_interval()
+                    },
+                    // Keep the pending event and retry sooner
+                    None => 60,
+                };
+
+                let started = Instant::now();
+                let timeout = Duration::from_secs(wait);
+                loop {
+                    let elapsed = started.elapsed();
+                    if elapsed >= timeout {
+                        break;
+                    }
+                    match rx.recv_timeout(timeout - elapsed) {
+                        Ok(Event::Stopped) | Err(RecvTimeoutError::Disconnected) => {
+                            event = Event::Stopped;
+                            break;
+                        },
+                        Ok(Event::Completed) => {
+                            event = Event::Completed;
+                            break;
+                        },
+                        Ok(_) => {},
+                        Err(RecvTimeoutError::Timeout) => break,
+                    }
+                }
+            }
+        });
+        (tx, handle)
+    }
+
+    /// Refreshes the totals reported to the trackers.
+    fn update_stats(&mut self) {
+        let (mut uploaded, mut downloaded) = (self.removed_uploaded, self.removed_downloaded);
+        for peer in self.torrent.peers.values() {
+            uploaded += peer.uploaded;
+            downloaded += peer.downloaded;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.uploaded = uploaded as u64;
+        stats.downloaded = downloaded as u64;
+        stats.left = self.torrent.get_bytes_left() as u64;
+    }
+
+    /// Our extended handshake, sent to every peer that supports the extension protocol.
+    fn extended_handshake(&self) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: self.registry.ids(),
+            v: Some(format!("leech {}", env!("CARGO_PKG_VERSION"))),
+            p: Some(self.config.listen_port),
+            reqq: Some(extension::MAX_QUEUED_REQUESTS),
+            yourip: None,
+            metadata_size: Some(self.torrent.metadata.len()),
+        }
+    }
+
+    fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.registry.disconnect(addr);
+        if let Some(mut peer) = self.torrent.remove_peer(addr) {
+            println!("client: removing peer {}", addr);
+            self.removed_uploaded += peer.uploaded;
+            self.removed_downloaded += peer.downloaded;
+            // Only the pieces already counted by the picker are removed from availability
+            let uncounted = peer.take_new_pieces();
+            for (piece, &has) in peer.is_piece_downloaded.iter().enumerate() {
+                if has && !uncounted.contains(&piece) {
+                    self.picker.remove_have(piece);
+                }
+            }
+        }
+        self.torrent.seeders.retain(|seeder| seeder != addr);
+    }
+
+    fn spawn_dht(&self, event_loop_channel: Sender<Message>) {
+        if self.config.dht_port == 0 {
+            return;
+        }
+        println!("client: spawning dht thread");
+
+        let config = self.config.clone();
+        let info_hash = self.torrent.info_hash;
         thread::spawn(move || {
+            let mut dht = match Dht::new(&config) {
+                Ok(dht) => dht,
+                Err(err) => {
+                    println!("client: unable to start the dht: {}", err);
+                    return;
+                },
+            };
+            dht.set_peer_sink(event_loop_channel);
+            dht.bootstrap(&config.dht_bootstrap);
+
+            let mut last_lookup: Option<Instant> = None;
+            let mut last_save = Instant::now();
             loop {
-                let peer_addresses = tracker.get_peers_addresses();
-                if peer_addresses.is_empty() {
-                    println!("torrent: no peers found!");
-                } else {
-                    for peer in &peer_addresses {
-                        event_loop_channel.send(Message::AddPeer(*peer)).unwrap();
+                // Look for peers and announce ourselves every 15 mins once the routing table
+                // is filled, a lookup that no node answered is retried after a minute
+                let due = match last_lookup {
+                    Some(last) => {
+                        let unanswered = dht.is_lookup_done(&info_hash) && dht.lookup_responders(&info_hash) == 0;
+                        last.elapsed().as_secs() >= if unanswered { 60 } else { 15 * 60 }
+                    },
+                    None => dht.is_bootstrapped(),
+                };
+                if due {
+                    dht.get_peers(info_hash, Some(config.listen_port));
+                    last_lookup = Some(Instant::now());
+                }
+                dht.step();
+                if last_save.elapsed().as_secs() >= 5 * 60 {
+                    if let Err(err) = dht.save(&config.dht_state_path()) {
+                        println!("client: error while saving the dht state {}", err);
                     }
+                    last_save = Instant::now();
                 }
-                thread::sleep(Duration::from_secs(30 * 60)); // 30 mins
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+    }
+
+    fn spawn_lsd(&self, event_loop_channel: Sender<Message>) {
+        if !self.config.lsd {
+            return;
+        }
+        println!("client: spawning local service discovery thread");
+
+        let info_hash = self.torrent.info_hash;
+        let port = self.config.listen_port;
+        thread::spawn(move || {
+            let mut lsd = match Lsd::new(info_hash, port, event_loop_channel) {
+                Ok(lsd) => lsd,
+                Err(err) => {
+                    println!("client: unable to start local service discovery: {}", err);
+                    return;
+                },
+            };
+            loop {
+                lsd.step();
+                thread::sleep(Duration::from_millis(100));
             }
         });
     }
 
     fn process_peers(&mut self) {
-        let is_complete = self.torrent.is_complete();
+        let is_complete = self.torrent.is_wanted_complete();
         for (addr, peer) in &mut self.torrent.peers {
             peer.process_data();
 
+            for piece in peer.take_new_pieces() {
+                self.picker.add_have(piece);
+            }
+
+            for (id, payload) in peer.take_extended_messages() {
+                self.registry.dispatch(peer, id, &payload);
+            }
+
             if peer.is_timed_out() {
                 //FIXME: disconnect the peer
                 continue;
@@ -117,57 +461,299 @@ impl Client {
             }
 
             peer.send_keepalive();
+            self.registry.tick(peer);
 
             if !is_complete
                 && !peer.is_choke_received
-                && self.torrent.seeders.len() < 7
+                && self.torrent.seeders.len() < self.config.max_seeders
                 && !self.torrent.seeders.contains(&addr)
-            { // FIXME: make the number of seeders configurable
+            {
                 println!("client: adding {} to seeders", addr);
                 self.torrent.seeders.push(*addr);
             }
         }
+
+        self.choker.run(&mut self.torrent.peers, is_complete);
+        self.process_uploads();
+        self.process_hashes();
     }
 
-    fn process_downloads(&mut self) {
-        if self.torrent.is_complete() {
+    /// Serves the hash requests of the peers and fetches the piece layers of
+    /// v2 torrents that we don't know yet.
+    fn process_hashes(&mut self) {
+        if self.torrent.info_hash_v2.is_none() {
             return;
         }
 
-        for piece in 0..self.torrent.no_of_pieces {
-            // Check if the piece is already downloaded
-            if self.torrent.is_piece_downloaded[piece] {
-                continue;
+        let mut requests = vec![];
+        let mut received = vec![];
+        for (addr, peer) in &mut self.torrent.peers {
+            while let Some(request) = peer.next_hash_request() {
+                requests.push((*addr, request));
             }
+            for (request, hashes) in peer.take_hashes() {
+                received.push((*addr, request, hashes));
+            }
+        }
 
-            let block_count = self.torrent.get_block_count(piece);
-            // Go through the seeders and request pieces
-            for block in 0..block_count {
-                // Skip if the block is already downloaded
-                if self.torrent.is_block_downloaded[piece][block] {
-                    continue;
+        for (addr, request) in requests {
+            let hashes = self.torrent.serve_hashes(&request);
+            if let Some(peer) = self.torrent.peers.get_mut(&addr) {
+                match hashes {
+                    Some(hashes) => peer.send_hashes(&request, &hashes),
+                    None => peer.send_hash_reject(&request),
+                }
+            }
+        }
+        for (addr, request, hashes) in received {
+            if !self.torrent.add_hashes(&request, &hashes) {
+                println!("client: invalid hashes from {}", addr);
+            }
+        }
+
+        // One hash request in flight per peer
+        let mut in_flight: Vec<_> = self.torrent.peers.values().flat_map(|p| p.hash_requests_sent.clone()).collect();
+        let addrs: Vec<_> = self.torrent.peers.iter()
+            .filter(|&(_, p)| p.is_handshake_received && p.supports_v2 && p.hash_requests_sent.is_empty())
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in addrs {
+            let request = match self.torrent.next_hash_request(&in_flight) {
+                Some(request) => request,
+                None => break,
+            };
+            in_flight.push(request);
+            self.torrent.peers.get_mut(&addr).unwrap().send_hash_request(request);
+        }
+    }
+
+    fn process_uploads(&mut self) {
+        let mut requests = vec![];
+        for (addr, peer) in &mut self.torrent.peers {
+            // Serve a few blocks per peer each round so a single peer can't hog the loop
+            for _ in 0..4 {
+                match peer.next_request() {
+                    Some((piece, begin, length)) => requests.push((*addr, piece, begin, length)),
+                    None => break,
                 }
+            }
+        }
 
-                // Skip if the block is already requested
-                if self.torrent.is_block_requested(piece, block) {
-                    continue;
+        for (addr, piece, begin, length) in requests {
+            if !self.torrent.is_piece_downloaded[piece] || begin + length > self.torrent.get_piece_size(piece) {
+                self.torrent.peers.get_mut(&addr).unwrap().finish_request((piece, begin, length));
+                continue;
+            }
+            let offset = piece * self.torrent.piece_size;
+            let piece_size = self.torrent.get_piece_size(piece);
+            // Blocks that aren't cached are sent once the disk threads have read them
+            let block = match self.disk {
+                Some(ref disk) => disk.read_block(addr, piece, offset, piece_size, begin, length),
+                None => None,
+            };
+            if let (Some(block), Some(peer)) = (block, self.torrent.peers.get_mut(&addr)) {
+                if peer.finish_request((piece, begin, length)) {
+                    peer.send_piece(piece, begin, block);
+                }
+            }
+        }
+    }
+
+    fn process_disk_event(&mut self, event: DiskEvent) {
+        match event {
+            DiskEvent::Written { piece, verified, result } => match result {
+                Ok(_) => self.torrent.finish_piece(piece, verified),
+                Err(err) => {
+                    println!("client: error while writing piece {} {}", piece, err);
+                    self.torrent.finish_piece(piece, Some(false));
+                },
+            },
+            DiskEvent::Read { addr, piece, begin, length, result } => {
+                // The peer may have been choked or cancelled the block while it was read
+                let wanted = match self.torrent.peers.get_mut(&addr) {
+                    Some(peer) => peer.finish_request((piece, begin, length)),
+                    None => false,
+                };
+                match result {
+                    Ok(block) => {
+                        if wanted {
+                            self.torrent.peers.get_mut(&addr).unwrap().send_piece(piece, begin, block);
+                        }
+                    },
+                    Err(err) => println!("client: error while reading a block for {}: {}", addr, err),
                 }
+            },
+        }
+    }
+
+    fn process_downloads(&mut self) {
+        if self.torrent.is_wanted_complete() {
+            return;
+        }
+
+        let is_partial: Vec<bool> = (0..self.torrent.no_of_pieces)
+            .map(|piece| self.torrent.is_piece_partial(piece))
+            .collect();
+
+        let streaming = self.picker.mode() == DownloadMode::Streaming;
+        let seeders = if streaming {
+            self.update_deadlines();
+            self.seeders_by_rate()
+        } else {
+            self.torrent.seeders.clone()
+        };
+        // Only the faster half of the seeders is trusted with the pieces that have a deadline
+        let fast_seeders = (seeders.len() + 1) / 2;
+
+        // Go through the seeders and request blocks of the pieces they should give us
+        for (rank, addr) in seeders.into_iter().enumerate() {
+            let (mut requested, pieces) = match self.torrent.peers.get(&addr) {
+                Some(seeder) => {
+                    let requested = seeder.no_of_blocks_requested();
+                    if requested >= self.config.max_requests {
+                        continue;
+                    }
+                    let mut pieces = self.picker.pick(&seeder.is_piece_downloaded, &self.torrent.is_piece_downloaded, &is_partial, &self.torrent.piece_priorities);
+                    if rank >= fast_seeders {
+                        pieces.retain(|&piece| self.picker.deadline(piece).is_none());
+                    }
+                    (requested, pieces)
+                },
+                None => continue,
+            };
 
-                let size = self.torrent.get_block_size(piece, block);
-                for addr in &mut self.torrent.seeders {
-                    let seeder = self.torrent.peers.get_mut(addr).unwrap();
-                    if !seeder.is_piece_downloaded[piece] {
+            'pieces: for piece in pieces {
+                let block_count = self.torrent.get_block_count(piece);
+                for block in 0..block_count {
+                    // Skip if the block is already downloaded
+                    if self.torrent.is_block_downloaded[piece][block] {
                         continue;
                     }
 
-                    if seeder.no_of_blocks_requested() > 5 { // FIXME: make this configurable
+                    // Skip if the block is already requested
+                    if self.torrent.is_block_requested(piece, block) {
                         continue;
                     }
 
-                    seeder.send_request(piece, block * BLOCK_SIZE, size);
-                    break;
+                    if requested >= self.config.max_requests {
+                        break 'pieces;
+                    }
+
+                    self.torrent.request_block(&addr, piece, block);
+                    requested += 1;
+                }
+            }
+        }
+
+        if streaming {
+            self.process_deadlines();
+        }
+
+        if self.torrent.is_endgame() {
+            self.process_endgame();
+        }
+    }
+
+    /// Seeders ordered from the fastest to the slowest
+    fn seeders_by_rate(&self) -> Vec<SocketAddr> {
+        let mut seeders: Vec<(SocketAddr, f64)> = self.torrent.seeders.iter()
+            .filter_map(|addr| self.torrent.peers.get(addr).map(|peer| (*addr, peer.download_rate)))
+            .collect();
+        sort_by_rate(&mut seeders);
+        seeders.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Gives deadlines to the wanted pieces after the read cursor that aren't
+    /// downloaded yet, the further from the cursor the later. The pieces keep
+    /// their deadline until they are downloaded so that they can be late.
+    fn update_deadlines(&mut self) {
+        let first = cmp::min(self.read_cursor / self.torrent.piece_size, self.torrent.no_of_pieces);
+        let window: Vec<usize> = (first..self.torrent.no_of_pieces)
+            .filter(|&piece| !self.torrent.is_piece_downloaded[piece] && self.torrent.is_piece_wanted(piece))
+            .take(self.config.streaming_window)
+            .collect();
+
+        for piece in self.picker.deadline_pieces() {
+            if !window.contains(&piece) {
+                self.picker.remove_deadline(piece);
+            }
+        }
+        let mut deadline = Instant::now();
+        for &piece in &window {
+            deadline += self.piece_deadline(piece);
+            if self.picker.deadline(piece).is_none() {
+                self.picker.set_deadline(piece, deadline);
+            }
+        }
+    }
+
+    /// Time to download a piece in streaming mode, by its number of blocks
+    fn piece_deadline(&self, piece: usize) -> Duration {
+        Duration::from_millis(BLOCK_DEADLINE * self.torrent.get_block_count(piece) as u64)
+    }
+
+    /// Requests the missing blocks of the pieces past their deadline again from
+    /// the fastest seeder that has them and hasn't been asked yet. The deadline
+    /// is then pushed back, so each miss asks one more seeder per block.
+    fn process_deadlines(&mut self) {
+        let now = Instant::now();
+        let late: Vec<usize> = self.picker.deadline_pieces().into_iter()
+            .filter(|&piece| self.picker.deadline(piece).map_or(false, |deadline| deadline < now))
+            .collect();
+        if late.is_empty() {
+            return;
+        }
+
+        let seeders = self.seeders_by_rate();
+        let max_requests = self.config.max_requests;
+        for piece in late {
+            for block in 0..self.torrent.get_block_count(piece) {
+                if self.torrent.is_block_downloaded[piece][block] {
+                    continue;
+                }
+                let seeder = seeders.iter().filter_map(|addr| self.torrent.peers.get(addr)).find(|seeder| {
+                    seeder.is_piece_downloaded[piece]
+                        && !seeder.is_block_requested[piece][block]
+                        && seeder.no_of_blocks_requested() < max_requests
+                }).map(|seeder| seeder.addr());
+                if let Some(addr) = seeder {
+                    println!("client: piece {} is late, requesting block {} from {}", piece, block, addr);
+                    self.torrent.request_block(&addr, piece, block);
+                }
+            }
+            let deadline = now + self.piece_deadline(piece);
+            self.picker.set_deadline(piece, deadline);
+        }
+    }
+
+    /// Requests the remaining blocks from every seeder that has them, the
+    /// duplicates are cancelled when the first copy is written.
+    fn process_endgame(&mut self) {
+        for addr in self.torrent.seeders.clone() {
+            let mut blocks = vec![];
+            if let Some(seeder) = self.torrent.peers.get(&addr) {
+                let mut requested = seeder.no_of_blocks_requested();
+                'pieces: for piece in 0..self.torrent.no_of_pieces {
+                    if self.torrent.is_piece_downloaded[piece] || !self.torrent.is_piece_wanted(piece) || !seeder.is_piece_downloaded[piece] {
+                        continue;
+                    }
+                    for block in 0..self.torrent.get_block_count(piece) {
+                        if requested >= self.config.max_requests {
+                            break 'pieces;
+                        }
+                        if self.torrent.is_block_downloaded[piece][block] || seeder.is_block_requested[piece][block] {
+                            continue;
+                        }
+                        blocks.push((piece, block));
+                        requested += 1;
+                    }
                 }
             }
+
+            for (piece, block) in blocks {
+                println!("client: endgame request of piece {} block {} from {}", piece, block, addr);
+                self.torrent.request_block(&addr, piece, block);
+            }
         }
     }
 }
@@ -0,0 +1,334 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+use bencoding::{self, BEncoding};
+use disk::MAX_DISK_THREADS;
+use error::{Error, Result};
+use picker::{DownloadMode, Priority};
+use tracker::MAX_UDP_RETRANSMISSIONS;
+
+/// Session settings shared by the library and the binary
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory the torrent files are downloaded to
+    pub download_dir: PathBuf,
+    /// TCP port to accept peer connections on
+    pub listen_port: u16,
+    /// Local UDP port used to talk to UDP trackers, 0 picks any free port.
+    /// Trackers contacted while the port is taken use any free port instead.
+    pub tracker_port: u16,
+    /// Retransmissions of a UDP tracker request before giving up, waiting
+    /// 15 * 2^n seconds after each (BEP 15)
+    pub udp_retransmissions: u32,
+    /// Whether to announce to every tracker tier in parallel instead of the first that answers
+    pub announce_all_tiers: bool,
+    /// Maximum number of connected peers
+    pub max_peers: usize,
+    /// Maximum number of peers to download from at once
+    pub max_seeders: usize,
+    /// Maximum number of outstanding block requests per peer
+    pub max_requests: usize,
+    /// Where to save the .torrent file built from a magnet link's metadata
+    pub save_torrent: Option<PathBuf>,
+    /// UDP port of the DHT node, 0 disables the DHT
+    pub dht_port: u16,
+    /// Nodes used to join the DHT network
+    pub dht_bootstrap: Vec<String>,
+    /// File the DHT routing table is saved to, defaults to `.dht` in the download directory
+    pub dht_state: Option<PathBuf>,
+    /// Whether to look for peers on the local network
+    pub lsd: bool,
+    /// Info hashes the tracker server is restricted to, one hex hash per line
+    pub tracker_whitelist: Option<PathBuf>,
+    /// Number of threads doing the disk reads and writes
+    pub disk_threads: usize,
+    /// Bytes of pieces kept in memory to serve uploads
+    pub read_cache_size: usize,
+    /// Priorities of the files matching each selector, a file index or a
+    /// glob pattern, applied in order
+    pub file_priorities: Vec<(String, Priority)>,
+    /// Order in which the pieces are downloaded
+    pub download_mode: DownloadMode,
+    /// Number of pieces ahead of the read cursor that get deadlines in streaming mode
+    pub streaming_window: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            download_dir: PathBuf::from("/tmp"),
+            listen_port: 56789,
+            tracker_port: 0,
+            udp_retransmissions: MAX_UDP_RETRANSMISSIONS,
+            announce_all_tiers: false,
+            max_peers: 50,
+            max_seeders: 7,
+            max_requests: 6,
+            save_torrent: None,
+            dht_port: 6881,
+            dht_bootstrap: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            dht_state: None,
+            lsd: true,
+            tracker_whitelist: None,
+            disk_threads: 4,
+            read_cache_size: 32 * 1024 * 1024,
+            file_priorities: vec![],
+            download_mode: DownloadMode::RarestFirst,
+            streaming_window: 8,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the settings from a bencoded dictionary, missing keys keep their defaults.
+    ///
+    /// `d12:download-dir10:/srv/leech11:listen-porti6881ee`
+    pub fn from_file(path: &str) -> Result<Config> {
+        let mut buf = vec![];
+        let mut f = try!(fs::File::open(path));
+        try!(f.read_to_end(&mut buf));
+        let root = try!(BEncoding::decode(buf).ok_or(bencoding::Error::DecodeError));
+
+        let mut config = Config::default();
+        if let Ok(dir) = root.get_str("download-dir") {
+            config.download_dir = PathBuf::from(dir);
+        }
+        if let Some(port) = try!(get_number(&root, "listen-port")) {
+            config.listen_port = port;
+        }
+        if let Some(port) = try!(get_number(&root, "tracker-port")) {
+            config.tracker_port = port;
+        }
+        if let Some(retransmissions) = try!(get_number(&root, "udp-retransmissions")) {
+            config.udp_retransmissions = try!(check_udp_retransmissions("udp-retransmissions", retransmissions));
+        }
+        if let Ok(all) = root.get_int("announce-all-tiers") {
+            config.announce_all_tiers = all != 0;
+        }
+        if let Some(max) = try!(get_number(&root, "max-peers")) {
+            config.max_peers = try!(check_positive("max-peers", max));
+        }
+        if let Some(max) = try!(get_number(&root, "max-seeders")) {
+            config.max_seeders = try!(check_positive("max-seeders", max));
+        }
+        if let Some(max) = try!(get_number(&root, "max-requests")) {
+            config.max_requests = try!(check_positive("max-requests", max));
+        }
+        if let Some(port) = try!(get_number(&root, "dht-port")) {
+            config.dht_port = port;
+        }
+        if let Ok(nodes) = root.get_list("dht-bootstrap") {
+            config.dht_bootstrap = try!(nodes.iter().map(|n| n.to_str()).collect());
+        }
+        if let Ok(path) = root.get_str("dht-state") {
+            config.dht_state = Some(PathBuf::from(path));
+        }
+        if let Ok(lsd) = root.get_int("lsd") {
+            config.lsd = lsd != 0;
+        }
+        if let Ok(path) = root.get_str("tracker-whitelist") {
+            config.tracker_whitelist = Some(PathBuf::from(path));
+        }
+        if let Some(threads) = try!(get_number(&root, "disk-threads")) {
+            config.disk_threads = try!(check_disk_threads("disk-threads", threads));
+        }
+        if let Some(size) = try!(get_number(&root, "read-cache-size")) {
+            config.read_cache_size = size;
+        }
+        if let Ok(priorities) = root.get_list("file-priorities") {
+            for priority in priorities {
+                let priority = try!(priority.to_str());
+                config.file_priorities.extend(try!(parse_priorities("file-priorities", &priority)));
+            }
+        }
+        if let Ok(mode) = root.get_str("download-mode") {
+            config.download_mode = try!(parse_flag("download-mode", &mode));
+        }
+        if let Some(window) = try!(get_number(&root, "streaming-window")) {
+            config.streaming_window = try!(check_positive("streaming-window", window));
+        }
+        Ok(config)
+    }
+
+    /// Builds the settings from command line flags, loading `--config <file>` first
+    /// so that the other flags override it. Returns the remaining positional arguments.
+    pub fn from_args(args: &[String]) -> Result<(Config, Vec<String>)> {
+        let mut config = Config::default();
+        if let Some(pos) = args.iter().position(|arg| arg == "--config") {
+            let path = try!(args.get(pos + 1).ok_or(Error::Config("missing value for --config".into())));
+            config = try!(Config::from_file(path));
+        }
+
+        let mut rest = vec![];
+        let mut bootstrap = vec![];
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                rest.push(arg.clone());
+                continue;
+            }
+            let value = try!(iter.next().ok_or(Error::Config(format!("missing value for {}", arg))));
+            match arg.as_str() {
+                "--config" => {},
+                "--download-dir" => config.download_dir = PathBuf::from(value),
+                "--port" => config.listen_port = try!(parse_flag(arg, value)),
+                "--tracker-port" => config.tracker_port = try!(parse_flag(arg, value)),
+                "--udp-retransmissions" => config.udp_retransmissions = try!(check_udp_retransmissions(arg, try!(parse_flag(arg, value)))),
+                "--announce-all-tiers" => config.announce_all_tiers = try!(parse_flag(arg, value)),
+                "--max-peers" => config.max_peers = try!(check_positive(arg, try!(parse_flag(arg, value)))),
+                "--max-seeders" => config.max_seeders = try!(check_positive(arg, try!(parse_flag(arg, value)))),
+                "--max-requests" => config.max_requests = try!(check_positive(arg, try!(parse_flag(arg, value)))),
+                "--save-torrent" => config.save_torrent = Some(PathBuf::from(value)),
+                "--dht-port" => config.dht_port = try!(parse_flag(arg, value)),
+                "--dht-bootstrap" => bootstrap.push(value.clone()),
+                "--dht-state" => config.dht_state = Some(PathBuf::from(value)),
+                "--lsd" => config.lsd = try!(parse_flag(arg, value)),
+                "--whitelist" => config.tracker_whitelist = Some(PathBuf::from(value)),
+                "--disk-threads" => config.disk_threads = try!(check_disk_threads(arg, try!(parse_flag(arg, value)))),
+                "--read-cache-size" => config.read_cache_size = try!(parse_flag(arg, value)),
+                "--only" => {
+                    config.file_priorities.push(("*".to_string(), Priority::Skip));
+                    for selector in value.split(',') {
+                        config.file_priorities.push((selector.to_string(), Priority::Normal));
+                    }
+                },
+                "--priority" => config.file_priorities.extend(try!(parse_priorities(arg, value))),
+                "--download-mode" => config.download_mode = try!(parse_flag(arg, value)),
+                "--streaming-window" => config.streaming_window = try!(check_positive(arg, try!(parse_flag(arg, value)))),
+                _ => return Err(Error::Config(format!("unknown flag {}", arg))),
+            }
+        }
+        if !bootstrap.is_empty() {
+            config.dht_bootstrap = bootstrap;
+        }
+        Ok((config, rest))
+    }
+
+    pub fn dht_state_path(&self) -> PathBuf {
+        match self.dht_state {
+            Some(ref path) => path.clone(),
+            None => self.download_dir.join(".dht"),
+        }
+    }
+}
+
+/// Reads an integer of the file that has to fit in `T`, negative counts
+/// and out of range ports are rejected.
+fn get_number<T: TryFrom<i64>>(root: &BEncoding, key: &str) -> Result<Option<T>> {
+    match root.get_int(key) {
+        Ok(value) => T::try_from(value).map(Some).map_err(|_| Error::Config(format!("invalid value `{}` for {}", value, key))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Limits of zero peers, requests or pieces would stop every download
+fn check_positive(key: &str, value: usize) -> Result<usize> {
+    if value == 0 {
+        return Err(Error::Config(format!("{} has to be at least 1", key)));
+    }
+    Ok(value)
+}
+
+/// The disk pool needs a thread and more than a few dozen only contend for the disk
+fn check_disk_threads(key: &str, threads: usize) -> Result<usize> {
+    if threads == 0 || threads > MAX_DISK_THREADS {
+        return Err(Error::Config(format!("{} has to be between 1 and {}", key, MAX_DISK_THREADS)));
+    }
+    Ok(threads)
+}
+
+/// BEP 15 stops after 8 retransmissions, about two hours for a dead tracker
+fn check_udp_retransmissions(key: &str, retransmissions: u32) -> Result<u32> {
+    if retransmissions > MAX_UDP_RETRANSMISSIONS {
+        return Err(Error::Config(format!("{} has to be at most {}", key, MAX_UDP_RETRANSMISSIONS)));
+    }
+    Ok(retransmissions)
+}
+
+/// Parses `<selector>[,<selector>]=<priority>`, e.g. `2,*.nfo=low`
+fn parse_priorities(flag: &str, value: &str) -> Result<Vec<(String, Priority)>> {
+    let pos = try!(value.rfind('=').ok_or(Error::Config(format!("missing priority in `{}` for {}", value, flag))));
+    let priority = try!(parse_flag::<Priority>(flag, &value[pos + 1..]));
+    Ok(value[..pos].split(',').map(|selector| (selector.to_string(), priority)).collect())
+}
+
+fn parse_flag<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+    value.parse::<T>().map_err(|_| Error::Config(format!("invalid value `{}` for {}", value, flag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use utils::TempDir;
+
+    fn write_file(dir: &TempDir, contents: &[u8]) -> PathBuf {
+        let path = dir.path().join("config");
+        fs::File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = TempDir::new("config");
+        let path = write_file(&dir, b"d12:download-dir9:/srv/data11:listen-porti6881e9:max-peersi20e3:lsdi0e15:file-prioritiesl9:*.nfo=lowee");
+        let config = Config::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.download_dir, PathBuf::from("/srv/data"));
+        assert_eq!(config.listen_port, 6881);
+        assert_eq!(config.max_peers, 20);
+        assert!(!config.lsd);
+        assert_eq!(config.file_priorities, vec![("*.nfo".to_string(), Priority::Low)]);
+        // Untouched keys keep their defaults
+        assert_eq!(config.max_requests, Config::default().max_requests);
+        assert_eq!(config.udp_retransmissions, 8);
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let dir = TempDir::new("config");
+        let path = write_file(&dir, b"d11:listen-porti6881e9:max-peersi20ee");
+        let (config, rest) = Config::from_args(&args(&[
+            "--max-peers", "30", "--config", path.to_str().unwrap(), "--dht-bootstrap", "node:6881", "file.torrent",
+        ])).unwrap();
+        assert_eq!(config.listen_port, 6881);
+        assert_eq!(config.max_peers, 30);
+        assert_eq!(config.dht_bootstrap, vec!["node:6881".to_string()]);
+        assert_eq!(rest, vec!["file.torrent".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(Config::from_args(&args(&["--no-such-flag", "1"])).is_err());
+        assert!(Config::from_args(&args(&["--port", "70000"])).is_err());
+        assert!(Config::from_args(&args(&["--max-peers", "-1"])).is_err());
+        assert!(Config::from_args(&args(&["--max-peers"])).is_err());
+        assert!(Config::from_args(&args(&["--priority", "*.nfo"])).is_err());
+
+        for flag in &["--max-peers", "--max-seeders", "--max-requests", "--streaming-window", "--disk-threads"] {
+            assert!(Config::from_args(&args(&[flag, "0"])).is_err(), "{}", flag);
+        }
+        assert!(Config::from_args(&args(&["--disk-threads", "100000"])).is_err());
+        assert!(Config::from_args(&args(&["--udp-retransmissions", "9"])).is_err());
+        assert_eq!(Config::from_args(&args(&["--udp-retransmissions", "2"])).unwrap().0.udp_retransmissions, 2);
+
+        let dir = TempDir::new("config");
+        let path = write_file(&dir, b"d12:disk-threadsi-1ee");
+        assert!(Config::from_file(path.to_str().unwrap()).is_err());
+        let path = write_file(&dir, b"d12:disk-threadsi1000000ee");
+        assert!(Config::from_file(path.to_str().unwrap()).is_err());
+        let path = write_file(&dir, b"d11:max-seedersi0ee");
+        assert!(Config::from_file(path.to_str().unwrap()).is_err());
+        let path = write_file(&dir, b"d11:listen-porti70000ee");
+        assert!(Config::from_file(path.to_str().unwrap()).is_err());
+    }
+}
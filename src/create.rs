@@ -0,0 +1,336 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bencoding::*;
+use error::{Error, Result};
+use utils::*;
+
+/// Smallest piece length picked for a torrent
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Largest piece length picked for a torrent
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Number of pieces the picked piece length aims for
+const TARGET_PIECES: usize = 1500;
+
+/// A file of the torrent being created
+struct SourceFile {
+    path: PathBuf,
+    /// Path components relative to the torrent's root directory
+    parts: Vec<String>,
+    length: usize,
+    offset: usize,
+}
+
+/// Builds the metainfo of a torrent from a local file or directory
+pub struct TorrentBuilder {
+    path: PathBuf,
+    tiers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    url_list: Vec<String>,
+    source: Option<String>,
+    piece_length: Option<usize>,
+    threads: usize,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> TorrentBuilder {
+        TorrentBuilder {
+            path: path.as_ref().to_path_buf(),
+            tiers: vec![],
+            comment: None,
+            created_by: Some(format!("leech {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() as i64),
+            private: false,
+            url_list: vec![],
+            source: None,
+            piece_length: None,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+
+    /// Adds a tier of trackers, the first tracker added is also the `announce` url.
+    pub fn tier(mut self, urls: Vec<String>) -> TorrentBuilder {
+        if !urls.is_empty() {
+            self.tiers.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> TorrentBuilder {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> TorrentBuilder {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Seconds since the epoch, `None` leaves the date out.
+    pub fn creation_date(mut self, date: Option<i64>) -> TorrentBuilder {
+        self.creation_date = date;
+        self
+    }
+
+    /// Private torrents only get peers from their trackers (BEP 27).
+    pub fn private(mut self, private: bool) -> TorrentBuilder {
+        self.private = private;
+        self
+    }
+
+    /// Adds a web seed (BEP 19).
+    pub fn web_seed(mut self, url: &str) -> TorrentBuilder {
+        self.url_list.push(url.to_string());
+        self
+    }
+
+    /// Tags the torrent with its source, which gives private trackers distinct info hashes.
+    pub fn source(mut self, source: &str) -> TorrentBuilder {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// Overrides the picked piece length, it has to be a power of two of at least 16 KiB.
+    pub fn piece_length(mut self, length: usize) -> TorrentBuilder {
+        self.piece_length = Some(length);
+        self
+    }
+
+    /// Number of threads hashing the pieces.
+    pub fn threads(mut self, threads: usize) -> TorrentBuilder {
+        self.threads = cmp::max(1, threads);
+        self
+    }
+
+    /// Hashes the files and returns the metainfo dictionary.
+    pub fn build(&self) -> Result<BEncoding> {
+        let name = try!(self.path.file_name().and_then(|name| name.to_str())
+                        .ok_or(Error::Config(format!("invalid path {}", self.path.display()))));
+        let is_dir = try!(fs::metadata(&self.path)).is_dir();
+        let files = try!(self.list_files());
+        let total = files.iter().fold(0, |sum, file| sum + file.length);
+        if total == 0 {
+            return Err(Error::Config(format!("{} has no data to share", self.path.display())));
+        }
+
+        let piece_length = match self.piece_length {
+            Some(length) if length >= MIN_PIECE_LENGTH && length.is_power_of_two() => length,
+            Some(length) => return Err(Error::Config(format!("invalid piece length {}", length))),
+            None => piece_length_for(total),
+        };
+        println!("create: hashing {} bytes in pieces of {}", total, piece_length);
+        let pieces = try!(hash_pieces(files, total, piece_length, self.threads));
+
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(name.as_bytes().to_vec()));
+        info.insert("piece length".to_string(), BEncoding::Int(piece_length as i64));
+        info.insert("pieces".to_string(), BEncoding::Str(pieces.0));
+        if is_dir {
+            let list = pieces.1.iter().map(|file| {
+                let mut dict = BTreeMap::new();
+                dict.insert("length".to_string(), BEncoding::Int(file.length as i64));
+                let path = file.parts.iter().map(|part| BEncoding::Str(part.as_bytes().to_vec())).collect();
+                dict.insert("path".to_string(), BEncoding::List(path));
+                BEncoding::Dict(dict)
+            }).collect();
+            info.insert("files".to_string(), BEncoding::List(list));
+        } else {
+            info.insert("length".to_string(), BEncoding::Int(total as i64));
+        }
+        if self.private {
+            info.insert("private".to_string(), BEncoding::Int(1));
+        }
+        if let Some(ref source) = self.source {
+            info.insert("source".to_string(), BEncoding::Str(source.as_bytes().to_vec()));
+        }
+
+        let str = |value: &String| BEncoding::Str(value.as_bytes().to_vec());
+        let mut root = BTreeMap::new();
+        if let Some(url) = self.tiers.first().and_then(|tier| tier.first()) {
+            root.insert("announce".to_string(), str(url));
+        }
+        if self.tiers.len() > 1 || self.tiers.iter().any(|tier| tier.len() > 1) {
+            let tiers = self.tiers.iter().map(|tier| BEncoding::List(tier.iter().map(&str).collect())).collect();
+            root.insert("announce-list".to_string(), BEncoding::List(tiers));
+        }
+        if let Some(ref comment) = self.comment {
+            root.insert("comment".to_string(), str(comment));
+        }
+        if let Some(ref created_by) = self.created_by {
+            root.insert("created by".to_string(), str(created_by));
+        }
+        if let Some(date) = self.creation_date {
+            root.insert("creation date".to_string(), BEncoding::Int(date));
+        }
+        if !self.url_list.is_empty() {
+            root.insert("url-list".to_string(), BEncoding::List(self.url_list.iter().map(&str).collect()));
+        }
+        root.insert("info".to_string(), BEncoding::Dict(info));
+        Ok(BEncoding::Dict(root))
+    }
+
+    /// Builds the torrent and writes it to `out`, returning its info hash.
+    pub fn write<P: AsRef<Path>>(&self, out: P) -> Result<Hash> {
+        let root = try!(self.build());
+        let info_hash = Hash::from_slice(&sha1(&BEncoding::encode(try!(root.get_dict("info")))));
+        let mut f = try!(fs::File::create(out));
+        try!(f.write_all(&BEncoding::encode(&root)));
+        Ok(info_hash)
+    }
+
+    /// Lists the files under the path, sorted so that the layout is reproducible.
+    fn list_files(&self) -> io::Result<Vec<SourceFile>> {
+        let mut paths = vec![];
+        if try!(fs::metadata(&self.path)).is_dir() {
+            try!(walk(&self.path, &mut paths));
+            paths.sort();
+        } else {
+            paths.push(self.path.clone());
+        }
+
+        let mut files = vec![];
+        let mut offset = 0;
+        for path in paths {
+            let length = try!(fs::metadata(&path)).len() as usize;
+            let parts = path.strip_prefix(&self.path).unwrap_or(&path).components()
+                .map(|part| part.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            files.push(SourceFile {
+                path: path,
+                parts: parts,
+                length: length,
+                offset: offset,
+            });
+            offset += length;
+        }
+        Ok(files)
+    }
+}
+
+fn walk(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let metadata = try!(fs::metadata(&path));
+        if metadata.is_dir() {
+            try!(walk(&path, paths));
+        } else if metadata.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Picks a power of two piece length giving about `TARGET_PIECES` pieces.
+pub fn piece_length_for(total: usize) -> usize {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total / length > TARGET_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+/// Hashes the pieces of the files on several threads, each taking a contiguous
+/// range of pieces. Returns the concatenated hashes with the files.
+fn hash_pieces(files: Vec<SourceFile>, total: usize, piece_length: usize, threads: usize) -> Result<(Vec<u8>, Arc<Vec<SourceFile>>)> {
+    let no_of_pieces = (total + piece_length - 1) / piece_length;
+    let per_thread = (no_of_pieces + threads - 1) / threads;
+    let files = Arc::new(files);
+
+    let handles: Vec<_> = (0..threads).map(|i| {
+        let files = files.clone();
+        let first = cmp::min(i * per_thread, no_of_pieces);
+        let last = cmp::min(first + per_thread, no_of_pieces);
+        thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut hashes = vec![];
+            for piece in first..last {
+                let start = piece * piece_length;
+                let end = cmp::min(start + piece_length, total);
+                let data = try!(read_range(&files, start, end));
+                hashes.extend(sha1(&data));
+            }
+            Ok(hashes)
+        })
+    }).collect();
+
+    let mut pieces = vec![];
+    for handle in handles {
+        match handle.join() {
+            Ok(hashes) => pieces.extend(try!(hashes)),
+            Err(_) => return Err(Error::Config("hashing thread panicked".into())),
+        }
+    }
+    Ok((pieces, files))
+}
+
+/// Reads the bytes between `start` and `end` of the files laid end to end.
+fn read_range(files: &[SourceFile], start: usize, end: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    for file in files {
+        if end <= file.offset || start >= file.offset + file.length {
+            continue;
+        }
+        let fstart = start.saturating_sub(file.offset);
+        let fend = cmp::min(end - file.offset, file.length);
+        let mut buffer = vec![0; fend - fstart];
+        let mut f = try!(fs::File::open(&file.path));
+        try!(f.seek(SeekFrom::Start(fstart as u64)));
+        try!(f.read_exact(&mut buffer));
+        data.extend_from_slice(&buffer);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use torrent::Torrent;
+
+    #[test]
+    fn test_create_and_verify() {
+        let temp = TempDir::new("create");
+        let root = temp.path();
+        let dir = root.join("data");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::File::create(dir.join("a.bin")).unwrap().write_all(&vec![1; 40000]).unwrap();
+        fs::File::create(dir.join("sub").join("b.bin")).unwrap().write_all(&vec![2; 30000]).unwrap();
+
+        let out = root.join("data.torrent");
+        TorrentBuilder::new(&dir)
+            .tier(vec!["http://example.com/announce".into()])
+            .comment("test")
+            .private(true)
+            .threads(3)
+            .write(&out)
+            .unwrap();
+
+        let torrent_file = BEncoding::decode_file(out.to_str().unwrap()).unwrap();
+        assert_eq!(torrent_file.get_str("announce").unwrap(), "http://example.com/announce");
+        assert_eq!(torrent_file.get_dict("info").unwrap().get_int("private").unwrap(), 1);
+
+        // The files the torrent was created from are its complete download
+        let mut config = Config::default();
+        config.download_dir = root.to_path_buf();
+        let torrent = Torrent::new(out.to_str().unwrap(), &config).unwrap();
+        assert_eq!(torrent.no_of_pieces, 5);
+        assert!(torrent.is_complete());
+    }
+
+    #[test]
+    fn test_piece_length_for() {
+        assert_eq!(piece_length_for(1000), MIN_PIECE_LENGTH);
+        assert_eq!(piece_length_for(1 << 30), 1 << 20);
+        assert_eq!(piece_length_for(1 << 50), MAX_PIECE_LENGTH);
+    }
+}
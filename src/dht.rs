@@ -0,0 +1,850 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bencoding::*;
+use config::Config;
+use peer::Message;
+use utils::*;
+
+/// Number of nodes in a bucket, and of nodes returned by find_node/get_peers
+const K: usize = 8;
+
+/// Number of queries in flight for a lookup
+const ALPHA: usize = 3;
+
+/// Seconds to wait for a response to a query
+const QUERY_TIMEOUT: u64 = 2;
+
+/// Seconds after which a lookup is stopped
+const LOOKUP_TIMEOUT: u64 = 30;
+
+/// Seconds between rotations of the token secret
+const TOKEN_ROTATION: u64 = 5 * 60;
+
+/// Seconds an announced peer is kept
+const PEER_TTL: u64 = 30 * 60;
+
+/// Announced peers kept per info hash
+const MAX_PEERS: usize = 100;
+
+/// Info hashes for which announced peers are kept
+const MAX_INFO_HASHES: usize = 1000;
+
+/// Seconds after which a silent node is replaced by new ones
+const NODE_TTL: u64 = 15 * 60;
+
+/// Failed queries after which a node is dropped
+const MAX_FAILURES: u32 = 3;
+
+/// Node of the DHT network
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub id: Hash,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Node {
+    fn new(id: Hash, addr: SocketAddr) -> Node {
+        Node {
+            id: id,
+            addr: addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < Duration::from_secs(NODE_TTL)
+    }
+}
+
+fn distance(a: &Hash, b: &Hash) -> [u8; 20] {
+    let mut d = [0; 20];
+    for i in 0..20 {
+        d[i] = a.0[i] ^ b.0[i];
+    }
+    d
+}
+
+/// Kademlia routing table, bucket `i` holds the nodes whose distance has `i` leading zero bits
+pub struct RoutingTable {
+    id: Hash,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: Hash) -> RoutingTable {
+        RoutingTable {
+            id: id,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &Hash) -> Option<usize> {
+        for (i, byte) in distance(&self.id, id).iter().enumerate() {
+            if *byte != 0 {
+                return Some(i * 8 + byte.leading_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    /// Adds or refreshes a node that we heard from.
+    pub fn insert(&mut self, id: Hash, addr: SocketAddr) {
+        let index = match self.bucket_index(&id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.iter_mut().find(|n| n.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return;
+        }
+        if bucket.len() < K {
+            bucket.push(Node::new(id, addr));
+        } else if let Some(pos) = bucket.iter().position(|n| !n.is_good()) {
+            bucket[pos] = Node::new(id, addr);
+        }
+    }
+
+    /// Records a query that the node didn't answer.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for bucket in &mut self.buckets {
+            for node in bucket.iter_mut().filter(|n| n.addr == *addr) {
+                node.failures += 1;
+            }
+            bucket.retain(|n| n.failures < MAX_FAILURES);
+        }
+    }
+
+    /// The nodes closest to the target.
+    pub fn closest(&self, target: &Hash, count: usize) -> Vec<Node> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flat_map(|b| b.iter().cloned()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().fold(0, |sum, b| sum + b.len())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Query {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+}
+
+struct Transaction {
+    query: Query,
+    addr: SocketAddr,
+    lookup: Option<Hash>,
+    sent: Instant,
+}
+
+/// Iterative search for the nodes closest to a target (and for its peers)
+struct Lookup {
+    target: Hash,
+    get_peers: bool,
+    announce_port: Option<u16>,
+    candidates: Vec<Node>,
+    queried: HashSet<SocketAddr>,
+    responded: Vec<(Node, Vec<u8>)>,
+    outstanding: usize,
+    started: Instant,
+    done: bool,
+}
+
+/// Mainline DHT node (BEP 5)
+pub struct Dht {
+    id: Hash,
+    socket: UdpSocket,
+    table: RoutingTable,
+    transactions: HashMap<Vec<u8>, Transaction>,
+    next_transaction: u16,
+    lookups: HashMap<Hash, Lookup>,
+    storage: HashMap<Hash, HashMap<SocketAddr, Instant>>,
+    found: HashMap<Hash, HashSet<SocketAddr>>,
+    secret: [u8; 8],
+    previous_secret: [u8; 8],
+    last_rotation: Instant,
+    last_refresh: Instant,
+    peer_sink: Option<Sender<Message>>,
+}
+
+impl Dht {
+    /// Creates the node on the configured port, restoring the saved routing table.
+    pub fn new(config: &Config) -> io::Result<Dht> {
+        let mut dht = try!(Dht::bind(SocketAddr::from(([0, 0, 0, 0], config.dht_port))));
+        if let Err(err) = dht.load(&config.dht_state_path()) {
+            println!("dht: no saved routing table ({})", err);
+        }
+        Ok(dht)
+    }
+
+    pub fn bind(addr: SocketAddr) -> io::Result<Dht> {
+        let socket = try!(UdpSocket::bind(addr));
+        try!(socket.set_nonblocking(true));
+        let mut id = [0; 20];
+        for chunk in id.chunks_mut(8) {
+            let bytes = u64_to_byte_slice(random());
+            let len = chunk.len();
+            chunk.copy_from_slice(&bytes[..len]);
+        }
+        let id = Hash(id);
+
+        Ok(Dht {
+            id: id,
+            socket: socket,
+            table: RoutingTable::new(id),
+            transactions: HashMap::new(),
+            next_transaction: random() as u16,
+            lookups: HashMap::new(),
+            storage: HashMap::new(),
+            found: HashMap::new(),
+            secret: Self::new_secret(),
+            previous_secret: Self::new_secret(),
+            last_rotation: Instant::now(),
+            last_refresh: Instant::now(),
+            peer_sink: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Sends the peers found by lookups to the client as `Message::AddPeer`.
+    pub fn set_peer_sink(&mut self, sink: Sender<Message>) {
+        self.peer_sink = Some(sink);
+    }
+
+    /// Joins the network by looking up our own id through the bootstrap nodes.
+    pub fn bootstrap(&mut self, nodes: &[String]) {
+        let mut candidates = self.table.nodes();
+        for node in nodes {
+            match node.to_socket_addrs() {
+                Ok(addrs) => {
+                    for addr in addrs.filter(|a| a.is_ipv4()) {
+                        candidates.push(Node::new(Hash::default(), addr));
+                    }
+                },
+                Err(err) => println!("dht: unable to resolve bootstrap node {}: {}", node, err),
+            }
+        }
+        let id = self.id;
+        self.start_lookup(id, false, None, candidates);
+    }
+
+    /// Starts looking up peers for the info hash, announcing ourselves on
+    /// `announce_port` to the closest nodes when the lookup finishes.
+    pub fn get_peers(&mut self, info_hash: Hash, announce_port: Option<u16>) {
+        let candidates = self.table.closest(&info_hash, K);
+        self.start_lookup(info_hash, true, announce_port, candidates);
+    }
+
+    /// Whether the lookup for the target has finished.
+    pub fn is_lookup_done(&self, target: &Hash) -> bool {
+        self.lookups.get(target).map(|l| l.done).unwrap_or(true)
+    }
+
+    /// Whether the lookup of our own id started by `bootstrap` has finished,
+    /// before that the routing table may still be empty.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.is_lookup_done(&self.id)
+    }
+
+    /// Number of nodes that answered the lookup for the target.
+    pub fn lookup_responders(&self, target: &Hash) -> usize {
+        self.lookups.get(target).map(|l| l.responded.len()).unwrap_or(0)
+    }
+
+    /// Peers found so far for the info hash.
+    pub fn found_peers(&self, info_hash: &Hash) -> Vec<SocketAddr> {
+        self.found.get(info_hash).map(|peers| peers.iter().cloned().collect()).unwrap_or(vec![])
+    }
+
+    /// Handles the received packets, timeouts and periodic tasks without blocking.
+    pub fn step(&mut self) {
+        let mut buffer = [0; 2048];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, addr)) => {
+                    match BEncoding::decode(buffer[..len].to_vec()) {
+                        Some(message) => self.handle_message(&message, addr),
+                        None => println!("dht: invalid message from {}", addr),
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("dht: error while receiving {}", err);
+                    break;
+                },
+            }
+        }
+
+        self.process_timeouts();
+        self.process_lookups();
+
+        if self.last_rotation.elapsed() >= Duration::from_secs(TOKEN_ROTATION) {
+            self.previous_secret = self.secret;
+            self.secret = Self::new_secret();
+            self.last_rotation = Instant::now();
+            for peers in self.storage.values_mut() {
+                peers.retain(|_, added| added.elapsed() < Duration::from_secs(PEER_TTL));
+            }
+            self.storage.retain(|_, peers| !peers.is_empty());
+        }
+
+        if self.last_refresh.elapsed() >= Duration::from_secs(NODE_TTL) {
+            self.last_refresh = Instant::now();
+            // Nodes that don't answer the ping get replaced as new nodes show up
+            for node in self.table.nodes().iter().filter(|n| !n.is_good()) {
+                self.send_query(node.addr, Query::Ping, BTreeMap::new(), None);
+            }
+            let mut target = self.id;
+            target.0[19] ^= random() as u8;
+            let candidates = self.table.closest(&target, K);
+            self.start_lookup(target, false, None, candidates);
+        }
+    }
+
+    /// Saves our id and the routing table.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = vec![];
+        for node in self.table.nodes().iter().filter(|n| n.addr.is_ipv4()) {
+            nodes.extend_from_slice(&node.id.0);
+            nodes.extend(compact_addr(&node.addr));
+        }
+        let mut root = BTreeMap::new();
+        root.insert("id".to_string(), BEncoding::Str(self.id.0.to_vec()));
+        root.insert("nodes".to_string(), BEncoding::Str(nodes));
+
+        if let Some(dirs) = path.parent() {
+            try!(fs::create_dir_all(dirs));
+        }
+        let mut f = try!(fs::File::create(path));
+        try!(f.write_all(&BEncoding::encode(&BEncoding::Dict(root))));
+        Ok(())
+    }
+
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        let mut buf = vec![];
+        let mut f = try!(fs::File::open(path));
+        try!(f.read_to_end(&mut buf));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid dht state");
+        let root = try!(BEncoding::decode(buf).ok_or(invalid()));
+        let id = try!(root.get_bytes("id").map_err(|_| invalid()));
+        let nodes = try!(root.get_bytes("nodes").map_err(|_| invalid()));
+        if id.len() != 20 {
+            return Err(invalid());
+        }
+
+        self.id = Hash::from_slice(&id);
+        self.table = RoutingTable::new(self.id);
+        for (id, addr) in parse_nodes(&nodes) {
+            self.table.insert(id, addr);
+        }
+        println!("dht: loaded {} nodes from {}", self.table.len(), path.display());
+        Ok(())
+    }
+
+    fn new_secret() -> [u8; 8] {
+        let mut secret = [0; 8];
+        secret.copy_from_slice(&u64_to_byte_slice(random()));
+        secret
+    }
+
+    fn token(secret: &[u8; 8], ip: &IpAddr) -> Vec<u8> {
+        let mut data = secret.to_vec();
+        match *ip {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+        sha1(&data)[0..8].to_vec()
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: &IpAddr) -> bool {
+        token == &Self::token(&self.secret, ip)[..] || token == &Self::token(&self.previous_secret, ip)[..]
+    }
+
+    fn start_lookup(&mut self, target: Hash, get_peers: bool, announce_port: Option<u16>, candidates: Vec<Node>) {
+        let mut lookup = Lookup {
+            target: target,
+            get_peers: get_peers,
+            announce_port: announce_port,
+            candidates: vec![],
+            queried: HashSet::new(),
+            responded: vec![],
+            outstanding: 0,
+            started: Instant::now(),
+            done: false,
+        };
+        for node in candidates {
+            Self::add_candidate(&mut lookup, node);
+        }
+        self.lookups.insert(target, lookup);
+    }
+
+    fn add_candidate(lookup: &mut Lookup, node: Node) {
+        if lookup.candidates.iter().any(|n| n.addr == node.addr) {
+            return;
+        }
+        lookup.candidates.push(node);
+        let target = lookup.target;
+        lookup.candidates.sort_by_key(|n| distance(&n.id, &target));
+    }
+
+    fn process_lookups(&mut self) {
+        let mut queries = vec![];
+        let mut announces = vec![];
+        for lookup in self.lookups.values_mut().filter(|l| !l.done) {
+            let pending: Vec<Node> = lookup.candidates
+                .iter()
+                .take(K)
+                .filter(|n| !lookup.queried.contains(&n.addr))
+                .cloned()
+                .collect();
+            if lookup.started.elapsed() >= Duration::from_secs(LOOKUP_TIMEOUT)
+                || (pending.is_empty() && lookup.outstanding == 0) {
+                lookup.done = true;
+                if let Some(port) = lookup.announce_port {
+                    let target = lookup.target;
+                    lookup.responded.sort_by_key(|&(ref n, _)| distance(&n.id, &target));
+                    for &(ref node, ref token) in lookup.responded.iter().take(K) {
+                        announces.push((node.addr, lookup.target, port, token.clone()));
+                    }
+                }
+                continue;
+            }
+
+            for node in pending.into_iter().take(ALPHA - cmp::min(lookup.outstanding, ALPHA)) {
+                lookup.queried.insert(node.addr);
+                lookup.outstanding += 1;
+                queries.push((node.addr, lookup.target, lookup.get_peers));
+            }
+        }
+
+        for (addr, target, get_peers) in queries {
+            let mut args = BTreeMap::new();
+            let query = if get_peers {
+                args.insert("info_hash".to_string(), BEncoding::Str(target.0.to_vec()));
+                Query::GetPeers
+            } else {
+                args.insert("target".to_string(), BEncoding::Str(target.0.to_vec()));
+                Query::FindNode
+            };
+            self.send_query(addr, query, args, Some(target));
+        }
+
+        for (addr, info_hash, port, token) in announces {
+            println!("dht: announcing {} to {}", info_hash, addr);
+            let mut args = BTreeMap::new();
+            args.insert("info_hash".to_string(), BEncoding::Str(info_hash.0.to_vec()));
+            args.insert("port".to_string(), BEncoding::Int(port as i64));
+            args.insert("token".to_string(), BEncoding::Str(token));
+            self.send_query(addr, Query::AnnouncePeer, args, None);
+        }
+    }
+
+    fn process_timeouts(&mut self) {
+        let expired: Vec<Vec<u8>> = self.transactions
+            .iter()
+            .filter(|&(_, t)| t.sent.elapsed() >= Duration::from_secs(QUERY_TIMEOUT))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            if let Some(transaction) = self.transactions.remove(&id) {
+                self.table.failed(&transaction.addr);
+                self.finish_query(&transaction);
+            }
+        }
+    }
+
+    fn finish_query(&mut self, transaction: &Transaction) {
+        if let Some(target) = transaction.lookup {
+            if let Some(lookup) = self.lookups.get_mut(&target) {
+                if lookup.outstanding > 0 {
+                    lookup.outstanding -= 1;
+                }
+            }
+        }
+    }
+
+    fn send_query(&mut self, addr: SocketAddr, query: Query, mut args: BTreeMap<String, BEncoding>, lookup: Option<Hash>) {
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let t = vec![(self.next_transaction >> 8) as u8, self.next_transaction as u8];
+        args.insert("id".to_string(), BEncoding::Str(self.id.0.to_vec()));
+
+        let name = match query {
+            Query::Ping => "ping",
+            Query::FindNode => "find_node",
+            Query::GetPeers => "get_peers",
+            Query::AnnouncePeer => "announce_peer",
+        };
+        let mut root = BTreeMap::new();
+        root.insert("t".to_string(), BEncoding::Str(t.clone()));
+        root.insert("y".to_string(), BEncoding::Str(b"q".to_vec()));
+        root.insert("q".to_string(), BEncoding::Str(name.as_bytes().to_vec()));
+        root.insert("a".to_string(), BEncoding::Dict(args));
+
+        let transaction = Transaction {
+            query: query,
+            addr: addr,
+            lookup: lookup,
+            sent: Instant::now(),
+        };
+        if let Err(err) = self.socket.send_to(&BEncoding::encode(&BEncoding::Dict(root)), addr) {
+            println!("dht: error while sending to {}: {}", addr, err);
+            self.finish_query(&transaction);
+            return;
+        }
+        self.transactions.insert(t, transaction);
+    }
+
+    fn send_response(&self, addr: SocketAddr, t: Vec<u8>, mut values: BTreeMap<String, BEncoding>) {
+        values.insert("id".to_string(), BEncoding::Str(self.id.0.to_vec()));
+        let mut root = BTreeMap::new();
+        root.insert("t".to_string(), BEncoding::Str(t));
+        root.insert("y".to_string(), BEncoding::Str(b"r".to_vec()));
+        root.insert("r".to_string(), BEncoding::Dict(values));
+        let _ = self.socket.send_to(&BEncoding::encode(&BEncoding::Dict(root)), addr);
+    }
+
+    fn send_error(&self, addr: SocketAddr, t: Vec<u8>, code: i64, message: &str) {
+        let mut root = BTreeMap::new();
+        root.insert("t".to_string(), BEncoding::Str(t));
+        root.insert("y".to_string(), BEncoding::Str(b"e".to_vec()));
+        root.insert("e".to_string(), BEncoding::List(vec![
+            BEncoding::Int(code),
+            BEncoding::Str(message.as_bytes().to_vec()),
+        ]));
+        let _ = self.socket.send_to(&BEncoding::encode(&BEncoding::Dict(root)), addr);
+    }
+
+    fn handle_message(&mut self, message: &BEncoding, addr: SocketAddr) {
+        let t = match message.get_bytes("t") {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        // Compared as bytes, anyone can send us anything
+        match message.get_bytes("y").ok().as_ref().map(|y| y.as_slice()) {
+            Some(b"q") => self.handle_query(message, t, addr),
+            Some(b"r") => self.handle_response(message, t, addr),
+            Some(b"e") => {
+                println!("dht: error from {}: {}", addr, message);
+                if let Some(transaction) = self.transactions.remove(&t) {
+                    self.finish_query(&transaction);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn handle_query(&mut self, message: &BEncoding, t: Vec<u8>, addr: SocketAddr) {
+        let args = match message.get_dict("a") {
+            Ok(args) => args,
+            Err(_) => return self.send_error(addr, t, 203, "missing arguments"),
+        };
+        let id = match args.get_bytes("id") {
+            Ok(ref id) if id.len() == 20 => Hash::from_slice(id),
+            _ => return self.send_error(addr, t, 203, "invalid id"),
+        };
+        self.table.insert(id, addr);
+
+        let mut values = BTreeMap::new();
+        match message.get_bytes("q").unwrap_or(vec![]).as_slice() {
+            b"ping" => {},
+            b"find_node" => {
+                let target = match args.get_bytes("target") {
+                    Ok(ref target) if target.len() == 20 => Hash::from_slice(target),
+                    _ => return self.send_error(addr, t, 203, "invalid target"),
+                };
+                values.insert("nodes".to_string(), BEncoding::Str(compact_nodes(&self.table.closest(&target, K))));
+            },
+            b"get_peers" => {
+                let info_hash = match args.get_bytes("info_hash") {
+                    Ok(ref hash) if hash.len() == 20 => Hash::from_slice(hash),
+                    _ => return self.send_error(addr, t, 203, "invalid info_hash"),
+                };
+                values.insert("token".to_string(), BEncoding::Str(Self::token(&self.secret, &addr.ip())));
+                match self.storage.get(&info_hash) {
+                    Some(peers) if !peers.is_empty() => {
+                        let list = peers.keys().filter(|a| a.is_ipv4()).map(|a| BEncoding::Str(compact_addr(a))).collect();
+                        values.insert("values".to_string(), BEncoding::List(list));
+                    },
+                    _ => {
+                        values.insert("nodes".to_string(), BEncoding::Str(compact_nodes(&self.table.closest(&info_hash, K))));
+                    },
+                }
+            },
+            b"announce_peer" => {
+                let info_hash = match args.get_bytes("info_hash") {
+                    Ok(ref hash) if hash.len() == 20 => Hash::from_slice(hash),
+                    _ => return self.send_error(addr, t, 203, "invalid info_hash"),
+                };
+                let token = args.get_bytes("token").unwrap_or(vec![]);
+                if !self.is_valid_token(&token, &addr.ip()) {
+                    return self.send_error(addr, t, 203, "bad token");
+                }
+                let port = if args.get_int("implied_port").unwrap_or(0) != 0 {
+                    addr.port()
+                } else {
+                    match args.get_int("port") {
+                        Ok(port) if port > 0 && port <= 65535 => port as u16,
+                        _ => return self.send_error(addr, t, 203, "invalid port"),
+                    }
+                };
+                self.store_peer(info_hash, SocketAddr::new(addr.ip(), port));
+            },
+            _ => return self.send_error(addr, t, 204, "method unknown"),
+        }
+        self.send_response(addr, t, values);
+    }
+
+    /// Keeps an announced peer, making room by dropping the oldest
+    /// announce or the info hash announced the longest ago.
+    fn store_peer(&mut self, info_hash: Hash, peer: SocketAddr) {
+        if !self.storage.contains_key(&info_hash) && self.storage.len() >= MAX_INFO_HASHES {
+            let oldest = self.storage.iter()
+                .min_by_key(|&(_, peers)| peers.values().max().cloned())
+                .map(|(hash, _)| *hash);
+            if let Some(hash) = oldest {
+                self.storage.remove(&hash);
+            }
+        }
+        let peers = self.storage.entry(info_hash).or_insert(HashMap::new());
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS {
+            let oldest = peers.iter().min_by_key(|&(_, added)| *added).map(|(addr, _)| *addr);
+            if let Some(addr) = oldest {
+                peers.remove(&addr);
+            }
+        }
+        peers.insert(peer, Instant::now());
+    }
+
+    fn handle_response(&mut self, message: &BEncoding, t: Vec<u8>, addr: SocketAddr) {
+        let transaction = match self.transactions.remove(&t) {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        if transaction.addr != addr {
+            // Not from the node we asked, keep waiting for the real response
+            self.transactions.insert(t, transaction);
+            return;
+        }
+        self.finish_query(&transaction);
+
+        let values = match message.get_dict("r") {
+            Ok(values) => values,
+            Err(_) => return,
+        };
+        let id = match values.get_bytes("id") {
+            Ok(ref id) if id.len() == 20 => Hash::from_slice(id),
+            _ => return,
+        };
+        self.table.insert(id, addr);
+
+        if transaction.query != Query::FindNode && transaction.query != Query::GetPeers {
+            return;
+        }
+        let target = match transaction.lookup {
+            Some(target) => target,
+            None => return,
+        };
+
+        let nodes = values.get_bytes("nodes").map(|n| parse_nodes(&n)).unwrap_or(vec![]);
+        let peers: Vec<SocketAddr> = values.get_list("values").map(|list| {
+            list.iter()
+                .filter_map(|v| v.to_bytes().ok())
+                .flat_map(|v| parse_compact_addrs(&v, false))
+                .collect()
+        }).unwrap_or(vec![]);
+        let token = values.get_bytes("token").ok();
+
+        if let Some(lookup) = self.lookups.get_mut(&target) {
+            for (node_id, node_addr) in nodes {
+                if node_id != self.id {
+                    Self::add_candidate(lookup, Node::new(node_id, node_addr));
+                }
+            }
+            // Learn the real id of nodes we only knew the address of (bootstrap nodes)
+            for node in lookup.candidates.iter_mut().filter(|n| n.addr == addr) {
+                node.id = id;
+            }
+            if let Some(token) = token {
+                lookup.responded.push((Node::new(id, addr), token));
+            }
+        }
+
+        if !peers.is_empty() {
+            let found = self.found.entry(target).or_insert(HashSet::new());
+            for peer in peers {
+                if found.insert(peer) {
+                    if let Some(ref sink) = self.peer_sink {
+                        let _ = sink.send(Message::AddPeer(peer));
+                    }
+                }
+            }
+            println!("dht: {} peers known for {}", found.len(), target);
+        }
+    }
+}
+
+/// Encodes nodes in compact node info form (20 byte id + 6 byte address)
+fn compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut data = vec![];
+    for node in nodes.iter().filter(|n| n.addr.is_ipv4()) {
+        data.extend_from_slice(&node.id.0);
+        data.extend(compact_addr(&node.addr));
+    }
+    data
+}
+
+fn parse_nodes(data: &[u8]) -> Vec<(Hash, SocketAddr)> {
+    data.chunks(26).filter(|c| c.len() == 26).map(|chunk| {
+        let addr = parse_compact_addrs(&chunk[20..26], false)[0];
+        (Hash::from_slice(&chunk[0..20]), addr)
+    }).collect()
+}
+
+/// Finds peers for the info hash through the DHT, blocking until the lookup
+/// finishes. Used when a magnet link has no trackers.
+pub fn find_peers(config: &Config, info_hash: &Hash) -> Vec<SocketAddr> {
+    let mut dht = match Dht::new(config) {
+        Ok(dht) => dht,
+        Err(err) => {
+            println!("dht: unable to start: {}", err);
+            return vec![];
+        },
+    };
+    dht.bootstrap(&config.dht_bootstrap);
+    while !dht.is_bootstrapped() {
+        dht.step();
+        thread::sleep(Duration::from_millis(10));
+    }
+    dht.get_peers(*info_hash, None);
+    while !dht.is_lookup_done(info_hash) {
+        dht.step();
+        thread::sleep(Duration::from_millis(10));
+    }
+    if let Err(err) = dht.save(&config.dht_state_path()) {
+        println!("dht: error while saving routing table {}", err);
+    }
+    dht.found_peers(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Steps the nodes until the condition holds, failing after a few seconds
+    fn run<F: Fn(&[Dht]) -> bool>(nodes: &mut Vec<Dht>, done: F) {
+        let started = Instant::now();
+        while !done(nodes) {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            for node in nodes.iter_mut() {
+                node.step();
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn announce_and_get_peers() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut nodes: Vec<Dht> = (0..5).map(|_| Dht::bind(localhost).unwrap()).collect();
+        let bootstrap = vec![format!("{}", nodes[0].local_addr().unwrap())];
+        for node in nodes.iter_mut().skip(1) {
+            node.bootstrap(&bootstrap);
+        }
+        run(&mut nodes, |nodes| nodes.iter().all(|n| n.routing_table().len() > 0 && n.is_bootstrapped()));
+
+        let info_hash = Hash([7; 20]);
+        nodes[1].get_peers(info_hash, Some(7777));
+        run(&mut nodes, |nodes| nodes[1].is_lookup_done(&info_hash));
+        assert!(nodes[1].lookup_responders(&info_hash) > 0);
+
+        nodes[4].get_peers(info_hash, None);
+        run(&mut nodes, |nodes| nodes[4].is_lookup_done(&info_hash));
+        assert_eq!(vec![SocketAddr::from(([127, 0, 0, 1], 7777))], nodes[4].found_peers(&info_hash));
+    }
+
+    #[test]
+    fn lookup_without_nodes() {
+        let mut node = Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        node.bootstrap(&[]);
+        node.step();
+        assert!(node.is_bootstrapped());
+
+        let info_hash = Hash([7; 20]);
+        node.get_peers(info_hash, None);
+        node.step();
+        assert!(node.is_lookup_done(&info_hash));
+        assert_eq!(node.lookup_responders(&info_hash), 0);
+    }
+
+    #[test]
+    fn announce_storage_is_bounded() {
+        let mut node = Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let info_hash = Hash([7; 20]);
+        for port in 1..(MAX_PEERS as u16 + 11) {
+            node.store_peer(info_hash, SocketAddr::from(([127, 0, 0, 1], port)));
+        }
+        assert_eq!(node.storage[&info_hash].len(), MAX_PEERS);
+        assert!(node.storage[&info_hash].contains_key(&SocketAddr::from(([127, 0, 0, 1], MAX_PEERS as u16 + 10))));
+
+        for i in 0..MAX_INFO_HASHES {
+            let mut hash = [0; 20];
+            hash[0] = (i >> 8) as u8;
+            hash[1] = i as u8;
+            node.store_peer(Hash(hash), SocketAddr::from(([127, 0, 0, 1], 1)));
+        }
+        assert_eq!(node.storage.len(), MAX_INFO_HASHES);
+        assert!(!node.storage.contains_key(&info_hash));
+    }
+
+    #[test]
+    fn garbage_messages() {
+        let mut node = Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9));
+        let messages: Vec<&[u8]> = vec![
+            b"d1:t2:aa1:y1:\xffe",
+            b"d1:t2:aa1:y1:q1:q2:\xff\xfe1:ad2:id20:aaaaaaaaaaaaaaaaaaaaee",
+            b"d1:t2:aa1:y1:q1:qi5e1:ad2:id3:abcee",
+            b"d1:t2:aa1:y1:r1:rd2:id20:aaaaaaaaaaaaaaaaaaaa5:nodes3:\xff\xff\xffee",
+            b"d1:ti1e1:y1:qe",
+            b"d1:y1:ee",
+            b"le",
+        ];
+        for message in messages {
+            if let Some(message) = BEncoding::decode(message.to_vec()) {
+                node.handle_message(&message, addr);
+            }
+        }
+        // Only the valid id of the unknown query made it to the routing table
+        assert_eq!(1, node.routing_table().len());
+    }
+}
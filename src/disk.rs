@@ -0,0 +1,217 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+
+use storage::Storage;
+
+/// Most threads a disk pool runs
+pub const MAX_DISK_THREADS: usize = 64;
+
+/// Work for the disk threads
+enum Job {
+    /// Writes a whole piece at once
+    Write { piece: usize, offset: usize, data: Vec<u8>, verified: Option<bool> },
+    /// Reads the piece a peer asked a block of, through the read cache
+    Read { addr: SocketAddr, piece: usize, offset: usize, length: usize, begin: usize, block_length: usize },
+}
+
+/// Completed disk work, reported back to the client
+pub enum DiskEvent {
+    Written { piece: usize, verified: Option<bool>, result: io::Result<()> },
+    Read { addr: SocketAddr, piece: usize, begin: usize, length: usize, result: io::Result<Vec<u8>> },
+}
+
+/// Least recently used pieces read for uploads, peers usually ask for the
+/// blocks of a piece one after another.
+struct ReadCache {
+    pieces: HashMap<usize, Arc<Vec<u8>>>,
+    order: VecDeque<usize>,
+    size: usize,
+    capacity: usize,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            capacity: capacity,
+        }
+    }
+
+    fn get(&mut self, piece: usize) -> Option<Arc<Vec<u8>>> {
+        let data = match self.pieces.get(&piece) {
+            Some(data) => data.clone(),
+            None => return None,
+        };
+        self.order.retain(|&p| p != piece);
+        self.order.push_back(piece);
+        Some(data)
+    }
+
+    fn insert(&mut self, piece: usize, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        self.remove(piece);
+        self.size += data.len();
+        self.pieces.insert(piece, data);
+        self.order.push_back(piece);
+        while self.size > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.remove(oldest),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, piece: usize) {
+        if let Some(data) = self.pieces.remove(&piece) {
+            self.size -= data.len();
+            self.order.retain(|&p| p != piece);
+        }
+    }
+}
+
+/// Pool of threads doing the disk work of a torrent off the client's loop
+pub struct DiskIo {
+    jobs: Option<Sender<Job>>,
+    cache: Arc<Mutex<ReadCache>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl DiskIo {
+    pub fn new(storage: Arc<dyn Storage>, threads: usize, cache_size: usize, events: Sender<DiskEvent>) -> DiskIo {
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let cache = Arc::new(Mutex::new(ReadCache::new(cache_size)));
+        let threads = (0..threads.max(1).min(MAX_DISK_THREADS)).map(|_| {
+            let rx = rx.clone();
+            let storage = storage.clone();
+            let cache = cache.clone();
+            let events = events.clone();
+            thread::spawn(move || Self::work(rx, storage, cache, events))
+        }).collect();
+        DiskIo {
+            jobs: Some(tx),
+            cache: cache,
+            threads: threads,
+        }
+    }
+
+    fn work(jobs: Arc<Mutex<Receiver<Job>>>, storage: Arc<dyn Storage>, cache: Arc<Mutex<ReadCache>>, events: Sender<DiskEvent>) {
+        loop {
+            // The lock is only held while waiting, so the other threads take the next jobs
+            let job = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let event = match job {
+                Job::Write { piece, offset, data, verified } => {
+                    let result = storage.write_block(offset, &data);
+                    DiskEvent::Written { piece: piece, verified: verified, result: result }
+                },
+                Job::Read { addr, piece, offset, length, begin, block_length } => {
+                    let result = storage.read_block(offset, length).map(|data| {
+                        let block = data[begin..begin + block_length].to_vec();
+                        cache.lock().unwrap().insert(piece, Arc::new(data));
+                        block
+                    });
+                    DiskEvent::Read { addr: addr, piece: piece, begin: begin, length: block_length, result: result }
+                },
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Queues the write of a complete piece, `verified` is passed back with its completion.
+    pub fn write_piece(&self, piece: usize, offset: usize, data: Vec<u8>, verified: Option<bool>) {
+        self.cache.lock().unwrap().remove(piece);
+        if let Some(ref jobs) = self.jobs {
+            let _ = jobs.send(Job::Write { piece: piece, offset: offset, data: data, verified: verified });
+        }
+    }
+
+    /// Reads a block for an upload. Returns it right away when its piece is
+    /// cached, otherwise the piece is read on a disk thread.
+    pub fn read_block(&self, addr: SocketAddr, piece: usize, offset: usize, length: usize, begin: usize, block_length: usize) -> Option<Vec<u8>> {
+        if let Some(data) = self.cache.lock().unwrap().get(piece) {
+            return Some(data[begin..begin + block_length].to_vec());
+        }
+        if let Some(ref jobs) = self.jobs {
+            let job = Job::Read { addr: addr, piece: piece, offset: offset, length: length, begin: begin, block_length: block_length };
+            let _ = jobs.send(job);
+        }
+        None
+    }
+
+    /// Finishes the queued jobs and stops the threads.
+    pub fn shutdown(&mut self) {
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for DiskIo {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::MemoryStorage;
+    use torrent::FileItem;
+
+    #[test]
+    fn test_disk_io() {
+        let files = vec![FileItem { length: 100, offset: 0, path: "a".into(), pad: false }];
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(&files));
+        let (tx, rx) = channel();
+        let mut disk = DiskIo::new(storage, 2, 60, tx);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+
+        disk.write_piece(1, 50, vec![5; 50], Some(true));
+        match rx.recv().unwrap() {
+            DiskEvent::Written { piece: 1, verified: Some(true), result: Ok(()) } => {},
+            _ => panic!("expected the write of piece 1"),
+        }
+
+        // The first read goes to disk, the next ones hit the cache
+        assert!(disk.read_block(addr, 1, 50, 50, 10, 5).is_none());
+        match rx.recv().unwrap() {
+            DiskEvent::Read { piece: 1, begin: 10, result: Ok(ref data), .. } => assert_eq!(*data, vec![5; 5]),
+            _ => panic!("expected the read of piece 1"),
+        }
+        assert_eq!(disk.read_block(addr, 1, 50, 50, 20, 5), Some(vec![5; 5]));
+
+        // Writing the piece again drops it from the cache
+        disk.write_piece(1, 50, vec![6; 50], None);
+        assert!(disk.read_block(addr, 1, 50, 50, 20, 5).is_none());
+        disk.shutdown();
+        assert_eq!(rx.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let mut cache = ReadCache::new(100);
+        cache.insert(0, Arc::new(vec![0; 40]));
+        cache.insert(1, Arc::new(vec![1; 40]));
+        assert!(cache.get(0).is_some());
+        cache.insert(2, Arc::new(vec![2; 40]));
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(0).is_some());
+        assert_eq!(cache.size, 80);
+        cache.insert(3, Arc::new(vec![3; 101]));
+        assert!(cache.get(3).is_none());
+    }
+}
@@ -11,6 +11,11 @@ pub enum Error {
     Io(io::Error),
     Hyper(hyper::Error),
     BEncoding(bencoding::Error),
+    Config(String),
+    Metadata(String),
+    Tracker(String),
+    /// Reason a tracker gave for refusing a request
+    TrackerFailure(String),
 }
 
 impl From<net::AddrParseError> for Error {
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bencoding::*;
+use peer::Peer;
+
+/// Byte of the handshake's reserved field carrying the extension protocol bit
+pub const RESERVED_BYTE: usize = 5;
+
+/// Bit that signals support for the extension protocol (BEP 10)
+pub const RESERVED_BIT: u8 = 0x10;
+
+/// Extended message id of the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Number of outstanding requests we let a peer queue
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// Bencoded dictionary exchanged right after the BitTorrent handshake
+#[derive(Default, Clone, Debug)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message ids the sender wants to receive them on
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version
+    pub v: Option<String>,
+    /// Listen port of the sender
+    pub p: Option<u16>,
+    /// Number of outstanding requests the sender supports
+    pub reqq: Option<usize>,
+    /// Address of the receiver as seen by the sender
+    pub yourip: Option<IpAddr>,
+    /// Size of the info dictionary, used by ut_metadata
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        let m = self.m.iter().map(|(name, &id)| (name.clone(), BEncoding::Int(id as i64))).collect();
+        root.insert("m".to_string(), BEncoding::Dict(m));
+        if let Some(ref v) = self.v {
+            root.insert("v".to_string(), BEncoding::Str(v.clone().into_bytes()));
+        }
+        if let Some(p) = self.p {
+            root.insert("p".to_string(), BEncoding::Int(p as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            root.insert("reqq".to_string(), BEncoding::Int(reqq as i64));
+        }
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            root.insert("yourip".to_string(), BEncoding::Str(bytes));
+        }
+        if let Some(size) = self.metadata_size {
+            root.insert("metadata_size".to_string(), BEncoding::Int(size as i64));
+        }
+        BEncoding::encode(&BEncoding::Dict(root))
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<ExtendedHandshake> {
+        let root = BEncoding::decode(payload.to_vec())?;
+        let mut handshake = ExtendedHandshake::default();
+        if let Ok(m) = root.get_dict("m") {
+            for (name, id) in m.to_dict().ok()? {
+                // An id of 0 means the extension was disabled
+                if let Ok(id) = id.to_int() {
+                    if id > 0 && id <= 255 {
+                        handshake.m.insert(name.clone(), id as u8);
+                    }
+                }
+            }
+        }
+        // Client names are free text, a name that isn't UTF-8 is kept as best we can
+        handshake.v = root.get_bytes("v").ok().map(|v| String::from_utf8_lossy(&v).into_owned());
+        // Ports out of 1..=65535 and negative counts are dropped rather than wrapped
+        handshake.p = root.get_int("p").ok().and_then(|p| u16::try_from(p).ok()).filter(|&p| p != 0);
+        handshake.reqq = root.get_int("reqq").ok().and_then(|reqq| usize::try_from(reqq).ok());
+        handshake.yourip = root.get_bytes("yourip").ok().and_then(|ip| {
+            match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))),
+                16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&ip);
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                },
+                _ => None,
+            }
+        });
+        handshake.metadata_size = root.get_int("metadata_size").ok().and_then(|size| usize::try_from(size).ok());
+        Some(handshake)
+    }
+}
+
+/// Handler for the messages of an extension (ut_pex, ut_metadata, ...)
+pub trait Extension {
+    /// Name of the extension in the `m` dictionary
+    fn name(&self) -> &'static str;
+
+    /// Called when the peer's extended handshake arrives.
+    fn on_handshake(&mut self, _peer: &mut Peer, _handshake: &ExtendedHandshake) {}
+
+    /// Called with the payload of a message sent to this extension's id.
+    fn on_message(&mut self, peer: &mut Peer, payload: &[u8]);
+
+    /// Called on every round of the client loop.
+    fn tick(&mut self, _peer: &mut Peer) {}
+
+    /// Called when the peer disconnects.
+    fn on_disconnect(&mut self, _addr: &SocketAddr) {}
+}
+
+/// Extensions supported by the client, the local message id of an extension is its position
+pub struct Registry {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            extensions: vec![],
+        }
+    }
+
+    /// Adds an extension and returns the message id peers should use for it.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// The `m` dictionary advertised in our extended handshake.
+    pub fn ids(&self) -> BTreeMap<String, u8> {
+        self.extensions
+            .iter()
+            .enumerate()
+            .map(|(i, ext)| (ext.name().to_string(), i as u8 + 1))
+            .collect()
+    }
+
+    /// Hands an extended message received from the peer to its extension.
+    pub fn dispatch(&mut self, peer: &mut Peer, id: u8, payload: &[u8]) {
+        if id == HANDSHAKE_ID {
+            if let Some(handshake) = peer.remote_extensions.clone() {
+                for extension in &mut self.extensions {
+                    extension.on_handshake(peer, &handshake);
+                }
+            }
+            return;
+        }
+        match self.extensions.get_mut(id as usize - 1) {
+            Some(extension) => extension.on_message(peer, payload),
+            None => println!("extension: unknown extended message {} from {}", id, peer),
+        }
+    }
+
+    pub fn tick(&mut self, peer: &mut Peer) {
+        for extension in &mut self.extensions {
+            extension.tick(peer);
+        }
+    }
+
+    pub fn disconnect(&mut self, addr: &SocketAddr) {
+        for extension in &mut self.extensions {
+            extension.on_disconnect(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert("ut_metadata".to_string(), 1);
+        handshake.m.insert("ut_pex".to_string(), 2);
+        handshake.v = Some("leech 0.2.9".to_string());
+        handshake.p = Some(6881);
+        handshake.reqq = Some(250);
+        handshake.yourip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        handshake.metadata_size = Some(31235);
+
+        let decoded = ExtendedHandshake::decode(&handshake.encode()).unwrap();
+        assert_eq!(handshake.m, decoded.m);
+        assert_eq!(handshake.v, decoded.v);
+        assert_eq!(handshake.p, decoded.p);
+        assert_eq!(handshake.reqq, decoded.reqq);
+        assert_eq!(handshake.yourip, decoded.yourip);
+        assert_eq!(handshake.metadata_size, decoded.metadata_size);
+    }
+
+    #[test]
+    fn disabled_extensions_are_dropped() {
+        let decoded = ExtendedHandshake::decode(b"d1:md11:ut_metadatai0e6:ut_pexi3eee").unwrap();
+        assert_eq!(None, decoded.m.get("ut_metadata"));
+        assert_eq!(Some(&3), decoded.m.get("ut_pex"));
+    }
+
+    #[test]
+    fn invalid_utf8_client_name() {
+        let decoded = ExtendedHandshake::decode(b"d1:md6:ut_pexi1ee1:v2:\xff\xfee").unwrap();
+        assert_eq!(Some("\u{fffd}\u{fffd}".to_string()), decoded.v);
+        assert_eq!(Some(&1), decoded.m.get("ut_pex"));
+    }
+
+    #[test]
+    fn out_of_range_values_are_dropped() {
+        for p in &["i0e", "i65536e", "i-1e", "i72001e"] {
+            let payload = format!("d1:md6:ut_pexi1ee1:p{}e", p);
+            assert_eq!(None, ExtendedHandshake::decode(payload.as_bytes()).unwrap().p);
+        }
+        assert_eq!(Some(65535), ExtendedHandshake::decode(b"d1:pi65535ee").unwrap().p);
+        let decoded = ExtendedHandshake::decode(b"d13:metadata_sizei-1e4:reqqi-5ee").unwrap();
+        assert_eq!(None, decoded.metadata_size);
+        assert_eq!(None, decoded.reqq);
+    }
+}
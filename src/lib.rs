@@ -1,12 +1,28 @@
 extern crate hyper;
 extern crate rustc_serialize;
 extern crate sha1;
+extern crate sha2;
+extern crate socket2;
 
 pub mod utils;
 pub mod magnet;
+pub mod metadata;
 pub mod bencoding;
+pub mod merkle;
 pub mod torrent;
+pub mod create;
 pub mod tracker;
+pub mod tracker_server;
 pub mod peer;
+pub mod extension;
+pub mod pex;
+pub mod dht;
+pub mod lsd;
 pub mod client;
+pub mod choker;
+pub mod picker;
+pub mod resume;
+pub mod storage;
+pub mod disk;
 pub mod error;
+pub mod config;
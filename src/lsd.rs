@@ -0,0 +1,223 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use peer::Message;
+use utils::*;
+
+/// IPv4 multicast group of Local Service Discovery (BEP 14)
+pub const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+
+/// IPv6 multicast group of Local Service Discovery
+pub const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// Port of the multicast groups
+pub const LSD_PORT: u16 = 6771;
+
+/// Seconds between our announces
+const ANNOUNCE_INTERVAL: u64 = 5 * 60;
+
+/// Finds peers on the local network through multicast announces
+pub struct Lsd {
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    info_hash: Hash,
+    listen_port: u16,
+    cookie: String,
+    last_announce: Option<Instant>,
+    sink: Sender<Message>,
+}
+
+impl Lsd {
+    pub fn new(info_hash: Hash, listen_port: u16, sink: Sender<Message>) -> io::Result<Lsd> {
+        let mut sockets = vec![];
+        match Self::bind_v4() {
+            Ok(socket) => sockets.push((socket, SocketAddr::new(IpAddr::V4(LSD_GROUP_V4), LSD_PORT))),
+            Err(err) => println!("lsd: unable to join the IPv4 group: {}", err),
+        }
+        match Self::bind_v6() {
+            Ok(socket) => sockets.push((socket, SocketAddr::new(IpAddr::V6(LSD_GROUP_V6), LSD_PORT))),
+            Err(err) => println!("lsd: unable to join the IPv6 group: {}", err),
+        }
+        if sockets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no multicast group joined"));
+        }
+
+        Ok(Lsd {
+            sockets: sockets,
+            info_hash: info_hash,
+            listen_port: listen_port,
+            cookie: format!("{:x}", random()),
+            last_announce: None,
+            sink: sink,
+        })
+    }
+
+    fn bind_v4() -> io::Result<UdpSocket> {
+        let socket = try!(bind_shared(SocketAddr::from(([0, 0, 0, 0], LSD_PORT))));
+        try!(socket.join_multicast_v4(&LSD_GROUP_V4, &Ipv4Addr::new(0, 0, 0, 0)));
+        try!(socket.set_multicast_loop_v4(true));
+        try!(socket.set_nonblocking(true));
+        Ok(socket)
+    }
+
+    fn bind_v6() -> io::Result<UdpSocket> {
+        let socket = try!(bind_shared(SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), LSD_PORT)));
+        try!(socket.join_multicast_v6(&LSD_GROUP_V6, 0));
+        try!(socket.set_nonblocking(true));
+        Ok(socket)
+    }
+
+    /// Sends our announce when it is due and hands the peers announced by others to the client.
+    pub fn step(&mut self) {
+        let is_due = self.last_announce
+            .map(|last| last.elapsed() >= Duration::from_secs(ANNOUNCE_INTERVAL))
+            .unwrap_or(true);
+        if is_due {
+            self.announce();
+        }
+
+        let mut buffer = [0; 1500];
+        let mut found = vec![];
+        for &(ref socket, _) in &self.sockets {
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, from)) => {
+                        if let Some(addr) = self.parse(&buffer[..len], from) {
+                            found.push(addr);
+                        }
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        println!("lsd: error while receiving {}", err);
+                        break;
+                    },
+                }
+            }
+        }
+
+        for addr in found {
+            println!("lsd: found local peer {}", addr);
+            let _ = self.sink.send(Message::AddPeer(addr));
+        }
+    }
+
+    fn announce(&mut self) {
+        for &(ref socket, group) in &self.sockets {
+            let message = format!("BT-SEARCH * HTTP/1.1\r\n\
+                                   Host: {}\r\n\
+                                   Port: {}\r\n\
+                                   Infohash: {}\r\n\
+                                   cookie: {}\r\n\
+                                   \r\n\r\n",
+                                  group, self.listen_port, self.info_hash, self.cookie);
+            if let Err(err) = socket.send_to(message.as_bytes(), group) {
+                println!("lsd: error while announcing to {}: {}", group, err);
+            }
+        }
+        self.last_announce = Some(Instant::now());
+    }
+
+    /// Parses an announce, returning the address of the peer if it is for our torrent.
+    fn parse(&self, data: &[u8], from: SocketAddr) -> Option<SocketAddr> {
+        let text = str::from_utf8(data).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let (mut port, mut cookie, mut matches) = (None, None, false);
+        let info_hash = format!("{}", self.info_hash);
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = parts.next().unwrap_or("").trim();
+            match key.as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => matches = matches || value.to_lowercase() == info_hash,
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {},
+            }
+        }
+
+        // Our own announces come back through the multicast loop
+        if cookie.as_ref() == Some(&self.cookie) || !matches {
+            return None;
+        }
+        match port {
+            Some(port) if port != 0 => Some(SocketAddr::new(from.ip(), port)),
+            _ => None,
+        }
+    }
+}
+
+/// Binds a UDP socket with SO_REUSEADDR so that the other LSD clients of the
+/// host can listen on the port too. IPv6 sockets are IPv6 only, otherwise the
+/// IPv6 one can't share the port with the IPv4 one.
+fn bind_shared(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = try!(Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP)));
+    try!(socket.set_reuse_address(true));
+    if addr.is_ipv6() {
+        try!(socket.set_only_v6(true));
+    }
+    try!(socket.bind(&addr.into()));
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn lsd(info_hash: Hash) -> Lsd {
+        Lsd {
+            sockets: vec![],
+            info_hash: info_hash,
+            listen_port: 6881,
+            cookie: "ours".to_string(),
+            last_announce: None,
+            sink: channel().0,
+        }
+    }
+
+    #[test]
+    fn parse_announce() {
+        let lsd = lsd(Hash([0xab; 20]));
+        let from = "192.168.1.5:6771".parse::<SocketAddr>().unwrap();
+        let announce = |info_hash: &str, port: &str, cookie: &str| {
+            format!("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n", port, info_hash, cookie)
+        };
+        let ours = format!("{}", Hash([0xab; 20]));
+
+        let addr = lsd.parse(announce(&ours, "51413", "theirs").as_bytes(), from);
+        assert_eq!(addr, Some("192.168.1.5:51413".parse().unwrap()));
+        let addr = lsd.parse(announce(&ours.to_uppercase(), "51413", "theirs").as_bytes(), from);
+        assert_eq!(addr, Some("192.168.1.5:51413".parse().unwrap()));
+
+        assert_eq!(lsd.parse(announce(&format!("{}", Hash([1; 20])), "51413", "theirs").as_bytes(), from), None);
+        assert_eq!(lsd.parse(announce(&ours, "0", "theirs").as_bytes(), from), None);
+        assert_eq!(lsd.parse(announce(&ours, "port", "theirs").as_bytes(), from), None);
+        assert_eq!(lsd.parse(b"NOTIFY * HTTP/1.1\r\n\r\n", from), None);
+        assert_eq!(lsd.parse(b"BT-SEARCH * HTTP/1.1\r\n\xff\r\n", from), None);
+    }
+
+    #[test]
+    fn ignore_own_announce() {
+        let lsd = lsd(Hash([0xab; 20]));
+        let from = "192.168.1.2:6771".parse::<SocketAddr>().unwrap();
+        let message = format!("BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\ncookie: ours\r\n\r\n\r\n", Hash([0xab; 20]));
+        assert_eq!(lsd.parse(message.as_bytes(), from), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn share_port() {
+        let first = bind_shared(SocketAddr::from(([0, 0, 0, 0], 0))).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(bind_shared(SocketAddr::from(([0, 0, 0, 0], port))).is_ok());
+        assert!(bind_shared(SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), port)).is_ok());
+    }
+}
@@ -1,3 +1,5 @@
+use utils::*;
+
 #[derive(Default)]
 pub struct Magnet {
     pub xt: String,
@@ -24,15 +26,70 @@ impl Magnet {
             let value = key_val.get(1).unwrap().clone();
 
             match key {
-                "xt" => magnet.xt = value.to_string(),
+                // Hybrid links carry both topics, the v1 one names the swarm
+                "xt" if !magnet.xt.starts_with("urn:btih:") => magnet.xt = value.to_string(),
                 "dn" => magnet.dn = value.to_string(),
-                "tr" => magnet.tr.push(value.to_string()),
+                "tr" => magnet.tr.push(String::from_utf8_lossy(&url_decode(value)).into_owned()),
                 _ => {}
             }
         }
 
         Ok(magnet)
     }
+
+    /// Parses the info hash from the `urn:btih:` topic, in hex or base32 form,
+    /// or truncates the SHA-256 multihash of a `urn:btmh:` topic (BEP 52).
+    pub fn info_hash(&self) -> Result<Hash, &str> {
+        if self.xt.starts_with("urn:btmh:1220") {
+            return match hex_decode(self.xt[13..].as_bytes()) {
+                Some(ref bytes) if bytes.len() == 32 => Ok(Hash256::from_slice(bytes).truncated()),
+                _ => Err("Invalid info hash in magnet link"),
+            };
+        }
+        if !self.xt.starts_with("urn:btih:") {
+            return Err("Exact topic should start with 'urn:btih:'");
+        }
+        let hash = &self.xt[9..];
+        let bytes = match hash.len() {
+            40 => hex_decode(hash.as_bytes()),
+            32 => base32_decode(hash),
+            _ => None,
+        };
+        match bytes {
+            Some(ref bytes) if bytes.len() == 20 => Ok(Hash::from_slice(bytes)),
+            _ => Err("Invalid info hash in magnet link"),
+        }
+    }
+}
+
+fn hex_decode(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 {
+        return None;
+    }
+    input.chunks(2).map(|pair| {
+        let hex = ::std::str::from_utf8(pair).ok()?;
+        u8::from_str_radix(hex, 16).ok()
+    }).collect()
+}
+
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in input.to_uppercase().bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
 }
 
 #[cfg(test)]
@@ -58,4 +115,26 @@ mod tests {
         assert_eq!("udp://tracker.leechers-paradise.org:6969", magnet.tr[1]);
         assert_eq!("udp://open.demonii.com:1337", magnet.tr[2]);
     }
+
+    #[test]
+    fn info_hash() {
+        let hex = Magnet::new("magnet:?xt=urn:btih:99E0511CCB7622664F09B6CD16AAB10AC9DB7104").unwrap();
+        assert_eq!("99e0511ccb7622664f09b6cd16aab10ac9db7104", format!("{}", hex.info_hash().unwrap()));
+
+        let base32 = Magnet::new("magnet:?xt=urn:btih:THQFCHGLOYRGMTYJW3GRNKVRBLE5W4IE&tr=udp%3A%2F%2Ftracker.example.org%3A6969").unwrap();
+        assert_eq!("99e0511ccb7622664f09b6cd16aab10ac9db7104", format!("{}", base32.info_hash().unwrap()));
+        assert_eq!("udp://tracker.example.org:6969", base32.tr[0]);
+
+        let escaped = Magnet::new("magnet:?xt=urn:btih:THQFCHGLOYRGMTYJW3GRNKVRBLE5W4IE&tr=http%3A%2F%2Ft.example.org%2Fa+b%2").unwrap();
+        assert_eq!("http://t.example.org/a+b%2", escaped.tr[0]);
+        let escaped = Magnet::new("magnet:?xt=urn:btih:THQFCHGLOYRGMTYJW3GRNKVRBLE5W4IE&tr=http%3A%2F%2Ft.example.org%2F%C3%A9%2F").unwrap();
+        assert_eq!("http://t.example.org/\u{e9}/", escaped.tr[0]);
+
+        let v2 = Magnet::new("magnet:?xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e").unwrap();
+        assert_eq!("caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa", format!("{}", v2.info_hash().unwrap()));
+
+        let hybrid = Magnet::new("magnet:?xt=urn:btih:99E0511CCB7622664F09B6CD16AAB10AC9DB7104\
+                                  &xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e").unwrap();
+        assert_eq!("99e0511ccb7622664f09b6cd16aab10ac9db7104", format!("{}", hybrid.info_hash().unwrap()));
+    }
 }
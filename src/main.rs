@@ -1,14 +1,189 @@
+extern crate ctrlc;
 extern crate leech;
-use leech::client::Client;
+
+use std::process;
+
+use leech::client::{Client, ClientHandle};
+use leech::config::Config;
+use leech::create::TorrentBuilder;
+use leech::torrent::Torrent;
+use leech::tracker_server::TrackerServer;
+
+fn usage(program: &str) {
+    println!("leech: usage: {} [options] <torrent file | magnet link>", program);
+    println!("       {} [options] scrape <torrent file | magnet link>", program);
+    println!("       {} [options] trackers <torrent file | magnet link>", program);
+    println!("       {} --port <port> [--whitelist <file>] tracker", program);
+    println!("       {} create [create options] <file | directory>", program);
+    println!("options:");
+    println!("    --config <file>          bencoded settings file");
+    println!("    --download-dir <dir>     directory to download to (default /tmp)");
+    println!("    --port <port>            port to accept peer connections on");
+    println!("    --tracker-port <port>    local port for UDP trackers");
+    println!("    --udp-retransmissions <n>  retries of a UDP tracker request, at most 8 (default)");
+    println!("    --announce-all-tiers <true|false>  announce to every tracker tier in parallel");
+    println!("    --max-peers <n>          maximum number of connected peers");
+    println!("    --max-seeders <n>        maximum number of peers to download from");
+    println!("    --max-requests <n>       outstanding block requests per peer");
+    println!("    --save-torrent <file>    save the metadata of a magnet link as a .torrent");
+    println!("    --dht-port <port>        UDP port of the DHT node, 0 disables it");
+    println!("    --dht-bootstrap <node>   host:port of a DHT bootstrap node (repeatable)");
+    println!("    --dht-state <file>       file to save the DHT routing table to");
+    println!("    --lsd <true|false>       look for peers on the local network");
+    println!("    --whitelist <file>       info hashes the tracker serves, one hex hash per line");
+    println!("    --disk-threads <n>       threads doing the disk reads and writes");
+    println!("    --read-cache-size <bytes>  memory used to cache pieces for uploads");
+    println!("    --only <files>           download only these files, indices or globs like 2,5,*.mkv");
+    println!("    --priority <files>=<level>  priority of files: skip, low, normal or high (repeatable)");
+    println!("    --download-mode <mode>   rarest-first, sequential or streaming");
+    println!("    --streaming-window <n>   pieces ahead of the read cursor with deadlines");
+    println!("create options:");
+    println!("    --output <file>          where to write the .torrent (default <name>.torrent)");
+    println!("    --tracker <url[,url]>    tier of trackers (repeatable)");
+    println!("    --comment <text>         comment of the torrent");
+    println!("    --private                only get peers from the trackers");
+    println!("    --web-seed <url>         url the data can also be downloaded from (repeatable)");
+    println!("    --source <text>          source tag of the torrent");
+    println!("    --piece-length <bytes>   piece length, picked from the size by default");
+    println!("    --threads <n>            threads hashing the pieces");
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let file = match args.len() {
-        2 => args[1].to_string(),
-        _ => {
-            println!("leech: usage: {} <torrent file>", args[0]);
+    if args.get(1).map(|arg| arg.as_str()) == Some("create") {
+        if let Err(err) = create(&args[2..]) {
+            println!("leech: {}", err);
+            usage(&args[0]);
+        }
+        return;
+    }
+    let (config, rest) = match Config::from_args(&args[1..]) {
+        Ok(result) => result,
+        Err(err) => {
+            println!("leech: {:?}", err);
+            usage(&args[0]);
+            return;
+        },
+    };
+    match rest.len() {
+        1 if rest[0] == "tracker" => tracker(&config),
+        1 => {
+            let mut client = Client::new(&rest[0], config);
+            stop_on_signal(client.handle());
+            client.start();
+        },
+        2 if rest[0] == "scrape" => scrape(&rest[1], &config),
+        2 if rest[0] == "trackers" => trackers(&rest[1], &config),
+        _ => usage(&args[0]),
+    }
+}
+
+/// Shuts the client down on Ctrl-C or SIGTERM so that the resume data is saved
+/// and the trackers get our `stopped` announce. A second signal kills us.
+fn stop_on_signal(handle: ClientHandle) {
+    let mut stopping = false;
+    let result = ctrlc::set_handler(move || {
+        if stopping {
+            process::exit(1);
+        }
+        stopping = true;
+        println!("leech: stopping, interrupt again to quit right away");
+        handle.shutdown();
+    });
+    if let Err(err) = result {
+        println!("leech: unable to handle signals: {}", err);
+    }
+}
+
+fn scrape(file: &str, config: &Config) {
+    let tracker = match Torrent::read_tracker(file, config) {
+        Ok(tracker) => tracker,
+        Err(err) => {
+            println!("leech: {:?}", err);
             return;
         },
     };
-    Client::new(&file).start();
+    match tracker.scrape() {
+        Some(stats) => {
+            println!("seeders: {}", stats.complete);
+            println!("leechers: {}", stats.incomplete);
+            println!("completed: {}", stats.downloaded);
+        },
+        None => println!("leech: no tracker answered the scrape for {}", tracker),
+    }
+}
+
+fn trackers(file: &str, config: &Config) {
+    let tracker = match Torrent::read_tracker(file, config) {
+        Ok(tracker) => tracker,
+        Err(err) => {
+            println!("leech: {:?}", err);
+            return;
+        },
+    };
+    for status in tracker.probe() {
+        println!("{}", status);
+    }
+}
+
+fn tracker(config: &Config) {
+    let mut server = TrackerServer::new();
+    if let Some(ref path) = config.tracker_whitelist {
+        match TrackerServer::load_whitelist(path) {
+            Ok(info_hashes) => server.set_whitelist(info_hashes),
+            Err(err) => {
+                println!("leech: unable to read the whitelist: {}", err);
+                return;
+            },
+        }
+    }
+    if let Err(err) = server.run(config.listen_port) {
+        println!("leech: {}", err);
+    }
+}
+
+fn create(args: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut output = None;
+    let mut builder_args = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            path = Some(arg.clone());
+            continue;
+        }
+        if arg == "--private" {
+            builder_args.push((arg.clone(), String::new()));
+            continue;
+        }
+        let value = try!(iter.next().ok_or(format!("missing value for {}", arg)));
+        if arg == "--output" {
+            output = Some(value.clone());
+        } else {
+            builder_args.push((arg.clone(), value.clone()));
+        }
+    }
+
+    let path = try!(path.ok_or("missing file or directory".to_string()));
+    let mut builder = TorrentBuilder::new(&path);
+    for (arg, value) in builder_args {
+        builder = match arg.as_str() {
+            "--tracker" => builder.tier(value.split(',').map(|url| url.to_string()).collect()),
+            "--comment" => builder.comment(&value),
+            "--private" => builder.private(true),
+            "--web-seed" => builder.web_seed(&value),
+            "--source" => builder.source(&value),
+            "--piece-length" => builder.piece_length(try!(value.parse().map_err(|_| format!("invalid value {} for {}", value, arg)))),
+            "--threads" => builder.threads(try!(value.parse().map_err(|_| format!("invalid value {} for {}", value, arg)))),
+            _ => return Err(format!("unknown flag {}", arg)),
+        };
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let name = std::path::Path::new(&path).file_name().map(|name| name.to_string_lossy().into_owned());
+        format!("{}.torrent", name.unwrap_or("out".to_string()))
+    });
+    let info_hash = try!(builder.write(&output).map_err(|err| format!("{:?}", err)));
+    println!("leech: wrote {} with info hash {}", output, info_hash);
+    Ok(())
 }
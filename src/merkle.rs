@@ -0,0 +1,303 @@
+use std::cmp;
+
+use utils::*;
+
+/// Size of the blocks hashed into the leaves of the merkle trees (BEP 52)
+pub const MERKLE_BLOCK_SIZE: usize = 16384;
+
+/// Most hashes a peer may ask for in one hash request
+pub const MAX_HASHES: usize = 512;
+
+/// Hashes two sibling nodes into their parent
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut data = [0; 64];
+    data[..32].copy_from_slice(&left.0);
+    data[32..].copy_from_slice(&right.0);
+    Hash256(sha256(&data))
+}
+
+/// Leaf hashes of the data, one per 16 KiB block
+pub fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(|block| Hash256(sha256(block))).collect()
+}
+
+/// Root of a subtree of `leaves` zero leaves
+pub fn pad_hash(leaves: usize) -> Hash256 {
+    let mut pad = Hash256::default();
+    let mut width = 1;
+    while width < leaves {
+        pad = hash_pair(&pad, &pad);
+        width *= 2;
+    }
+    pad
+}
+
+/// Root of the tree over the nodes, padded with `pad` up to `width` nodes,
+/// a power of two.
+pub fn root(nodes: &[Hash256], width: usize, pad: Hash256) -> Hash256 {
+    let mut layer = nodes.to_vec();
+    let mut pad = pad;
+    let mut width = cmp::max(width, 1);
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().cloned().unwrap_or(pad)
+}
+
+/// Layer of the tree above the nodes, padded with `pad`
+fn parent_layer(nodes: &[Hash256], pad: Hash256) -> Vec<Hash256> {
+    let mut nodes = nodes.to_vec();
+    if nodes.len() % 2 == 1 {
+        nodes.push(pad);
+    }
+    nodes.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect()
+}
+
+/// Checks hashes of a layer against the root using the uncle hashes of the
+/// proof. `index` is the position of the first hash in its layer and the
+/// number of hashes is a power of two.
+pub fn verify_proof(hashes: &[Hash256], index: usize, proof: &[Hash256], expected: &Hash256) -> bool {
+    if hashes.is_empty() || !hashes.len().is_power_of_two() || index % hashes.len() != 0 {
+        return false;
+    }
+    let mut node = root(hashes, hashes.len(), Hash256::default());
+    let mut position = index / hashes.len();
+    for uncle in proof {
+        node = if position % 2 == 0 { hash_pair(&node, uncle) } else { hash_pair(uncle, &node) };
+        position /= 2;
+    }
+    position == 0 && node == *expected
+}
+
+/// Request for hashes of a file's merkle tree (BEP 52)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HashRequest {
+    pub pieces_root: Hash256,
+    /// Layer of the hashes, 0 being the leaves
+    pub base_layer: usize,
+    pub index: usize,
+    pub length: usize,
+    /// Number of uncle layers to include above the hashes
+    pub proof_layers: usize,
+}
+
+impl HashRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.pieces_root.0.to_vec();
+        data.extend(u32_to_byte_slice(self.base_layer as u32));
+        data.extend(u32_to_byte_slice(self.index as u32));
+        data.extend(u32_to_byte_slice(self.length as u32));
+        data.extend(u32_to_byte_slice(self.proof_layers as u32));
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<HashRequest> {
+        if data.len() < 48 {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: Hash256::from_slice(&data[0..32]),
+            base_layer: byte_slice_to_u32(&data[32..36]) as usize,
+            index: byte_slice_to_u32(&data[36..40]) as usize,
+            length: byte_slice_to_u32(&data[40..44]) as usize,
+            proof_layers: byte_slice_to_u32(&data[44..48]) as usize,
+        })
+    }
+}
+
+/// A file of a v2 torrent, verified against the root of its merkle tree
+#[derive(Clone, Debug)]
+pub struct MerkleFile {
+    /// Index of the file in the torrent's files
+    pub file: usize,
+    pub length: usize,
+    pub pieces_root: Hash256,
+    pub first_piece: usize,
+    pub no_of_pieces: usize,
+    /// Hashes of the file's pieces, only needed when it spans several pieces
+    pub piece_layer: Vec<Hash256>,
+    /// Which chunks of `MAX_HASHES` piece hashes are known
+    layer_chunks: Vec<bool>,
+}
+
+impl MerkleFile {
+    pub fn new(file: usize, length: usize, pieces_root: Hash256, first_piece: usize, piece_length: usize) -> MerkleFile {
+        let no_of_pieces = (length + piece_length - 1) / piece_length;
+        let chunks = if no_of_pieces > 1 { (no_of_pieces + MAX_HASHES - 1) / MAX_HASHES } else { 0 };
+        MerkleFile {
+            file: file,
+            length: length,
+            pieces_root: pieces_root,
+            first_piece: first_piece,
+            no_of_pieces: no_of_pieces,
+            piece_layer: vec![Hash256::default(); if no_of_pieces > 1 { no_of_pieces } else { 0 }],
+            layer_chunks: vec![false; chunks],
+        }
+    }
+
+    /// Layer of the piece hashes in the tree
+    pub fn piece_level(piece_length: usize) -> usize {
+        (piece_length / MERKLE_BLOCK_SIZE).trailing_zeros() as usize
+    }
+
+    /// Number of nodes of the piece layer once padded to a full tree
+    fn layer_width(&self) -> usize {
+        self.no_of_pieces.next_power_of_two()
+    }
+
+    pub fn has_piece_layer(&self) -> bool {
+        self.layer_chunks.iter().all(|&known| known)
+    }
+
+    /// Whether the hash of a piece of the file is known, a file of one piece
+    /// is hashed by its root.
+    pub fn has_piece_hash(&self, piece: usize) -> bool {
+        self.no_of_pieces == 1 || self.layer_chunks.get(piece / MAX_HASHES) == Some(&true)
+    }
+
+    /// Sets the piece layer from the `piece layers` of the .torrent, checking it against the root.
+    pub fn set_piece_layer(&mut self, layer: Vec<Hash256>, piece_length: usize) -> bool {
+        if layer.len() != self.no_of_pieces || self.no_of_pieces <= 1 {
+            return false;
+        }
+        let pad = pad_hash(piece_length / MERKLE_BLOCK_SIZE);
+        if root(&layer, self.layer_width(), pad) != self.pieces_root {
+            return false;
+        }
+        self.piece_layer = layer;
+        for known in self.layer_chunks.iter_mut() {
+            *known = true;
+        }
+        true
+    }
+
+    /// Verifies the data of a piece of the file, without the padding past the end of the file.
+    pub fn verify_piece(&self, piece: usize, data: &[u8], piece_length: usize) -> bool {
+        let leaves = block_hashes(data);
+        if self.no_of_pieces == 1 {
+            // Files of one piece have no piece layer, their tree is only as wide as needed
+            return root(&leaves, leaves.len().next_power_of_two(), Hash256::default()) == self.pieces_root;
+        }
+        if !self.has_piece_hash(piece) {
+            return false;
+        }
+        root(&leaves, piece_length / MERKLE_BLOCK_SIZE, Hash256::default()) == self.piece_layer[piece]
+    }
+
+    /// Next request for an unknown chunk of the piece layer.
+    pub fn next_hash_request(&self, piece_length: usize, skip: &[HashRequest]) -> Option<HashRequest> {
+        let length = cmp::min(MAX_HASHES, self.layer_width());
+        let proof_layers = (self.layer_width() / length).trailing_zeros() as usize;
+        self.layer_chunks.iter().enumerate()
+            .filter(|&(_, &known)| !known)
+            .map(|(chunk, _)| HashRequest {
+                pieces_root: self.pieces_root,
+                base_layer: Self::piece_level(piece_length),
+                index: chunk * length,
+                length: length,
+                proof_layers: proof_layers,
+            })
+            .find(|request| !skip.contains(request))
+    }
+
+    /// Stores the piece hashes of a hashes message if they prove to be part of the tree.
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash256], piece_length: usize) -> bool {
+        if request.base_layer != Self::piece_level(piece_length) || hashes.len() < request.length
+            || request.length == 0 || request.index >= self.no_of_pieces {
+            return false;
+        }
+        let (layer, proof) = hashes.split_at(request.length);
+        if !verify_proof(layer, request.index, proof, &self.pieces_root) {
+            return false;
+        }
+        let end = cmp::min(request.index + layer.len(), self.no_of_pieces);
+        self.piece_layer[request.index..end].copy_from_slice(&layer[..end - request.index]);
+        if request.length == cmp::min(MAX_HASHES, self.layer_width()) {
+            self.layer_chunks[request.index / request.length] = true;
+        }
+        true
+    }
+
+    /// Hashes and uncle hashes answering a request for the piece layer, if we know it.
+    pub fn serve_hashes(&self, request: &HashRequest, piece_length: usize) -> Option<Vec<Hash256>> {
+        if !self.has_piece_layer() || self.no_of_pieces <= 1
+            || request.base_layer != Self::piece_level(piece_length)
+            || request.length < 2 || !request.length.is_power_of_two() || request.length > MAX_HASHES
+            || request.index % request.length != 0 || request.index + request.length > self.layer_width() {
+            return None;
+        }
+
+        let mut pad = pad_hash(piece_length / MERKLE_BLOCK_SIZE);
+        let mut layer = self.piece_layer.clone();
+        layer.resize(self.layer_width(), pad);
+        let mut hashes = layer[request.index..request.index + request.length].to_vec();
+
+        // Climb to the layer above the requested hashes, then add one uncle per layer
+        let mut position = request.index;
+        let mut width = 1;
+        while width < request.length {
+            layer = parent_layer(&layer, pad);
+            pad = hash_pair(&pad, &pad);
+            position /= 2;
+            width *= 2;
+        }
+        for _ in 0..request.proof_layers {
+            if layer.len() <= 1 {
+                break;
+            }
+            hashes.push(layer[position ^ 1]);
+            layer = parent_layer(&layer, pad);
+            pad = hash_pair(&pad, &pad);
+            position /= 2;
+        }
+        Some(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_layer_and_proofs() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let data: Vec<u8> = (0..5 * MERKLE_BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let leaves = block_hashes(&data);
+        let pieces_root = root(&leaves, leaves.len().next_power_of_two(), Hash256::default());
+        let layer: Vec<_> = leaves.chunks(2).map(|pair| root(pair, 2, Hash256::default())).collect();
+
+        let mut file = MerkleFile::new(0, data.len(), pieces_root, 0, piece_length);
+        assert_eq!(file.no_of_pieces, 3);
+        assert!(!file.has_piece_layer());
+        assert!(file.set_piece_layer(layer.clone(), piece_length));
+        for (piece, data) in data.chunks(piece_length).enumerate() {
+            assert!(file.verify_piece(piece, data, piece_length));
+        }
+        assert!(!file.verify_piece(1, &data[..piece_length], piece_length));
+
+        // Another peer learns the layer from our hashes message
+        let mut other = MerkleFile::new(0, data.len(), pieces_root, 0, piece_length);
+        let request = other.next_hash_request(piece_length, &[]).unwrap();
+        assert_eq!((request.index, request.length, request.proof_layers), (0, 4, 0));
+        let hashes = file.serve_hashes(&request, piece_length).unwrap();
+        assert!(other.add_hashes(&request, &hashes, piece_length));
+        assert!(other.has_piece_layer());
+        assert_eq!(other.piece_layer, layer);
+
+        // Proofs for part of the layer
+        let request = HashRequest { pieces_root: pieces_root, base_layer: 1, index: 2, length: 2, proof_layers: 1 };
+        let hashes = file.serve_hashes(&request, piece_length).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert!(verify_proof(&hashes[..2], 2, &hashes[2..], &pieces_root));
+        assert!(!verify_proof(&hashes[..2], 0, &hashes[2..], &pieces_root));
+
+        // Requests running past the end of the layer are rejected
+        let request = HashRequest { pieces_root: pieces_root, base_layer: 1, index: 0, length: 8, proof_layers: 0 };
+        assert_eq!(file.serve_hashes(&request, piece_length), None);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use bencoding::*;
+use error::{Error, Result};
+use extension::{self, Extension, ExtendedHandshake};
+use peer::Peer;
+use utils::*;
+
+/// Size of each metadata piece exchanged with ut_metadata (BEP 9)
+const METADATA_PIECE_SIZE: usize = 16384;
+
+/// Largest info dictionary we are willing to download
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Our message id for ut_metadata, announced in the extended handshake
+const UT_METADATA_ID: u8 = 1;
+
+/// Serves the info dictionary to peers that ask for it (BEP 9)
+pub struct MetadataExtension {
+    metadata: Vec<u8>,
+}
+
+impl MetadataExtension {
+    pub fn new(metadata: Vec<u8>) -> MetadataExtension {
+        MetadataExtension {
+            metadata: metadata,
+        }
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn on_message(&mut self, peer: &mut Peer, payload: &[u8]) {
+        let header = match BEncoding::decode_prefix(payload) {
+            Some((header, _)) => header,
+            None => return,
+        };
+        // Only requests are handled, we never ask connected peers for metadata
+        if header.get_int("msg_type").ok() != Some(0) {
+            return;
+        }
+        let piece = match header.get_int("piece") {
+            Ok(piece) if piece >= 0 => piece as usize,
+            _ => return,
+        };
+
+        let mut response = BTreeMap::new();
+        response.insert("piece".to_string(), BEncoding::Int(piece as i64));
+        // Checked before multiplying so a huge piece number can't overflow
+        if piece >= (self.metadata.len() + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE {
+            response.insert("msg_type".to_string(), BEncoding::Int(2));
+            peer.send_extended(self.name(), BEncoding::encode(&BEncoding::Dict(response)));
+            return;
+        }
+
+        let start = piece * METADATA_PIECE_SIZE;
+        let end = ::std::cmp::min(start + METADATA_PIECE_SIZE, self.metadata.len());
+        response.insert("msg_type".to_string(), BEncoding::Int(1));
+        response.insert("total_size".to_string(), BEncoding::Int(self.metadata.len() as i64));
+        let mut data = BEncoding::encode(&BEncoding::Dict(response));
+        data.extend_from_slice(&self.metadata[start..end]);
+        peer.send_extended(self.name(), data);
+    }
+}
+
+/// Downloads the info dictionary of a torrent from the given peers.
+///
+/// The peers are tried one after another until one of them sends the complete
+/// metadata, which is verified against the info hash before it is returned.
+pub fn fetch(info_hash: &Hash, peers: &[SocketAddr]) -> Result<Vec<u8>> {
+    for addr in peers {
+        match fetch_from_peer(info_hash, addr) {
+            Ok(metadata) => {
+                println!("metadata: received {} bytes from {}", metadata.len(), addr);
+                return Ok(metadata);
+            },
+            Err(err) => {
+                println!("metadata: error while fetching from {}: {:?}", addr, err);
+                continue;
+            },
+        }
+    }
+    Err(Error::Metadata("no peer sent the metadata".into()))
+}
+
+fn fetch_from_peer(info_hash: &Hash, addr: &SocketAddr) -> Result<Vec<u8>> {
+    let mut socket = try!(TcpStream::connect_timeout(addr, Duration::from_secs(5)));
+    try!(socket.set_read_timeout(Some(Duration::from_secs(10))));
+    try!(socket.set_write_timeout(Some(Duration::from_secs(10))));
+
+    // Handshake with the extension protocol bit set
+    let mut data: Vec<u8> = vec![];
+    data.push(19);
+    data.extend_from_slice(b"BitTorrent protocol");
+    let mut reserved = [0; 8];
+    reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+    data.extend_from_slice(&reserved);
+    data.extend_from_slice(&info_hash.0);
+    data.extend_from_slice(&MY_PEER_ID.0);
+    try!(socket.write_all(&data));
+
+    let mut handshake = [0; 68];
+    try!(socket.read_exact(&mut handshake));
+    if Hash::from_slice(&handshake[28..48]) != *info_hash {
+        return Err(Error::Metadata("invalid info hash in handshake".into()));
+    }
+    if handshake[20 + extension::RESERVED_BYTE] & extension::RESERVED_BIT == 0 {
+        return Err(Error::Metadata("extension protocol not supported".into()));
+    }
+
+    let mut handshake = ExtendedHandshake::default();
+    handshake.m.insert("ut_metadata".to_string(), UT_METADATA_ID);
+    try!(send_extended(&mut socket, extension::HANDSHAKE_ID, &handshake.encode()));
+
+    // Wait for the peer's extended handshake to learn its ut_metadata id and the size
+    let (remote_id, size) = loop {
+        let (id, payload) = try!(recv_extended(&mut socket));
+        if id != extension::HANDSHAKE_ID {
+            continue;
+        }
+        let handshake = try!(ExtendedHandshake::decode(&payload).ok_or(Error::Metadata("invalid extended handshake".into())));
+        match (handshake.m.get("ut_metadata"), handshake.metadata_size) {
+            (Some(&id), Some(size)) if size > 0 && size <= MAX_METADATA_SIZE => break (id, size),
+            _ => return Err(Error::Metadata("peer doesn't serve metadata".into())),
+        }
+    };
+
+    let no_of_pieces = (size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+    let mut metadata = vec![0; size];
+    for piece in 0..no_of_pieces {
+        let mut request = BTreeMap::new();
+        request.insert("msg_type".to_string(), BEncoding::Int(0));
+        request.insert("piece".to_string(), BEncoding::Int(piece as i64));
+        try!(send_extended(&mut socket, remote_id, &BEncoding::encode(&BEncoding::Dict(request))));
+
+        loop {
+            let (id, payload) = try!(recv_extended(&mut socket));
+            if id != UT_METADATA_ID {
+                continue;
+            }
+            let (header, len) = try!(BEncoding::decode_prefix(&payload).ok_or(Error::Metadata("invalid metadata message".into())));
+            match try!(header.get_int("msg_type")) {
+                1 if try!(header.get_int("piece")) == piece as i64 => {
+                    let start = piece * METADATA_PIECE_SIZE;
+                    let end = start + payload.len() - len;
+                    if end > size {
+                        return Err(Error::Metadata("metadata piece is too large".into()));
+                    }
+                    metadata[start..end].copy_from_slice(&payload[len..]);
+                    break;
+                },
+                2 => return Err(Error::Metadata("metadata piece rejected".into())),
+                _ => continue,
+            }
+        }
+    }
+
+    // Pure v2 torrents go by the truncated SHA-256 of their info
+    if Hash::from_slice(&sha1(&metadata)) != *info_hash && Hash256(sha256(&metadata)).truncated() != *info_hash {
+        return Err(Error::Metadata("metadata doesn't match the info hash".into()));
+    }
+    Ok(metadata)
+}
+
+fn send_extended(socket: &mut TcpStream, id: u8, payload: &[u8]) -> Result<()> {
+    let mut data = u32_to_byte_slice(payload.len() as u32 + 2);
+    data.push(20);
+    data.push(id);
+    data.extend_from_slice(payload);
+    try!(socket.write_all(&data));
+    Ok(())
+}
+
+/// Reads messages until an extended one arrives, returning its id and payload.
+fn recv_extended(socket: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    loop {
+        let mut length = [0; 4];
+        try!(socket.read_exact(&mut length));
+        let length = byte_slice_to_u32(&length) as usize;
+        if length > MAX_METADATA_SIZE {
+            return Err(Error::Metadata("message is too large".into()));
+        }
+        let mut message = vec![0; length];
+        try!(socket.read_exact(&mut message));
+        if length >= 2 && message[0] == 20 {
+            return Ok((message[1], message[2..].to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use config::Config;
+    use peer::Message;
+    use torrent::Torrent;
+
+    #[test]
+    fn out_of_range_requests_are_rejected() {
+        let torrent = Torrent::in_memory(&[0; 10], 16384, &Config::default());
+
+        let (tx, rx) = channel();
+        let mut peer = Peer::new("10.0.0.1:6881".parse().unwrap(), &torrent, ExtendedHandshake::default(), tx, channel().0);
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert("ut_metadata".to_string(), 3);
+        peer.remote_extensions = Some(handshake);
+        let _ = rx.try_recv();
+
+        let metadata = vec![7; METADATA_PIECE_SIZE + 10];
+        let mut extension = MetadataExtension::new(metadata.clone());
+        let mut response = |piece: i64| {
+            extension.on_message(&mut peer, format!("d8:msg_typei0e5:piecei{}ee", piece).as_bytes());
+            match rx.try_recv() {
+                Ok(Message::Data(_, data)) => {
+                    let (header, len) = BEncoding::decode_prefix(&data[6..]).unwrap();
+                    (header.get_int("msg_type").unwrap(), data[6 + len..].to_vec())
+                },
+                _ => panic!("no response to piece {}", piece),
+            }
+        };
+        assert_eq!(response(1), (1, metadata[METADATA_PIECE_SIZE..].to_vec()));
+        assert_eq!(response(2).0, 2);
+        assert_eq!(response(i64::max_value()).0, 2);
+    }
+}
@@ -3,12 +3,18 @@ use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::fmt;
 use std::mem;
 use std::collections::{HashMap, VecDeque};
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{channel, Sender, Receiver};
 use std::time::{Duration, Instant};
 use std::thread;
 
 use utils::*;
 use torrent::*;
+use merkle::HashRequest;
+use extension::{self, ExtendedHandshake};
+
+/// Reserved bit announcing support for v2 torrents (BEP 52)
+const V2_RESERVED_BYTE: usize = 7;
+const V2_RESERVED_BIT: u8 = 0x10;
 
 // BitTorrent message types
 #[derive(Debug)]
@@ -27,6 +33,10 @@ pub enum MessageType {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    Extended = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
 #[allow(unused_comparisons)]
@@ -34,6 +44,14 @@ impl From<u8> for MessageType {
     fn from(id: u8) -> Self {
         if 0 <= id && id <= 9 {
             unsafe { mem::transmute(id) }
+        } else if id == 20 {
+            MessageType::Extended
+        } else if id == 21 {
+            MessageType::HashRequest
+        } else if id == 22 {
+            MessageType::Hashes
+        } else if id == 23 {
+            MessageType::HashReject
         } else {
             MessageType::Unknown
         }
@@ -68,8 +86,15 @@ impl Connection {
     }
 
     fn writable(&mut self) -> io::Result<()> {
-        while let Some(data) = self.send_queue.pop_back() {
+        while let Some(mut data) = self.send_queue.pop_back() {
             match self.socket.write(&data) {
+                Ok(len) if len < data.len() => {
+                    // Partial write, keep the rest for the next round
+                    println!("connection: wrote {} of {} bytes", len, data.len());
+                    data.drain(0..len);
+                    self.send_queue.push_back(data);
+                    return Ok(());
+                }
                 Ok(len) => {
                     println!("connection: wrote {} bytes", len);
                 }
@@ -119,6 +144,8 @@ pub struct Handler {
     data_channel: Sender<Message>,
     notifications: Receiver<Message>,
     disconnects: Vec<SocketAddr>,
+    connecting: Vec<SocketAddr>,
+    connected: (Sender<(SocketAddr, io::Result<TcpStream>)>, Receiver<(SocketAddr, io::Result<TcpStream>)>),
 }
 
 
@@ -130,6 +157,8 @@ impl Handler {
             data_channel: chn,
             notifications: notifications,
             disconnects: vec![],
+            connecting: vec![],
+            connected: channel(),
         }
     }
 
@@ -155,7 +184,19 @@ impl Handler {
             // accept new connections
             while let Ok((sock, addr)) = self.socket.accept() {
                 sock.set_nonblocking(true)?;
-                self.add_conn(addr, sock);
+                self.add_conn(canonical_addr(addr), sock);
+            }
+
+            // outgoing connections that finished connecting
+            while let Ok((addr, result)) = self.connected.1.try_recv() {
+                self.connecting.retain(|a| *a != addr);
+                match result {
+                    Ok(sock) => {
+                        sock.set_nonblocking(true)?;
+                        self.add_conn(addr, sock);
+                    },
+                    Err(err) => println!("handler: failed to connect to {:?} {}", addr, err),
+                }
             }
 
             // r/w for connections
@@ -198,11 +239,21 @@ impl Handler {
     fn notify(&mut self, msg: Message) {
         match msg {
             Message::AddPeer(addr) => {
-                let socket = TcpStream::connect(&addr).unwrap();
-                self.add_conn(addr, socket);
+                if self.conns.contains_key(&addr) || self.connecting.contains(&addr) {
+                    return;
+                }
+                // Connect on a separate thread so that unreachable peers don't stall the loop
+                self.connecting.push(addr);
+                let connected = self.connected.0.clone();
+                thread::spawn(move || {
+                    let result = TcpStream::connect_timeout(&addr, Duration::from_secs(5));
+                    let _ = connected.send((addr, result));
+                });
             },
             Message::Data(addr, data) => {
-                self.conns.get_mut(&addr).expect("missing connection").send_data(data);
+                if let Some(conn) = self.conns.get_mut(&addr) {
+                    conn.send_data(data);
+                }
             },
             Message::Disconnect(addr) => {
                 self.disconnect(&addr);
@@ -216,6 +267,9 @@ impl Handler {
 pub struct Peer {
     addr: SocketAddr,
     info_hash: Hash,
+    is_v2: bool,
+    piece_size: usize,
+    total_size: usize,
     channel: Sender<Message>,
     tpieces: Sender<(usize, usize, Vec<u8>)>,
 
@@ -226,17 +280,39 @@ pub struct Peer {
     pub is_handshake_sent: bool,
     pub is_interested_sent: bool,
     pub is_choke_received: bool,
+    pub is_interested_received: bool,
+    pub is_choke_sent: bool,
     pub blocks_requested: usize,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub upload_rate: f64,
+    pub download_rate: f64,
+    last_rate_update: Instant,
+    last_uploaded: usize,
+    last_downloaded: usize,
+    requests_received: VecDeque<(usize, usize, usize)>,
+    requests_reading: Vec<(usize, usize, usize)>,
+    new_pieces: Vec<usize>,
+    local_extensions: ExtendedHandshake,
+    pub remote_extensions: Option<ExtendedHandshake>,
+    extended_messages: Vec<(u8, Vec<u8>)>,
     pub is_piece_downloaded: Vec<bool>,
     pub is_block_requested: Vec<Vec<bool>>,
     bitfield: Vec<bool>,
+    pub supports_v2: bool,
+    hash_requests_received: VecDeque<HashRequest>,
+    pub hash_requests_sent: Vec<HashRequest>,
+    hashes_received: Vec<(HashRequest, Vec<Hash256>)>,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr, torrent: &Torrent, chn: Sender<Message>, t: Sender<(usize, usize, Vec<u8>)>) -> Peer {
+    pub fn new(addr: SocketAddr, torrent: &Torrent, extensions: ExtendedHandshake, chn: Sender<Message>, t: Sender<(usize, usize, Vec<u8>)>) -> Peer {
         let mut p = Peer {
             addr: addr,
             info_hash: torrent.info_hash.clone(),
+            is_v2: torrent.info_hash_v2.is_some(),
+            piece_size: torrent.piece_size,
+            total_size: torrent.get_total_size(),
             channel: chn,
             tpieces: t,
             data: vec![],
@@ -246,12 +322,31 @@ impl Peer {
             is_handshake_sent: false,
             is_interested_sent: false,
             is_choke_received: true,
+            is_interested_received: false,
+            is_choke_sent: true,
             blocks_requested: 0,
+            uploaded: 0,
+            downloaded: 0,
+            upload_rate: 0.0,
+            download_rate: 0.0,
+            last_rate_update: Instant::now(),
+            last_uploaded: 0,
+            last_downloaded: 0,
+            requests_received: VecDeque::new(),
+            requests_reading: vec![],
+            new_pieces: vec![],
+            local_extensions: extensions,
+            remote_extensions: None,
+            extended_messages: vec![],
             is_piece_downloaded: vec![false; torrent.no_of_pieces],
             is_block_requested: {
                 (0..torrent.no_of_pieces).map(|piece| { vec![false; torrent.get_block_count(piece)] }).collect()
             },
             bitfield: torrent.is_piece_downloaded.clone(),
+            supports_v2: false,
+            hash_requests_received: VecDeque::new(),
+            hash_requests_sent: vec![],
+            hashes_received: vec![],
         };
         p.send_handshake();
         p
@@ -288,14 +383,18 @@ impl Peer {
             MessageType::KeepAlive => self.recv_keepalive(message),
             MessageType::Choke => self.recv_choke(message),
             MessageType::UnChoke => self.recv_unchoke(message),
-            MessageType::Interested => println!("peer: recv interested"),
-            MessageType::NotInterested => println!("peer: recv not interested"),
+            MessageType::Interested => self.recv_interested(message),
+            MessageType::NotInterested => self.recv_not_interested(message),
             MessageType::Have => self.recv_have(message),
             MessageType::Bitfield => self.recv_bitfield(message),
-            MessageType::Request => println!("peer: recv request"),
+            MessageType::Request => self.recv_request(message),
             MessageType::Piece => self.recv_piece(message),
-            MessageType::Cancel => println!("peer: recv cancel"),
+            MessageType::Cancel => self.recv_cancel(message),
             MessageType::Port => println!("peer: recv port"),
+            MessageType::Extended => self.recv_extended(message),
+            MessageType::HashRequest => self.recv_hash_request(message),
+            MessageType::Hashes => self.recv_hashes(message),
+            MessageType::HashReject => self.recv_hash_reject(message),
             MessageType::Unknown => println!("peer: unknown message"),
         }
     }
@@ -320,12 +419,77 @@ impl Peer {
         }
     }
 
-    pub fn no_of_blocks_requested(&self) -> u32 {
-        self.is_block_requested
-            .iter()
-            .fold(0, |sum, ref x| {
-                sum + x.iter().filter(|&b| *b ).collect::<Vec<_>>().len() as u32
-            })
+    pub fn no_of_blocks_requested(&self) -> usize {
+        self.blocks_requested
+    }
+
+    /// Pops the next block request received from the peer, it is being
+    /// read until `finish_request` is called.
+    pub fn next_request(&mut self) -> Option<(usize, usize, usize)> {
+        let request = self.requests_received.pop_front();
+        if let Some(request) = request {
+            self.requests_reading.push(request);
+        }
+        request
+    }
+
+    /// Whether the block being read is still wanted, false once the peer
+    /// cancelled it or we choked the peer.
+    pub fn finish_request(&mut self, request: (usize, usize, usize)) -> bool {
+        match self.requests_reading.iter().position(|&r| r == request) {
+            Some(i) => {
+                self.requests_reading.remove(i);
+                !self.is_choke_sent
+            },
+            None => false,
+        }
+    }
+
+    /// Pops the next hash request received from the peer.
+    pub fn next_hash_request(&mut self) -> Option<HashRequest> {
+        self.hash_requests_received.pop_front()
+    }
+
+    /// Takes the hashes received since the last call along with the requests they answer.
+    pub fn take_hashes(&mut self) -> Vec<(HashRequest, Vec<Hash256>)> {
+        self.hashes_received.drain(..).collect()
+    }
+
+    /// Takes the extended messages received since the last call, to be
+    /// dispatched to the extension registry.
+    pub fn take_extended_messages(&mut self) -> Vec<(u8, Vec<u8>)> {
+        self.extended_messages.drain(..).collect()
+    }
+
+    /// Whether the peer accepts messages of the named extension.
+    pub fn supports_extension(&self, name: &str) -> bool {
+        match self.remote_extensions {
+            Some(ref handshake) => handshake.m.contains_key(name),
+            None => false,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Takes the pieces the peer has announced since the last call.
+    pub fn take_new_pieces(&mut self) -> Vec<usize> {
+        self.new_pieces.drain(..).collect()
+    }
+
+    /// Recalculates the transfer rates (bytes/sec) since the last update.
+    pub fn update_rates(&mut self) {
+        let elapsed = self.last_rate_update.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        if secs <= 0.0 {
+            return;
+        }
+        self.upload_rate = (self.uploaded - self.last_uploaded) as f64 / secs;
+        self.download_rate = (self.downloaded - self.last_downloaded) as f64 / secs;
+        self.last_uploaded = self.uploaded;
+        self.last_downloaded = self.downloaded;
+        self.last_rate_update = Instant::now();
     }
 
     pub fn is_timed_out(&self) -> bool {
@@ -337,7 +501,12 @@ impl Peer {
         let mut data: Vec<u8> = vec![];
         data.push(19);
         data.extend_from_slice(b"BitTorrent protocol");
-        data.extend_from_slice(&[0; 8]);
+        let mut reserved = [0; 8];
+        reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+        if self.is_v2 {
+            reserved[V2_RESERVED_BYTE] |= V2_RESERVED_BIT;
+        }
+        data.extend_from_slice(&reserved);
         data.extend_from_slice(&self.info_hash.0);
         data.extend_from_slice(&MY_PEER_ID.0);
 
@@ -378,8 +547,34 @@ impl Peer {
         self.is_interested_sent = false;
     }
 
+    pub fn send_choke(&mut self) {
+        if self.is_choke_sent {
+            return;
+        }
+        println!("peer: send_choke to {}", self);
+        let data: Vec<u8> = vec![0, 0, 0, 1, 0];
+
+        self.write(data);
+        self.is_choke_sent = true;
+        // Pending requests are discarded when the peer is choked
+        self.requests_received.clear();
+        self.requests_reading.clear();
+    }
+
+    pub fn send_unchoke(&mut self) {
+        if !self.is_choke_sent {
+            return;
+        }
+        println!("peer: send_unchoke to {}", self);
+        let data: Vec<u8> = vec![0, 0, 0, 1, 1];
+
+        self.write(data);
+        self.is_choke_sent = false;
+    }
+
     pub fn send_have(&mut self, piece: usize) {
         println!("peer: send_have to {}", self);
+        self.bitfield[piece] = true;
         let piece = u32_to_byte_slice(piece as u32);
 
         let mut data: Vec<u8> = vec![0, 0, 0, 5, 4];
@@ -418,7 +613,113 @@ impl Peer {
         data.extend_from_slice(&length);
 
         self.write(data);
-        self.is_block_requested[piece][block] = true;
+        if !self.is_block_requested[piece][block] {
+            self.is_block_requested[piece][block] = true;
+            self.blocks_requested += 1;
+        }
+    }
+
+    pub fn send_piece(&mut self, index: usize, begin: usize, block: Vec<u8>) {
+        println!("peer: send_piece to {}", self);
+
+        let length = u32_to_byte_slice(block.len() as u32 + 9);
+        let len = block.len();
+
+        let mut data: Vec<u8> = vec![];
+        data.extend_from_slice(&length);
+        data.push(7);
+        data.extend_from_slice(&u32_to_byte_slice(index as u32));
+        data.extend_from_slice(&u32_to_byte_slice(begin as u32));
+        data.extend(block);
+
+        self.write(data);
+        self.uploaded += len;
+    }
+
+    pub fn send_cancel(&mut self, index: usize, begin: usize, length: usize) {
+        println!("peer: send_cancel to {}", self);
+
+        let piece = index;
+        let block = begin / BLOCK_SIZE;
+        let index = u32_to_byte_slice(index as u32);
+        let begin = u32_to_byte_slice(begin as u32);
+        let length = u32_to_byte_slice(length as u32);
+
+        let mut data: Vec<u8> = vec![0, 0, 0, 13, 8];
+        data.extend_from_slice(&index);
+        data.extend_from_slice(&begin);
+        data.extend_from_slice(&length);
+
+        self.write(data);
+        if self.is_block_requested[piece][block] {
+            self.is_block_requested[piece][block] = false;
+            self.blocks_requested -= 1;
+        }
+    }
+
+    pub fn send_hash_request(&mut self, request: HashRequest) {
+        println!("peer: send_hash_request to {}", self);
+        self.send_hash_message(21, &request, &[]);
+        self.hash_requests_sent.push(request);
+    }
+
+    pub fn send_hashes(&mut self, request: &HashRequest, hashes: &[Hash256]) {
+        println!("peer: send_hashes to {}", self);
+        self.send_hash_message(22, request, hashes);
+    }
+
+    pub fn send_hash_reject(&mut self, request: &HashRequest) {
+        println!("peer: send_hash_reject to {}", self);
+        self.send_hash_message(23, request, &[]);
+    }
+
+    fn send_hash_message(&mut self, id: u8, request: &HashRequest, hashes: &[Hash256]) {
+        let payload = request.encode();
+        let mut data: Vec<u8> = vec![];
+        data.extend_from_slice(&u32_to_byte_slice((payload.len() + hashes.len() * 32) as u32 + 1));
+        data.push(id);
+        data.extend(payload);
+        for hash in hashes {
+            data.extend_from_slice(&hash.0);
+        }
+
+        self.write(data);
+    }
+
+    fn send_extended_handshake(&mut self) {
+        println!("peer: send_extended_handshake to {}", self);
+        let mut handshake = self.local_extensions.clone();
+        handshake.yourip = Some(self.addr.ip());
+        let payload = handshake.encode();
+
+        let mut data: Vec<u8> = vec![];
+        data.extend_from_slice(&u32_to_byte_slice(payload.len() as u32 + 2));
+        data.push(20);
+        data.push(extension::HANDSHAKE_ID);
+        data.extend(payload);
+
+        self.write(data);
+    }
+
+    /// Sends a message to the named extension, using the id the peer assigned to it.
+    pub fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> bool {
+        let id = match self.remote_extensions {
+            Some(ref handshake) => match handshake.m.get(name) {
+                Some(&id) => id,
+                None => return false,
+            },
+            None => return false,
+        };
+        println!("peer: send_extended {} to {}", name, self);
+
+        let mut data: Vec<u8> = vec![];
+        data.extend_from_slice(&u32_to_byte_slice(payload.len() as u32 + 2));
+        data.push(20);
+        data.push(id);
+        data.extend(payload);
+
+        self.write(data);
+        true
     }
 
     fn recv_keepalive(&mut self, message: &Vec<u8>) {
@@ -443,7 +744,11 @@ impl Peer {
             return;
         }
         self.is_handshake_received = true;
+        self.supports_v2 = message[20 + V2_RESERVED_BYTE] & V2_RESERVED_BIT != 0;
         self.send_bitfield();
+        if message[20 + extension::RESERVED_BYTE] & extension::RESERVED_BIT != 0 {
+            self.send_extended_handshake();
+        }
     }
 
     fn recv_choke(&mut self, message: &Vec<u8>) {
@@ -455,17 +760,38 @@ impl Peer {
         self.is_choke_received = true;
     }
 
+    fn recv_interested(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_interested from {}", self);
+        if message.len() != 5 {
+            println!("peer: invalid interested");
+            return;
+        }
+        self.is_interested_received = true;
+    }
+
+    fn recv_not_interested(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_not_interested from {}", self);
+        if message.len() != 5 {
+            println!("peer: invalid not interested");
+            return;
+        }
+        self.is_interested_received = false;
+    }
+
     fn recv_bitfield(&mut self, message: &Vec<u8>) {
         println!("peer: recv_bitfield from {}", self);
         let no_of_pieces = self.is_piece_downloaded.len();
-        let expected_length: usize = (no_of_pieces/8) + 1;
+        let expected_length: usize = (no_of_pieces + 7) / 8;
         if message.len() != expected_length + 5 {
             println!("peer: invalid bitfield length");
             return;
         }
         let bits = to_bits(&message[5..]);
         for piece in 0..no_of_pieces {
-            self.is_piece_downloaded[piece] = if bits[piece] == 1 { true } else { false };
+            if bits[piece] == 1 && !self.is_piece_downloaded[piece] {
+                self.is_piece_downloaded[piece] = true;
+                self.new_pieces.push(piece);
+            }
         }
     }
 
@@ -485,20 +811,158 @@ impl Peer {
             return;
         }
         let piece = byte_slice_to_u32(&message[5..9]) as usize;
-        self.is_piece_downloaded[piece] = true;
+        if piece >= self.is_piece_downloaded.len() {
+            println!("peer: invalid piece in have");
+            return;
+        }
+        if !self.is_piece_downloaded[piece] {
+            self.is_piece_downloaded[piece] = true;
+            self.new_pieces.push(piece);
+        }
     }
 
-    fn recv_piece(&mut self, message: &Vec<u8>) {
-        println!("peer: recv_piece from {}", self);
+    fn recv_extended(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_extended from {}", self);
+        if message.len() < 6 {
+            println!("peer: invalid extended message");
+            return;
+        }
+        let id = message[5];
+        let payload = message[6..].to_vec();
+        if id == extension::HANDSHAKE_ID {
+            match ExtendedHandshake::decode(&payload) {
+                Some(handshake) => {
+                    println!("peer: {} supports extensions {:?} ({:?})", self, handshake.m, handshake.v);
+                    self.remote_extensions = Some(handshake);
+                },
+                None => {
+                    println!("peer: invalid extended handshake");
+                    return;
+                },
+            }
+        }
+        self.extended_messages.push((id, payload));
+    }
+
+    fn recv_hash_request(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_hash_request from {}", self);
+        let request = match HashRequest::decode(&message[5..]) {
+            Some(request) if message.len() == 53 => request,
+            _ => {
+                println!("peer: invalid hash request");
+                return;
+            },
+        };
+        if self.hash_requests_received.len() >= extension::MAX_QUEUED_REQUESTS {
+            println!("peer: too many queued hash requests from {}", self);
+            return;
+        }
+        self.hash_requests_received.push_back(request);
+    }
+
+    fn recv_hashes(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_hashes from {}", self);
+        let request = match HashRequest::decode(&message[5..]) {
+            Some(request) if (message.len() - 53) % 32 == 0 => request,
+            _ => {
+                println!("peer: invalid hashes");
+                return;
+            },
+        };
+        if !self.hash_requests_sent.contains(&request) {
+            println!("peer: unrequested hashes from {}", self);
+            return;
+        }
+        self.hash_requests_sent.retain(|r| *r != request);
+        let hashes = message[53..].chunks(32).map(Hash256::from_slice).collect();
+        self.hashes_received.push((request, hashes));
+    }
+
+    fn recv_hash_reject(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_hash_reject from {}", self);
+        match HashRequest::decode(&message[5..]) {
+            Some(request) => self.hash_requests_sent.retain(|r| *r != request),
+            None => println!("peer: invalid hash reject"),
+        }
+    }
+
+    fn recv_request(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_request from {}", self);
+        if message.len() != 17 {
+            println!("peer: invalid request");
+            return;
+        }
+        if self.is_choke_sent {
+            println!("peer: ignoring request from choked peer {}", self);
+            return;
+        }
 
         let index = byte_slice_to_u32(&message[5..9]) as usize;
         let begin = byte_slice_to_u32(&message[9..13]) as usize;
-        let block = message[13..].to_vec();
-        self.tpieces.send((index, begin / BLOCK_SIZE, block)).unwrap();
+        let length = byte_slice_to_u32(&message[13..17]) as usize;
+        if index >= self.bitfield.len() || !self.bitfield[index] || length > MAX_REQUEST_SIZE {
+            println!("peer: invalid request for piece {} from {}", index, self);
+            return;
+        }
 
-        let piece = index;
+        let request = (index, begin, length);
+        if self.requests_received.len() + self.requests_reading.len() >= extension::MAX_QUEUED_REQUESTS {
+            println!("peer: too many queued requests from {}", self);
+            return;
+        }
+        if !self.requests_received.contains(&request) {
+            self.requests_received.push_back(request);
+        }
+    }
+
+    fn recv_cancel(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_cancel from {}", self);
+        if message.len() != 17 {
+            println!("peer: invalid cancel");
+            return;
+        }
+
+        let index = byte_slice_to_u32(&message[5..9]) as usize;
+        let begin = byte_slice_to_u32(&message[9..13]) as usize;
+        let length = byte_slice_to_u32(&message[13..17]) as usize;
+        self.requests_received.retain(|&r| r != (index, begin, length));
+        self.requests_reading.retain(|&r| r != (index, begin, length));
+    }
+
+    fn recv_piece(&mut self, message: &Vec<u8>) {
+        println!("peer: recv_piece from {}", self);
+        if message.len() < 13 {
+            println!("peer: invalid piece");
+            return;
+        }
+
+        let piece = byte_slice_to_u32(&message[5..9]) as usize;
+        let begin = byte_slice_to_u32(&message[9..13]) as usize;
         let block = begin / BLOCK_SIZE;
+        let data = message[13..].to_vec();
+        if piece >= self.is_block_requested.len() || block >= self.is_block_requested[piece].len()
+            || begin % BLOCK_SIZE != 0 || data.len() != self.get_block_length(piece, block) {
+            println!("peer: invalid block in piece");
+            return;
+        }
+        // Blocks we never asked for or cancelled aren't counted as downloaded
+        if !self.is_block_requested[piece][block] {
+            println!("peer: unrequested block {} of piece {} from {}", block, piece, self);
+            return;
+        }
+        self.downloaded += data.len();
+        self.tpieces.send((piece, block, data)).unwrap();
         self.is_block_requested[piece][block] = false;
+        self.blocks_requested -= 1;
+    }
+
+    fn get_block_length(&self, piece: usize, block: usize) -> usize {
+        let piece_length = if piece == self.is_block_requested.len() - 1 {
+            self.total_size - piece * self.piece_size
+        } else {
+            self.piece_size
+        };
+        ::std::cmp::min(BLOCK_SIZE, piece_length - block * BLOCK_SIZE)
     }
 
 }
@@ -508,3 +972,122 @@ impl fmt::Display for Peer {
         write!(f, "{}", self.addr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    /// A peer of a torrent with one piece of two blocks, the second one short,
+    /// along with the messages it sends and the blocks it receives.
+    fn peer(downloaded: bool) -> (Peer, Receiver<Message>, Receiver<(usize, usize, Vec<u8>)>) {
+        let data = vec![7; BLOCK_SIZE + 100];
+        let mut torrent = Torrent::in_memory(&data, 2 * BLOCK_SIZE, &Config::default());
+        if downloaded {
+            torrent.write_block(0, 0, data[..BLOCK_SIZE].to_vec());
+            torrent.write_block(0, 1, data[BLOCK_SIZE..].to_vec());
+        }
+
+        let (tx, rx) = channel();
+        let (tpieces, rpieces) = channel();
+        let mut peer = Peer::new("10.0.0.1:6881".parse().unwrap(), &torrent, ExtendedHandshake::default(), tx, tpieces);
+        peer.is_handshake_received = true;
+        while rx.try_recv().is_ok() {}
+        (peer, rx, rpieces)
+    }
+
+    fn message(id: u8, index: usize, begin: usize, payload: &[u8]) -> Vec<u8> {
+        let mut data = u32_to_byte_slice(payload.len() as u32 + 9);
+        data.push(id);
+        data.extend_from_slice(&u32_to_byte_slice(index as u32));
+        data.extend_from_slice(&u32_to_byte_slice(begin as u32));
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn receive(peer: &mut Peer, data: Vec<u8>) {
+        peer.read(data);
+        peer.process_data();
+    }
+
+    #[test]
+    fn requests() {
+        let (mut peer, rx, _) = peer(true);
+        let request = message(6, 0, BLOCK_SIZE, &u32_to_byte_slice(100));
+
+        // Choked peers are ignored
+        receive(&mut peer, request.clone());
+        assert_eq!(peer.next_request(), None);
+
+        peer.send_unchoke();
+        receive(&mut peer, request.clone());
+        receive(&mut peer, request.clone());
+        receive(&mut peer, message(6, 1, 0, &u32_to_byte_slice(100)));
+        receive(&mut peer, message(6, 0, 0, &u32_to_byte_slice(MAX_REQUEST_SIZE as u32 + 1)));
+        assert_eq!(peer.next_request(), Some((0, BLOCK_SIZE, 100)));
+        assert_eq!(peer.next_request(), None);
+        assert!(peer.finish_request((0, BLOCK_SIZE, 100)));
+        assert!(!peer.finish_request((0, BLOCK_SIZE, 100)));
+
+        // Choking drops the requests that are being read
+        receive(&mut peer, request.clone());
+        assert_eq!(peer.next_request(), Some((0, BLOCK_SIZE, 100)));
+        peer.send_choke();
+        assert!(!peer.finish_request((0, BLOCK_SIZE, 100)));
+
+        peer.send_piece(0, 0, vec![7; 100]);
+        assert_eq!(peer.uploaded, 100);
+        let mut sent = vec![];
+        while let Ok(Message::Data(_, data)) = rx.try_recv() {
+            sent = data;
+        }
+        assert_eq!(sent, message(7, 0, 0, &[7; 100]));
+    }
+
+    #[test]
+    fn cancels() {
+        let (mut peer, _rx, _) = peer(true);
+        peer.send_unchoke();
+        let cancel = message(8, 0, 0, &u32_to_byte_slice(BLOCK_SIZE as u32));
+
+        receive(&mut peer, message(6, 0, 0, &u32_to_byte_slice(BLOCK_SIZE as u32)));
+        receive(&mut peer, cancel.clone());
+        assert_eq!(peer.next_request(), None);
+
+        // Cancelled while it is being read
+        receive(&mut peer, message(6, 0, 0, &u32_to_byte_slice(BLOCK_SIZE as u32)));
+        assert_eq!(peer.next_request(), Some((0, 0, BLOCK_SIZE)));
+        receive(&mut peer, cancel);
+        assert!(!peer.finish_request((0, 0, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn pieces() {
+        let (mut peer, _rx, rpieces) = peer(false);
+
+        // Never requested
+        receive(&mut peer, message(7, 0, 0, &[7; BLOCK_SIZE]));
+        assert!(rpieces.try_recv().is_err());
+
+        peer.send_request(0, 0, BLOCK_SIZE);
+        peer.send_request(0, BLOCK_SIZE, 100);
+        // Misaligned, of the wrong length or out of the torrent
+        receive(&mut peer, message(7, 0, 1, &[7; BLOCK_SIZE]));
+        receive(&mut peer, message(7, 0, 0, &[7; 100]));
+        receive(&mut peer, message(7, 0, BLOCK_SIZE, &[7; BLOCK_SIZE]));
+        receive(&mut peer, message(7, 1, 0, &[7; BLOCK_SIZE]));
+        assert!(rpieces.try_recv().is_err());
+        assert_eq!(peer.downloaded, 0);
+
+        receive(&mut peer, message(7, 0, BLOCK_SIZE, &[7; 100]));
+        assert_eq!(rpieces.try_recv().unwrap(), (0, 1, vec![7; 100]));
+        assert_eq!(peer.downloaded, 100);
+        assert!(!peer.is_block_requested[0][1]);
+
+        // Cancelled blocks that still arrive are dropped
+        peer.send_cancel(0, 0, BLOCK_SIZE);
+        receive(&mut peer, message(7, 0, 0, &[7; BLOCK_SIZE]));
+        assert!(rpieces.try_recv().is_err());
+        assert_eq!(peer.downloaded, 100);
+    }
+}
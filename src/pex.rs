@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use bencoding::*;
+use extension::{Extension, ExtendedHandshake};
+use peer::{Message, Peer};
+use utils::*;
+
+/// Minimum time between two PEX messages to the same peer
+const PEX_INTERVAL: u64 = 60;
+
+/// Maximum number of added or dropped peers in a single message
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// Number of gossiped addresses remembered so that they are only handed to the client once
+const MAX_KNOWN_PEERS: usize = 1000;
+
+/// Peer is a seed
+const FLAG_SEED: u8 = 0x02;
+
+/// Peer accepts incoming connections
+const FLAG_REACHABLE: u8 = 0x10;
+
+/// State of the exchange with a single peer
+struct PexState {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+/// Peer exchange (BEP 11), shares the addresses of our peers with each other
+pub struct PexExtension {
+    event_loop: Sender<Message>,
+    /// Listen address of each connected peer, keyed by the connection address
+    connected: HashMap<SocketAddr, SocketAddr>,
+    flags: HashMap<SocketAddr, u8>,
+    states: HashMap<SocketAddr, PexState>,
+    known: HashSet<SocketAddr>,
+    /// Known addresses from the oldest, the oldest are forgotten first
+    known_order: VecDeque<SocketAddr>,
+}
+
+impl PexExtension {
+    pub fn new(event_loop: Sender<Message>) -> PexExtension {
+        PexExtension {
+            event_loop: event_loop,
+            connected: HashMap::new(),
+            flags: HashMap::new(),
+            states: HashMap::new(),
+            known: HashSet::new(),
+            known_order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers an address, returns whether it was new.
+    fn remember(&mut self, addr: SocketAddr) -> bool {
+        if !self.known.insert(addr) {
+            return false;
+        }
+        self.known_order.push_back(addr);
+        while self.known_order.len() > MAX_KNOWN_PEERS {
+            if let Some(oldest) = self.known_order.pop_front() {
+                self.known.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Peers must not send more than one message a minute, returns whether
+    /// the message of the peer should be handled.
+    fn accept_message(&mut self, addr: SocketAddr) -> bool {
+        let state = self.states.entry(addr).or_insert(PexState {
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        });
+        if let Some(last) = state.last_received {
+            if last.elapsed() < Duration::from_secs(PEX_INTERVAL / 2) {
+                return false;
+            }
+        }
+        state.last_received = Some(Instant::now());
+        true
+    }
+
+    fn encode(added: &[SocketAddr], dropped: &[SocketAddr], flags: &HashMap<SocketAddr, u8>) -> Vec<u8> {
+        let mut lists: BTreeMap<&str, Vec<u8>> = BTreeMap::new();
+        for key in &["added", "added.f", "added6", "added6.f", "dropped", "dropped6"] {
+            lists.insert(key, vec![]);
+        }
+        for addr in added {
+            let (key, flags_key) = if addr.is_ipv4() { ("added", "added.f") } else { ("added6", "added6.f") };
+            lists.get_mut(key).unwrap().extend(compact_addr(addr));
+            lists.get_mut(flags_key).unwrap().push(*flags.get(addr).unwrap_or(&0));
+        }
+        for addr in dropped {
+            let key = if addr.is_ipv4() { "dropped" } else { "dropped6" };
+            lists.get_mut(key).unwrap().extend(compact_addr(addr));
+        }
+
+        let root = lists.into_iter().map(|(key, value)| (key.to_string(), BEncoding::Str(value))).collect();
+        BEncoding::encode(&BEncoding::Dict(root))
+    }
+
+    /// Returns the added and dropped peers of a message.
+    fn decode(payload: &[u8]) -> Option<(Vec<SocketAddr>, Vec<SocketAddr>)> {
+        let root = BEncoding::decode(payload.to_vec())?;
+        let list = |key: &str, ipv6: bool| root.get_bytes(key).map(|data| parse_compact_addrs(&data, ipv6)).unwrap_or(vec![]);
+        let mut added = list("added", false);
+        added.extend(list("added6", true));
+        let mut dropped = list("dropped", false);
+        dropped.extend(list("dropped6", true));
+        Some((added, dropped))
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_handshake(&mut self, peer: &mut Peer, handshake: &ExtendedHandshake) {
+        let addr = peer.addr();
+        let listen_addr = SocketAddr::new(addr.ip(), handshake.p.unwrap_or(addr.port()));
+        self.connected.insert(addr, listen_addr);
+        self.remember(listen_addr);
+        if handshake.p.is_some() {
+            *self.flags.entry(listen_addr).or_insert(0) |= FLAG_REACHABLE;
+        }
+    }
+
+    fn on_disconnect(&mut self, addr: &SocketAddr) {
+        if let Some(listen_addr) = self.connected.remove(addr) {
+            self.flags.remove(&listen_addr);
+        }
+        self.states.remove(addr);
+    }
+
+    fn on_message(&mut self, peer: &mut Peer, payload: &[u8]) {
+        if !self.accept_message(peer.addr()) {
+            println!("pex: ignoring message flood from {}", peer);
+            return;
+        }
+        let (added, _) = match Self::decode(payload) {
+            Some(peers) => peers,
+            None => return,
+        };
+
+        let mut count = 0;
+        for addr in added.into_iter().take(MAX_PEERS_PER_MESSAGE) {
+            if addr.port() == 0 || !self.remember(addr) {
+                continue;
+            }
+            let _ = self.event_loop.send(Message::AddPeer(addr));
+            count += 1;
+        }
+        println!("pex: received {} new peers from {}", count, peer);
+    }
+
+    fn tick(&mut self, peer: &mut Peer) {
+        let name = self.name();
+        let addr = peer.addr();
+        if let Some(&listen_addr) = self.connected.get(&addr) {
+            if peer.is_piece_downloaded.iter().all(|&b| b) {
+                *self.flags.entry(listen_addr).or_insert(0) |= FLAG_SEED;
+            }
+        }
+        if !peer.supports_extension(name) {
+            return;
+        }
+
+        let state = self.states.entry(addr).or_insert(PexState {
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        });
+        if let Some(last) = state.last_sent {
+            if last.elapsed() < Duration::from_secs(PEX_INTERVAL) {
+                return;
+            }
+        }
+
+        let own = self.connected.get(&addr).cloned();
+        let current: HashSet<SocketAddr> = self.connected.values().cloned().filter(|a| Some(*a) != own).collect();
+        let added: Vec<SocketAddr> = current.difference(&state.sent).cloned().take(MAX_PEERS_PER_MESSAGE).collect();
+        let dropped: Vec<SocketAddr> = state.sent.difference(&current).cloned().take(MAX_PEERS_PER_MESSAGE).collect();
+        if added.is_empty() && dropped.is_empty() {
+            return;
+        }
+
+        let payload = Self::encode(&added, &dropped, &self.flags);
+        if peer.send_extended(name, payload) {
+            for a in &added {
+                state.sent.insert(*a);
+            }
+            for d in &dropped {
+                state.sent.remove(d);
+            }
+            state.last_sent = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn message_round_trip() {
+        let added: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:51413".parse().unwrap()];
+        let dropped: Vec<SocketAddr> = vec!["10.0.0.2:6882".parse().unwrap()];
+        let mut flags = HashMap::new();
+        flags.insert(added[0], FLAG_SEED | FLAG_REACHABLE);
+
+        let payload = PexExtension::encode(&added, &dropped, &flags);
+        let root = BEncoding::decode(payload.clone()).unwrap();
+        assert_eq!(root.get_bytes("added.f").unwrap(), vec![FLAG_SEED | FLAG_REACHABLE]);
+        assert_eq!(root.get_bytes("added6.f").unwrap(), vec![0]);
+        assert_eq!(PexExtension::decode(&payload), Some((added, dropped)));
+        assert_eq!(PexExtension::decode(b"garbage"), None);
+    }
+
+    #[test]
+    fn flood_limit() {
+        let mut pex = PexExtension::new(channel().0);
+        let a = "10.0.0.1:6881".parse().unwrap();
+        let b = "10.0.0.2:6881".parse().unwrap();
+        assert!(pex.accept_message(a));
+        assert!(!pex.accept_message(a));
+        assert!(pex.accept_message(b));
+
+        // Forgotten on disconnect, a new connection starts over
+        pex.on_disconnect(&a);
+        assert!(pex.accept_message(a));
+    }
+
+    #[test]
+    fn known_peers_are_bounded() {
+        let mut pex = PexExtension::new(channel().0);
+        for i in 0..MAX_KNOWN_PEERS + 10 {
+            assert!(pex.remember(SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881))));
+        }
+        assert_eq!(pex.known.len(), MAX_KNOWN_PEERS);
+        assert!(!pex.remember(SocketAddr::from(([10, 0, 3, 241], 6881))));
+        // The oldest were forgotten
+        assert!(pex.remember(SocketAddr::from(([10, 0, 0, 0], 6881))));
+    }
+}
@@ -0,0 +1,240 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use utils::*;
+
+/// Download priority of a file, pieces get the highest priority of their files
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Priority, String> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority {}", s)),
+        }
+    }
+}
+
+/// Order in which the pieces are downloaded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DownloadMode {
+    RarestFirst,
+    /// In piece order, for files read while they download
+    Sequential,
+    /// The pieces ahead of a read cursor get deadlines, the rest is rarest first
+    Streaming,
+}
+
+impl FromStr for DownloadMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DownloadMode, String> {
+        match s {
+            "rarest-first" => Ok(DownloadMode::RarestFirst),
+            "sequential" => Ok(DownloadMode::Sequential),
+            "streaming" => Ok(DownloadMode::Streaming),
+            _ => Err(format!("unknown download mode {}", s)),
+        }
+    }
+}
+
+/// Chooses which pieces to request next (rarest first).
+pub struct PiecePicker {
+    availability: Vec<usize>,
+    mode: DownloadMode,
+    deadlines: HashMap<usize, Instant>,
+}
+
+impl PiecePicker {
+    pub fn new(no_of_pieces: usize) -> PiecePicker {
+        PiecePicker {
+            availability: vec![0; no_of_pieces],
+            mode: DownloadMode::RarestFirst,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> DownloadMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: DownloadMode) {
+        self.mode = mode;
+    }
+
+    /// Time by which the piece should be downloaded, if it has one.
+    pub fn deadline(&self, piece: usize) -> Option<Instant> {
+        self.deadlines.get(&piece).cloned()
+    }
+
+    pub fn set_deadline(&mut self, piece: usize, deadline: Instant) {
+        self.deadlines.insert(piece, deadline);
+    }
+
+    pub fn remove_deadline(&mut self, piece: usize) {
+        self.deadlines.remove(&piece);
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    /// Pieces with a deadline, the most urgent first.
+    pub fn deadline_pieces(&self) -> Vec<usize> {
+        let mut pieces: Vec<usize> = self.deadlines.keys().cloned().collect();
+        pieces.sort_by_key(|piece| self.deadlines[piece]);
+        pieces
+    }
+
+    /// Number of connected peers that have the piece.
+    pub fn availability(&self, piece: usize) -> usize {
+        self.availability[piece]
+    }
+
+    pub fn add_have(&mut self, piece: usize) {
+        self.availability[piece] += 1;
+    }
+
+    pub fn remove_have(&mut self, piece: usize) {
+        if self.availability[piece] > 0 {
+            self.availability[piece] -= 1;
+        }
+    }
+
+    pub fn add_bitfield(&mut self, has: &[bool]) {
+        for (piece, _) in has.iter().enumerate().filter(|&(_, &b)| b) {
+            self.add_have(piece);
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, has: &[bool]) {
+        for (piece, _) in has.iter().enumerate().filter(|&(_, &b)| b) {
+            self.remove_have(piece);
+        }
+    }
+
+    /// Returns the pieces that can be requested from a peer, in the order they should be requested.
+    ///
+    /// Pieces with a deadline come first, the most urgent first. Then partially
+    /// downloaded pieces so they get completed, the rest are ordered by priority
+    /// then rarest first with ties broken randomly, or by index in sequential
+    /// mode. Skipped pieces are left out.
+    pub fn pick(&self, peer_has: &[bool], is_downloaded: &[bool], is_partial: &[bool], priorities: &[Priority]) -> Vec<usize> {
+        let mut pieces: Vec<usize> = (0..self.availability.len())
+            .filter(|&piece| peer_has[piece] && !is_downloaded[piece] && priorities[piece] != Priority::Skip)
+            .collect();
+        match self.mode {
+            DownloadMode::Sequential => {
+                pieces.sort_by_key(|&piece| (self.deadlines.get(&piece).is_none(), self.deadlines.get(&piece).cloned(), Reverse(priorities[piece]), piece));
+            },
+            DownloadMode::RarestFirst | DownloadMode::Streaming => {
+                shuffle(&mut pieces);
+                pieces.sort_by_key(|&piece| (
+                    self.deadlines.get(&piece).is_none(),
+                    self.deadlines.get(&piece).cloned(),
+                    !is_partial[piece],
+                    Reverse(priorities[piece]),
+                    self.availability[piece],
+                ));
+            },
+        }
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pick_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_bitfield(&[true, true, true, true]);
+        picker.add_bitfield(&[true, false, true, true]);
+        picker.add_bitfield(&[true, false, false, true]);
+        assert_eq!(3, picker.availability(0));
+        assert_eq!(1, picker.availability(1));
+
+        let pieces = picker.pick(&[true; 4], &[false; 4], &[false; 4], &[Priority::Normal; 4]);
+        assert_eq!(vec![1, 2], pieces[0..2].to_vec());
+        assert_eq!(4, pieces.len());
+    }
+
+    #[test]
+    fn pick_partial_and_skip_downloaded() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_bitfield(&[true, true, true, true]);
+        picker.add_have(0);
+        picker.add_have(1);
+
+        let pieces = picker.pick(&[true, true, true, false], &[false, false, true, false], &[true, false, false, false], &[Priority::Normal; 4]);
+        assert_eq!(vec![0, 1], pieces);
+    }
+
+    #[test]
+    fn pick_by_priority() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_bitfield(&[true, true, true, true]);
+        picker.add_have(2);
+
+        let priorities = [Priority::Low, Priority::Skip, Priority::High, Priority::Normal];
+        let pieces = picker.pick(&[true; 4], &[false; 4], &[true, false, false, false], &priorities);
+        assert_eq!(vec![0, 2, 3], pieces);
+    }
+
+    #[test]
+    fn pick_sequential() {
+        let mut picker = PiecePicker::new(4);
+        picker.set_mode(DownloadMode::Sequential);
+        picker.add_bitfield(&[true, true, true, true]);
+        picker.add_have(0);
+
+        let pieces = picker.pick(&[true; 4], &[false, true, false, false], &[false, false, false, true], &[Priority::Normal; 4]);
+        assert_eq!(vec![0, 2, 3], pieces);
+    }
+
+    #[test]
+    fn pick_deadlines_first() {
+        let mut picker = PiecePicker::new(5);
+        picker.set_mode(DownloadMode::Streaming);
+        picker.add_bitfield(&[true, true, true, true, true]);
+        picker.add_bitfield(&[true, true, true, false, false]);
+        let now = Instant::now();
+        picker.set_deadline(2, now + Duration::from_secs(2));
+        picker.set_deadline(1, now + Duration::from_secs(1));
+
+        let pieces = picker.pick(&[true; 5], &[false; 5], &[true, false, false, false, false], &[Priority::Normal; 5]);
+        assert_eq!(vec![1, 2, 0], pieces[0..3].to_vec());
+        assert_eq!(vec![1, 2], picker.deadline_pieces());
+
+        picker.remove_deadline(1);
+        assert_eq!(vec![2], picker.deadline_pieces());
+        picker.clear_deadlines();
+        assert!(picker.deadline(2).is_none());
+    }
+
+    #[test]
+    fn remove_peer() {
+        let mut picker = PiecePicker::new(2);
+        picker.add_bitfield(&[true, true]);
+        picker.remove_bitfield(&[true, false]);
+        assert_eq!(0, picker.availability(0));
+        assert_eq!(1, picker.availability(1));
+        picker.remove_have(0);
+        assert_eq!(0, picker.availability(0));
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use bencoding::*;
+use error::Result;
+use torrent::Torrent;
+use utils::*;
+
+/// Size and modification time of a downloaded file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl FileState {
+    /// Reads the state of the file on disk, missing files are empty.
+    pub fn from_path(path: &str) -> FileState {
+        match fs::metadata(path) {
+            Ok(meta) => FileState {
+                size: meta.len(),
+                mtime: meta.modified().ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            },
+            Err(_) => FileState { size: 0, mtime: 0 },
+        }
+    }
+}
+
+/// Fast-resume data, saved so that the pieces don't need to be rehashed on startup.
+pub struct Resume {
+    pub info_hash: Hash,
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileState>,
+    pub partial: BTreeMap<usize, Vec<bool>>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Resume {
+    pub fn from_torrent(torrent: &Torrent) -> Resume {
+        let mut partial = BTreeMap::new();
+        for piece in 0..torrent.no_of_pieces {
+            // Buffered blocks aren't on disk yet
+            if !torrent.is_piece_downloaded[piece] && !torrent.is_piece_buffered(piece)
+                && torrent.is_block_downloaded[piece].iter().any(|&b| b) {
+                partial.insert(piece, torrent.is_block_downloaded[piece].clone());
+            }
+        }
+
+        Resume {
+            info_hash: torrent.info_hash,
+            pieces: torrent.is_piece_downloaded.clone(),
+            files: torrent.storage.file_states().unwrap_or(vec![]),
+            partial: partial,
+            peers: torrent.peers.keys().cloned().collect(),
+        }
+    }
+
+    /// Checks that the saved state still describes the files in the storage,
+    /// never for storages that can't describe their files.
+    pub fn matches(&self, torrent: &Torrent) -> bool {
+        if self.info_hash != torrent.info_hash || self.pieces.len() != torrent.no_of_pieces {
+            return false;
+        }
+        if self.partial.iter().any(|(&piece, blocks)| {
+            piece >= torrent.no_of_pieces || blocks.len() != torrent.get_block_count(piece)
+        }) {
+            return false;
+        }
+        torrent.storage.file_states().map(|states| states == self.files).unwrap_or(false)
+    }
+
+    pub fn load(path: &Path) -> Result<Resume> {
+        let mut buf = vec![];
+        let mut f = try!(fs::File::open(path));
+        try!(f.read_to_end(&mut buf));
+        let root = try!(BEncoding::decode(buf).ok_or(Error::DecodeError));
+        Self::decode(&root)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dirs) = path.parent() {
+            try!(fs::create_dir_all(dirs));
+        }
+        // Write to a temporary file first so a crash can't leave a truncated resume file
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = try!(fs::File::create(&tmp));
+            try!(f.write_all(&BEncoding::encode(&self.encode())));
+        }
+        try!(fs::rename(&tmp, path));
+        Ok(())
+    }
+
+    fn decode(root: &BEncoding) -> Result<Resume> {
+        let info_hash = try!(root.get_bytes("info-hash"));
+        if info_hash.len() != 20 {
+            return Err(Error::DecodeError.into());
+        }
+        let pieces = try!(decode_bitfield(root, "no-of-pieces", "pieces"));
+
+        let mut files = vec![];
+        for file in try!(root.get_list("files")) {
+            files.push(FileState {
+                size: try!(decode_int(&file, "size")),
+                mtime: try!(decode_int(&file, "mtime")),
+            });
+        }
+
+        let mut partial = BTreeMap::new();
+        for item in try!(root.get_list("partial")) {
+            let piece = try!(decode_int(&item, "piece"));
+            partial.insert(piece, try!(decode_bitfield(&item, "blocks", "bitfield")));
+        }
+
+        let mut peers = parse_compact_addrs(&try!(root.get_bytes("peers")), false);
+        if let Ok(peers6) = root.get_bytes("peers6") {
+            peers.extend(parse_compact_addrs(&peers6, true));
+        }
+
+        Ok(Resume {
+            info_hash: Hash::from_slice(&info_hash),
+            pieces: pieces,
+            files: files,
+            partial: partial,
+            peers: peers,
+        })
+    }
+
+    fn encode(&self) -> BEncoding {
+        let mut root = BTreeMap::new();
+        root.insert("info-hash".to_string(), BEncoding::Str(self.info_hash.0.to_vec()));
+        root.insert("no-of-pieces".to_string(), BEncoding::Int(self.pieces.len() as i64));
+        root.insert("pieces".to_string(), BEncoding::Str(encode_bits(&self.pieces)));
+
+        let files = self.files.iter().map(|file| {
+            let mut map = BTreeMap::new();
+            map.insert("size".to_string(), BEncoding::Int(file.size as i64));
+            map.insert("mtime".to_string(), BEncoding::Int(file.mtime as i64));
+            BEncoding::Dict(map)
+        }).collect();
+        root.insert("files".to_string(), BEncoding::List(files));
+
+        let partial = self.partial.iter().map(|(&piece, blocks)| {
+            let mut map = BTreeMap::new();
+            map.insert("piece".to_string(), BEncoding::Int(piece as i64));
+            map.insert("blocks".to_string(), BEncoding::Int(blocks.len() as i64));
+            map.insert("bitfield".to_string(), BEncoding::Str(encode_bits(blocks)));
+            BEncoding::Dict(map)
+        }).collect();
+        root.insert("partial".to_string(), BEncoding::List(partial));
+
+        let (mut peers, mut peers6) = (vec![], vec![]);
+        for addr in &self.peers {
+            if addr.is_ipv4() {
+                peers.extend(compact_addr(addr));
+            } else {
+                peers6.extend(compact_addr(addr));
+            }
+        }
+        root.insert("peers".to_string(), BEncoding::Str(peers));
+        root.insert("peers6".to_string(), BEncoding::Str(peers6));
+
+        BEncoding::Dict(root)
+    }
+}
+
+fn encode_bits(list: &[bool]) -> Vec<u8> {
+    let bits: Vec<u8> = list.iter().map(|&b| if b { 1 } else { 0 }).collect();
+    from_bits(&bits)
+}
+
+fn decode_bits(bytes: &[u8], count: usize) -> Vec<bool> {
+    let mut bits: Vec<bool> = to_bits(bytes).iter().map(|&b| b == 1).collect();
+    bits.resize(count, false);
+    bits
+}
+
+/// Reads an integer that has to fit the type, a corrupt file can have any.
+fn decode_int<T: TryFrom<i64>>(dict: &BEncoding, key: &str) -> Result<T> {
+    T::try_from(try!(dict.get_int(key))).map_err(|_| Error::DecodeError.into())
+}
+
+/// Reads a bitfield along with its bit count, which can't be more than the
+/// bits of the bitfield.
+fn decode_bitfield(dict: &BEncoding, count_key: &str, bits_key: &str) -> Result<Vec<bool>> {
+    let bytes = try!(dict.get_bytes(bits_key));
+    let count: usize = try!(decode_int(dict, count_key));
+    if count > bytes.len() * 8 {
+        return Err(Error::DecodeError.into());
+    }
+    Ok(decode_bits(&bytes, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use config::Config;
+
+    #[test]
+    fn test_round_trip() {
+        let mut partial = BTreeMap::new();
+        partial.insert(3, vec![true, false, true]);
+        partial.insert(7, vec![false; 11]);
+        let resume = Resume {
+            info_hash: Hash([5; 20]),
+            pieces: vec![true, false, false, true, false, true, true, false, true],
+            files: vec![FileState { size: 10, mtime: 1234 }, FileState { size: 0, mtime: 0 }],
+            partial: partial,
+            peers: vec!["10.0.0.1:6881".parse().unwrap(), "[::1]:51413".parse().unwrap()],
+        };
+        let temp = TempDir::new("resume");
+        let path = temp.path().join("resume");
+        resume.save(&path).unwrap();
+
+        let loaded = Resume::load(&path).unwrap();
+        assert_eq!(loaded.info_hash, resume.info_hash);
+        assert_eq!(loaded.pieces, resume.pieces);
+        assert_eq!(loaded.files, resume.files);
+        assert_eq!(loaded.partial, resume.partial);
+        assert_eq!(loaded.peers, resume.peers);
+    }
+
+    #[test]
+    fn test_corrupt_counts() {
+        let resume = Resume {
+            info_hash: Hash([5; 20]),
+            pieces: vec![true, false, true],
+            files: vec![FileState { size: 10, mtime: 1234 }],
+            partial: BTreeMap::new(),
+            peers: vec![],
+        };
+        assert!(Resume::decode(&resume.encode()).is_ok());
+        for &(key, value) in &[("no-of-pieces", -1), ("no-of-pieces", i64::max_value()), ("no-of-pieces", 9)] {
+            let mut root = resume.encode();
+            if let BEncoding::Dict(ref mut map) = root {
+                map.insert(key.to_string(), BEncoding::Int(value));
+            }
+            assert!(Resume::decode(&root).is_err(), "{} {}", key, value);
+        }
+
+        for &(key, value) in &[("size", -1), ("mtime", -1), ("blocks", -1), ("blocks", 9), ("piece", -1)] {
+            let mut item = BTreeMap::new();
+            item.insert("piece".to_string(), BEncoding::Int(1));
+            item.insert("blocks".to_string(), BEncoding::Int(3));
+            item.insert("bitfield".to_string(), BEncoding::Str(vec![0]));
+            let mut file = BTreeMap::new();
+            file.insert("size".to_string(), BEncoding::Int(10));
+            file.insert("mtime".to_string(), BEncoding::Int(1234));
+            if key == "size" || key == "mtime" {
+                file.insert(key.to_string(), BEncoding::Int(value));
+            } else {
+                item.insert(key.to_string(), BEncoding::Int(value));
+            }
+            let mut root = resume.encode();
+            if let BEncoding::Dict(ref mut map) = root {
+                map.insert("files".to_string(), BEncoding::List(vec![BEncoding::Dict(file)]));
+                map.insert("partial".to_string(), BEncoding::List(vec![BEncoding::Dict(item)]));
+            }
+            assert!(Resume::decode(&root).is_err(), "{} {}", key, value);
+        }
+    }
+
+    #[test]
+    fn test_stale_files() {
+        let temp = TempDir::new("resume");
+        let dir = temp.path();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        fs::File::create(dir.join("data")).unwrap().write_all(&data).unwrap();
+
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("length".to_string(), BEncoding::Int(data.len() as i64));
+        info.insert("piece length".to_string(), BEncoding::Int(32768));
+        let pieces = data.chunks(32768).flat_map(|piece| sha1(&piece.to_vec())).collect();
+        info.insert("pieces".to_string(), BEncoding::Str(pieces));
+        let mut config = Config::default();
+        config.download_dir = dir.to_path_buf();
+        let torrent = Torrent::from_info(&BEncoding::Dict(info), vec![], HashMap::new(), &config).unwrap();
+        assert!(torrent.is_complete());
+
+        let mut resume = Resume::from_torrent(&torrent);
+        assert!(resume.matches(&torrent));
+        resume.partial.insert(5, vec![true]);
+        assert!(!resume.matches(&torrent));
+        resume.partial.clear();
+
+        // The file changed behind our back
+        fs::OpenOptions::new().append(true).open(dir.join("data")).unwrap().write_all(b"more").unwrap();
+        assert!(!resume.matches(&torrent));
+    }
+
+    #[test]
+    fn test_partial_pieces() {
+        let temp = TempDir::new("resume");
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("length".to_string(), BEncoding::Int(data.len() as i64));
+        info.insert("piece length".to_string(), BEncoding::Int(32768));
+        let pieces = data.chunks(32768).flat_map(|piece| sha1(&piece.to_vec())).collect();
+        info.insert("pieces".to_string(), BEncoding::Str(pieces));
+        let info = BEncoding::Dict(info);
+        let mut config = Config::default();
+        config.download_dir = temp.path().to_path_buf();
+
+        let mut torrent = Torrent::from_info(&info, vec![], HashMap::new(), &config).unwrap();
+        torrent.write_block(0, 0, data[..BLOCK_SIZE].to_vec());
+        // Buffered blocks are only kept once they are flushed
+        assert!(Resume::from_torrent(&torrent).partial.is_empty());
+        torrent.flush_write_buffers();
+        assert_eq!(Resume::from_torrent(&torrent).partial.get(&0), Some(&vec![true, false]));
+        torrent.save_resume();
+
+        // The saved block completes the piece along with the one still missing
+        let mut torrent = Torrent::from_info(&info, vec![], HashMap::new(), &config).unwrap();
+        assert_eq!(torrent.is_block_downloaded[0], vec![true, false]);
+        assert!(torrent.is_piece_partial(0));
+        torrent.write_block(0, 1, data[BLOCK_SIZE..2 * BLOCK_SIZE].to_vec());
+        assert!(torrent.is_piece_downloaded[0]);
+    }
+
+    #[test]
+    fn test_storage_without_files() {
+        let torrent = Torrent::in_memory(&[0; 100], 32768, &Config::default());
+
+        // An empty backend can't be told apart from the saved data, the pieces are verified instead
+        let mut resume = Resume::from_torrent(&torrent);
+        resume.pieces = vec![true];
+        assert!(resume.files.is_empty());
+        assert!(!resume.matches(&torrent));
+    }
+}
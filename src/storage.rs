@@ -0,0 +1,498 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use resume::FileState;
+use torrent::FileItem;
+use utils::*;
+
+/// Most file handles a FsStorage keeps open
+const MAX_OPEN_FILES: usize = 64;
+
+/// Where the data of a torrent is kept. Offsets are in the torrent's data,
+/// i.e. the files one after another, the storage maps them to its files.
+///
+/// Storages synchronize themselves so that the disk threads can use them at
+/// once, ideally without waiting on each other for different files.
+pub trait Storage: Send + Sync {
+    fn write_block(&self, offset: usize, data: &[u8]) -> io::Result<()>;
+
+    fn read_block(&self, offset: usize, length: usize) -> io::Result<Vec<u8>>;
+
+    /// SHA-1 of the data of a piece, backends that can hash remotely may
+    /// avoid reading the piece.
+    fn hash_piece(&self, offset: usize, length: usize) -> io::Result<Hash> {
+        let data = try!(self.read_block(offset, length));
+        Ok(Hash::from_slice(&sha1(&data)))
+    }
+
+    /// Makes the writes so far durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Moves the data to another download directory.
+    fn move_to(&self, dir: &Path) -> io::Result<()>;
+
+    /// Deletes the data of the torrent.
+    fn delete(&self) -> io::Result<()>;
+
+    /// Size and modification time of the files, without the pad files, to
+    /// tell whether they changed since the resume data was saved. Storages
+    /// that can't tell return None and their pieces are verified on startup.
+    fn file_states(&self) -> Option<Vec<FileState>> {
+        None
+    }
+
+    /// Tells which files, pad files included, the user skipped. Storages
+    /// may keep the parts of the pieces straddling a wanted file that fall
+    /// in a skipped one apart from the user's files.
+    fn set_skipped(&self, _skipped: &[bool]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parts of the files covered by `length` bytes at `offset`, as the index of
+/// the file, the offset in the file, the offset in the data and the length.
+fn file_slices(files: &[FileItem], offset: usize, length: usize) -> Vec<(usize, usize, usize, usize)> {
+    let end = offset + length;
+    let mut slices = vec![];
+    for (index, file) in files.iter().enumerate() {
+        if end <= file.offset || offset >= file.offset + file.length {
+            continue;
+        }
+        let fstart = cmp::max(offset, file.offset) - file.offset;
+        let fend = cmp::min(end - file.offset, file.length);
+        slices.push((index, fstart, file.offset + fstart - offset, fend - fstart));
+    }
+    slices
+}
+
+/// Download directory and files of a FsStorage
+struct Layout {
+    dir: PathBuf,
+    files: Vec<FileItem>,
+    /// File in `dir` holding the data of the skipped files, at their
+    /// offsets in the torrent
+    part_file: String,
+    /// Skipped files whose data goes to the part file
+    in_part_file: Vec<bool>,
+}
+
+impl Layout {
+    fn part_path(&self) -> PathBuf {
+        self.dir.join(&self.part_file)
+    }
+
+    /// Handle and offset in its file of a slice of a file
+    fn locate(&self, index: usize, fstart: usize) -> (usize, usize) {
+        if self.in_part_file[index] {
+            (self.files.len(), self.files[index].offset + fstart)
+        } else {
+            (index, fstart)
+        }
+    }
+}
+
+/// Stores the torrent in its files in the download directory
+pub struct FsStorage {
+    /// Read by the reads and writes, written to move or delete the files
+    layout: RwLock<Layout>,
+    /// Open handle of each file and then the part file with whether it was
+    /// opened for writing, each file is locked on its own so that different
+    /// files are used at once
+    handles: Vec<Mutex<Option<(fs::File, bool)>>>,
+    /// Files with an open handle, the least recently opened first
+    open: Mutex<VecDeque<usize>>,
+}
+
+impl FsStorage {
+    /// Storage of `files` in `dir`, the data of the skipped files goes to
+    /// the file named `part_file` in `dir`.
+    pub fn new(dir: PathBuf, files: &[FileItem], part_file: &str) -> FsStorage {
+        FsStorage {
+            layout: RwLock::new(Layout {
+                dir: dir,
+                files: files.to_vec(),
+                part_file: part_file.into(),
+                in_part_file: vec![false; files.len()],
+            }),
+            handles: (0..files.len() + 1).map(|_| Mutex::new(None)).collect(),
+            open: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Runs `f` on the handle of a file, reopened for writing if needed.
+    /// Missing files are only created for writes.
+    fn with_file<T, F>(&self, layout: &Layout, index: usize, write: bool, f: F) -> io::Result<T>
+        where F: FnOnce(&mut fs::File) -> io::Result<T>
+    {
+        let mut handle = self.handles[index].lock().unwrap();
+        let reopen = match *handle {
+            Some((_, writable)) => write && !writable,
+            None => true,
+        };
+        if reopen {
+            let path = if index == layout.files.len() {
+                layout.part_path()
+            } else {
+                PathBuf::from(&layout.files[index].path)
+            };
+            let file = if write {
+                // Create directories in the file path if they don't exist
+                if let Some(dirs) = path.parent() {
+                    try!(fs::create_dir_all(dirs));
+                }
+                try!(fs::OpenOptions::new().read(true).write(true).create(true).open(&path))
+            } else {
+                try!(fs::OpenOptions::new().read(true).open(&path))
+            };
+            if handle.is_none() {
+                self.opened(index);
+            }
+            *handle = Some((file, write));
+        }
+        f(&mut handle.as_mut().unwrap().0)
+    }
+
+    /// Records that a file was opened, closing the oldest handle not in use
+    /// when too many are open.
+    fn opened(&self, index: usize) {
+        let mut open = self.open.lock().unwrap();
+        open.push_back(index);
+        if open.len() <= MAX_OPEN_FILES {
+            return;
+        }
+        // Only try the locks, the thread using a handle may be waiting for `open`
+        let victim = open.iter().position(|&i| i != index && match self.handles[i].try_lock() {
+            Ok(mut handle) => {
+                *handle = None;
+                true
+            },
+            Err(_) => false,
+        });
+        if let Some(position) = victim {
+            open.remove(position);
+        }
+    }
+
+    /// Closes every handle, the caller holds the layout for writing.
+    fn close_all(&self) {
+        for handle in &self.handles {
+            *handle.lock().unwrap() = None;
+        }
+        self.open.lock().unwrap().clear();
+    }
+
+    /// Copies the data of a file kept in the part file to the file itself.
+    fn copy_from_part_file(&self, layout: &Layout, index: usize) -> io::Result<()> {
+        let file = &layout.files[index];
+        let part_length = match fs::metadata(layout.part_path()) {
+            Ok(meta) => meta.len() as usize,
+            Err(_) => return Ok(()),
+        };
+        let end = cmp::min(file.offset + file.length, part_length);
+        let mut offset = file.offset;
+        while offset < end {
+            let length = cmp::min(end - offset, 1 << 20);
+            let mut data = vec![0; length];
+            try!(self.with_file(layout, layout.files.len(), false, |f| {
+                try!(f.seek(SeekFrom::Start(offset as u64)));
+                f.read_exact(&mut data)
+            }));
+            try!(self.with_file(layout, index, true, |f| {
+                try!(f.seek(SeekFrom::Start((offset - file.offset) as u64)));
+                f.write_all(&data)
+            }));
+            offset += length;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn write_block(&self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let layout = self.layout.read().unwrap();
+        for (index, fstart, bstart, length) in file_slices(&layout.files, offset, data.len()) {
+            if layout.files[index].pad {
+                continue;
+            }
+            let (handle, position) = layout.locate(index, fstart);
+            try!(self.with_file(&layout, handle, true, |f| {
+                try!(f.seek(SeekFrom::Start(position as u64)));
+                f.write_all(&data[bstart..bstart + length])
+            }));
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let layout = self.layout.read().unwrap();
+        let mut data = vec![0; length];
+        for (index, fstart, bstart, length) in file_slices(&layout.files, offset, length) {
+            // Pad files read as zeros
+            if layout.files[index].pad {
+                continue;
+            }
+            let (handle, position) = layout.locate(index, fstart);
+            try!(self.with_file(&layout, handle, false, |f| {
+                try!(f.seek(SeekFrom::Start(position as u64)));
+                f.read_exact(&mut data[bstart..bstart + length])
+            }));
+        }
+        Ok(data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for handle in &self.handles {
+            if let Some((ref f, true)) = *handle.lock().unwrap() {
+                try!(f.sync_data());
+            }
+        }
+        Ok(())
+    }
+
+    fn move_to(&self, dir: &Path) -> io::Result<()> {
+        let mut layout = self.layout.write().unwrap();
+        try!(self.flush());
+        self.close_all();
+        let old_dir = layout.dir.clone();
+        for file in layout.files.iter_mut().filter(|f| !f.pad) {
+            let relative = match Path::new(&file.path).strip_prefix(&old_dir) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+            let target = dir.join(relative);
+            if Path::new(&file.path).exists() {
+                if let Some(dirs) = target.parent() {
+                    try!(fs::create_dir_all(dirs));
+                }
+                // Renames fail across filesystems, copy the file instead
+                if fs::rename(&file.path, &target).is_err() {
+                    try!(fs::copy(&file.path, &target));
+                    try!(fs::remove_file(&file.path));
+                }
+            }
+            file.path = target.to_str().unwrap().into();
+        }
+        let part_path = layout.part_path();
+        if part_path.exists() {
+            try!(fs::create_dir_all(dir));
+            let target = dir.join(&layout.part_file);
+            if fs::rename(&part_path, &target).is_err() {
+                try!(fs::copy(&part_path, &target));
+                try!(fs::remove_file(&part_path));
+            }
+        }
+        layout.dir = dir.to_path_buf();
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        let layout = self.layout.write().unwrap();
+        self.close_all();
+        if let Err(err) = fs::remove_file(layout.part_path()) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        for file in layout.files.iter().filter(|f| !f.pad) {
+            if let Err(err) = fs::remove_file(&file.path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+            // Remove the directories left empty, up to the download directory
+            let mut dir = Path::new(&file.path).parent();
+            while let Some(path) = dir {
+                if path == layout.dir || fs::remove_dir(path).is_err() {
+                    break;
+                }
+                dir = path.parent();
+            }
+        }
+        Ok(())
+    }
+
+    fn file_states(&self) -> Option<Vec<FileState>> {
+        let layout = self.layout.read().unwrap();
+        let mut states: Vec<FileState> = layout.files.iter().filter(|f| !f.pad).map(|f| FileState::from_path(&f.path)).collect();
+        states.push(FileState::from_path(layout.part_path().to_str().unwrap()));
+        Some(states)
+    }
+
+    /// Skipped files that don't exist yet are kept in the part file, so the
+    /// pieces they share with wanted files don't create them. They're copied
+    /// out of it once wanted again, also after a restart when they still
+    /// don't exist, and the part file is removed when no file is kept in it.
+    fn set_skipped(&self, skipped: &[bool]) -> io::Result<()> {
+        let mut layout = self.layout.write().unwrap();
+        for index in 0..layout.files.len() {
+            if layout.files[index].pad {
+                continue;
+            }
+            let exists = Path::new(&layout.files[index].path).exists();
+            if !skipped[index] && (layout.in_part_file[index] || !exists) {
+                layout.in_part_file[index] = false;
+                try!(self.copy_from_part_file(&layout, index));
+            } else if skipped[index] && !exists {
+                layout.in_part_file[index] = true;
+            }
+        }
+        if !layout.in_part_file.iter().any(|&in_part_file| in_part_file) {
+            self.close_all();
+            if let Err(err) = fs::remove_file(layout.part_path()) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the torrent in memory, for tests and embedding
+pub struct MemoryStorage {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(files: &[FileItem]) -> MemoryStorage {
+        MemoryStorage {
+            data: Mutex::new(vec![0; files.iter().fold(0, |sum, f| sum + f.length)]),
+        }
+    }
+
+    /// The data of the torrent, unwritten parts are zeros.
+    pub fn data(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_block(&self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let mut buffer = self.data.lock().unwrap();
+        if offset + data.len() > buffer.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block is out of the torrent bounds"));
+        }
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(&self, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let buffer = self.data.lock().unwrap();
+        if offset + length > buffer.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "block is out of the torrent bounds"));
+        }
+        Ok(buffer[offset..offset + length].to_vec())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&self, _: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        for byte in self.data.lock().unwrap().iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(dir: &Path) -> Vec<FileItem> {
+        vec![
+            FileItem { length: 10, offset: 0, path: dir.join("t").join("a").to_str().unwrap().into(), pad: false },
+            FileItem { length: 6, offset: 10, path: ".pad/6".into(), pad: true },
+            FileItem { length: 20, offset: 16, path: dir.join("t").join("sub").join("b").to_str().unwrap().into(), pad: false },
+        ]
+    }
+
+    #[test]
+    fn test_fs_storage() {
+        let temp = TempDir::new("storage");
+        let dir = temp.path();
+        let storage = FsStorage::new(dir.to_path_buf(), &files(dir), ".parts");
+        assert!(storage.read_block(0, 10).is_err());
+
+        storage.write_block(5, &[7; 20]).unwrap();
+        assert_eq!(fs::metadata(dir.join("t").join("a")).unwrap().len(), 10);
+        assert_eq!(fs::metadata(dir.join("t").join("sub").join("b")).unwrap().len(), 9);
+        let data = storage.read_block(8, 10).unwrap();
+        assert_eq!(data, vec![7, 7, 0, 0, 0, 0, 0, 0, 7, 7]);
+        assert_eq!(storage.hash_piece(8, 10).unwrap(), Hash::from_slice(&sha1(&data)));
+
+        let moved = dir.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert!(!dir.join("t").join("a").exists());
+        assert_eq!(storage.read_block(8, 10).unwrap(), data);
+
+        storage.delete().unwrap();
+        assert!(!moved.join("t").exists());
+    }
+
+    #[test]
+    fn test_files_are_locked_separately() {
+        let temp = TempDir::new("storage");
+        let dir = temp.path();
+        let storage = FsStorage::new(dir.to_path_buf(), &files(dir), ".parts");
+        storage.write_block(0, &[1; 36]).unwrap();
+
+        // A thread busy with the first file doesn't hold up the second
+        let handle = storage.handles[0].lock().unwrap();
+        assert_eq!(storage.read_block(16, 20).unwrap(), vec![1; 20]);
+        drop(handle);
+
+        let storage = ::std::sync::Arc::new(storage);
+        let threads: Vec<_> = [0, 5, 16, 21, 26, 31].iter().map(|&offset| {
+            let storage = storage.clone();
+            ::std::thread::spawn(move || {
+                for _ in 0..50 {
+                    storage.write_block(offset, &[offset as u8; 5]).unwrap();
+                    assert_eq!(storage.read_block(offset, 5).unwrap(), vec![offset as u8; 5]);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_skipped_files_in_part_file() {
+        let temp = TempDir::new("storage");
+        let dir = temp.path();
+        let storage = FsStorage::new(dir.to_path_buf(), &files(dir), ".parts");
+        storage.set_skipped(&[false, false, true]).unwrap();
+        storage.write_block(5, &[7; 20]).unwrap();
+        assert!(!dir.join("t").join("sub").join("b").exists());
+        assert_eq!(fs::metadata(dir.join(".parts")).unwrap().len(), 25);
+        let data = storage.read_block(8, 10).unwrap();
+        assert_eq!(data, vec![7, 7, 0, 0, 0, 0, 0, 0, 7, 7]);
+        drop(storage);
+
+        // After a restart the file is copied out of the part file once wanted
+        let storage = FsStorage::new(dir.to_path_buf(), &files(dir), ".parts");
+        storage.set_skipped(&[false, false, false]).unwrap();
+        assert_eq!(fs::metadata(dir.join("t").join("sub").join("b")).unwrap().len(), 9);
+        assert!(!dir.join(".parts").exists());
+        assert_eq!(storage.read_block(8, 10).unwrap(), data);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new(&files(Path::new("/nonexistent")));
+        storage.write_block(30, &[1; 6]).unwrap();
+        assert!(storage.write_block(31, &[1; 6]).is_err());
+        assert_eq!(storage.read_block(28, 4).unwrap(), vec![0, 0, 1, 1]);
+        assert_eq!(storage.data().len(), 36);
+    }
+}
@@ -1,20 +1,24 @@
 use std::path::{PathBuf, Path};
 use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use std::fs;
 use std::cmp;
-use std::io::{
-    self,
-    Seek,
-    SeekFrom,
-    Write,
-    Read
-};
+use std::convert::TryFrom;
+use std::io::{self, Write};
 
 use bencoding::*;
 use tracker::*;
 use utils::*;
+use merkle::{self, HashRequest, MerkleFile};
 use peer::Peer;
+use picker::Priority;
+use resume::Resume;
+use storage::{Storage, FsStorage};
+use config::Config;
+use magnet::Magnet;
+use metadata;
+use dht;
 
 /// Files in a Torrent
 #[derive(Clone)]
@@ -22,6 +26,50 @@ pub struct FileItem {
     pub length: usize,
     pub offset: usize,
     pub path: String,
+    /// Pad files (BEP 47) only align the next file to a piece boundary,
+    /// they read as zeros and are never written to disk.
+    pub pad: bool,
+}
+
+/// Blocks of a piece kept in memory until all of them are in, hashed in
+/// order as they arrive
+#[derive(Clone)]
+struct PieceBuffer {
+    blocks: Vec<Option<Vec<u8>>>,
+    hasher: ::sha1::Sha1,
+    hashed: usize,
+}
+
+impl PieceBuffer {
+    fn new(block_count: usize) -> PieceBuffer {
+        PieceBuffer {
+            blocks: vec![None; block_count],
+            hasher: ::sha1::Sha1::new(),
+            hashed: 0,
+        }
+    }
+
+    fn add(&mut self, block: usize, data: Vec<u8>) {
+        self.blocks[block] = Some(data);
+        while self.hashed < self.blocks.len() {
+            match self.blocks[self.hashed] {
+                Some(ref data) => self.hasher.update(data),
+                None => break,
+            }
+            self.hashed += 1;
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.hashed == self.blocks.len()
+    }
+
+    /// The data of the piece and its SHA-1 hash
+    fn finish(self) -> (Vec<u8>, Hash) {
+        let hash = Hash(self.hasher.digest().bytes());
+        let data = self.blocks.into_iter().flat_map(|block| block.unwrap()).collect();
+        (data, hash)
+    }
 }
 
 /// Info parsed from a .torrent file
@@ -29,216 +77,837 @@ pub struct FileItem {
 pub struct Torrent {
     pub name: String,
     pub info_hash: Hash,
+    /// SHA-256 info hash of v2 and hybrid torrents
+    pub info_hash_v2: Option<Hash256>,
+    pub metadata: Vec<u8>,
     pub tracker: Tracker,
     pub piece_size: usize,
     pub pieces_hashes: Vec<Hash>,
     pub files: Vec<FileItem>,
+    /// Whether the file is in the download directory rather than in a
+    /// directory named after the torrent
+    single_file: bool,
+    /// Merkle trees of the files of v2 and hybrid torrents
+    pub merkle_files: Vec<MerkleFile>,
     pub no_of_pieces: usize,
+    /// Priority of each of the files, pad files included
+    pub file_priorities: Vec<Priority>,
+    pub piece_priorities: Vec<Priority>,
     pub is_piece_downloaded: Vec<bool>,
     pub is_block_downloaded: Vec<Vec<bool>>,
+    /// Number of peers each block is requested from
+    block_requests: Vec<Vec<usize>>,
+    /// Blocks of each piece that are downloaded or requested, so that endgame
+    /// and partial pieces are known without going through every block
+    active_blocks: Vec<usize>,
     pub peers: HashMap<SocketAddr, Peer>,
     pub seeders: Vec<SocketAddr>,
+    pub known_peers: Vec<SocketAddr>,
+    pub download_dir: PathBuf,
+    pub resume_file: PathBuf,
+    /// Backend the pieces are written to and read from
+    pub storage: Arc<dyn Storage>,
+    write_buffers: HashMap<usize, PieceBuffer>,
 }
 
 impl Torrent {
-    pub fn new(file: &str) -> Result<Torrent, Error> {
-        let root = BEncoding::decode_file(&file).unwrap();
+    pub fn new(file: &str, config: &Config) -> Result<Torrent, Error> {
+        let root = try!(BEncoding::decode_file(&file).ok_or(Error::DecodeError));
         println!("torrent: contents {}", root);
+
+        let tracker_list = try!(Self::tracker_list(&root));
         let info = try!(root.get_dict("info"));
-        let info_hash = {
-            let data = BEncoding::encode(&info);
-            let hash = sha1(&data);
-            Hash::from_slice(&hash)
-        };
+        Self::from_info(info, tracker_list, Self::piece_layers(&root), config)
+    }
+
+    /// Reads the trackers of a .torrent file or magnet link without opening
+    /// the files of the torrent.
+    pub fn read_tracker(file: &str, config: &Config) -> ::error::Result<Tracker> {
+        if file.starts_with("magnet:?") {
+            let magnet = try!(Magnet::new(file).map_err(|err| ::error::Error::Metadata(err.into())));
+            let info_hash = try!(magnet.info_hash().map_err(|err| ::error::Error::Metadata(err.into())));
+            return Ok(Tracker::new(Self::magnet_tiers(&magnet), info_hash, config));
+        }
 
+        let root = try!(BEncoding::decode_file(&file).ok_or(Error::DecodeError));
+        let tracker_list = try!(Self::tracker_list(&root));
         let info = try!(root.get_dict("info"));
-        let name = try!(info.get_str("name"));
+        let (info_hash, _) = Self::info_hashes(&BEncoding::encode(info), info);
+        Ok(Tracker::new(tracker_list, info_hash, config))
+    }
 
-        let mut tracker_list = vec![];
-        if let Ok(tracker) = root.get_str("announce") {
-            tracker_list.push(tracker);
+    /// Info hash used on the wire along with the v2 info hash. Hybrid
+    /// torrents go by their v1 hash, pure v2 ones by the truncated v2 hash.
+    fn info_hashes(metadata: &Vec<u8>, info: &BEncoding) -> (Hash, Option<Hash256>) {
+        let info_hash_v2 = match info.get_int("meta version") {
+            Ok(2) => Some(Hash256(sha256(metadata))),
+            _ => None,
+        };
+        match info_hash_v2 {
+            Some(hash) if info.get_bytes("pieces").is_err() => (hash.truncated(), info_hash_v2),
+            _ => (Hash::from_slice(&sha1(metadata)), info_hash_v2),
+        }
+    }
+
+    /// Piece hashes of the v2 files, keyed by the root of their merkle tree (BEP 52)
+    fn piece_layers(root: &BEncoding) -> HashMap<Hash256, Vec<Hash256>> {
+        let mut piece_layers = HashMap::new();
+        if let Ok(layers) = root.get_dict("piece layers").and_then(|layers| layers.to_binary_dict()) {
+            for (pieces_root, layer) in layers {
+                match layer.to_bytes() {
+                    Ok(ref hashes) if pieces_root.len() == 32 && hashes.len() % 32 == 0 => {
+                        let hashes = hashes.chunks(32).map(Hash256::from_slice).collect();
+                        piece_layers.insert(Hash256::from_slice(&pieces_root), hashes);
+                    },
+                    _ => println!("torrent: invalid piece layer"),
+                }
+            }
+        }
+        piece_layers
+    }
+
+    /// Files of a v2 file tree in order, as their path, length and pieces root
+    fn file_tree(tree: &BEncoding, path: Vec<String>, files: &mut Vec<(Vec<String>, usize, Option<Hash256>)>) -> Result<(), Error> {
+        for (name, node) in try!(tree.to_dict()) {
+            if name.is_empty() {
+                let length = try!(Self::file_length(node));
+                let pieces_root = match node.get_bytes("pieces root") {
+                    Ok(ref bytes) if bytes.len() == 32 => Some(Hash256::from_slice(bytes)),
+                    _ => None,
+                };
+                files.push((path.clone(), length, pieces_root));
+            } else {
+                try!(Self::check_path_part(name));
+                let mut path = path.clone();
+                path.push(name.clone());
+                try!(Self::file_tree(node, path, files));
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of a file of the info, which can come from any peer
+    fn file_length(file: &BEncoding) -> Result<usize, Error> {
+        usize::try_from(try!(file.get_int("length"))).map_err(|_| Error::DecodeError)
+    }
+
+    /// Rejects the names of files and directories that would escape the
+    /// download directory, the info can come from any peer.
+    fn check_path_part(name: &str) -> Result<(), Error> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\\' || c == '\0') {
+            return Err(Error::DecodeError);
         }
+        Ok(())
+    }
+
+    /// Tiers of trackers of the torrent, `announce-list` replaces `announce` when present (BEP 12)
+    fn tracker_list(root: &BEncoding) -> Result<Vec<Vec<String>>, Error> {
+        let mut tracker_list = vec![];
         if let Ok(announce_list) = root.get_list("announce-list") {
             for list in announce_list {
+                let mut tier = vec![];
                 for tracker in try!(list.to_list()) {
-                    tracker_list.push(try!(tracker.to_str()));
+                    tier.push(try!(tracker.to_str()));
                 }
+                tracker_list.push(tier);
+            }
+        }
+        if tracker_list.is_empty() {
+            if let Ok(tracker) = root.get_str("announce") {
+                tracker_list.push(vec![tracker]);
+            }
+        }
+        Ok(tracker_list)
+    }
+
+    /// Builds the torrent from a magnet link by downloading the info
+    /// dictionary from the peers of the magnet's trackers.
+    pub fn from_magnet(link: &str, config: &Config) -> ::error::Result<Torrent> {
+        let magnet = try!(Magnet::new(link).map_err(|err| ::error::Error::Metadata(err.into())));
+        let info_hash = try!(magnet.info_hash().map_err(|err| ::error::Error::Metadata(err.into())));
+        println!("torrent: fetching metadata for {} from {:?}", info_hash, magnet.tr);
+
+        let tracker = Tracker::new(Self::magnet_tiers(&magnet), info_hash, config);
+        let mut peers = tracker.get_peers_addresses();
+        if peers.is_empty() && config.dht_port != 0 {
+            println!("torrent: no peers from trackers, searching the dht");
+            peers = dht::find_peers(config, &info_hash);
+        }
+        let metadata = try!(metadata::fetch(&info_hash, &peers));
+        let info = try!(BEncoding::decode(metadata).ok_or(Error::DecodeError));
+
+        // The piece layers of v2 torrents aren't part of the info, they're requested from the peers
+        let torrent = try!(Self::from_info(&info, Self::magnet_tiers(&magnet), HashMap::new(), config));
+
+        if let Some(ref path) = config.save_torrent {
+            let mut root = BTreeMap::new();
+            if let Some(tracker) = magnet.tr.first() {
+                root.insert("announce".to_string(), BEncoding::Str(tracker.clone().into_bytes()));
             }
+            if magnet.tr.len() > 1 {
+                let tiers = magnet.tr.iter().map(|tr| BEncoding::List(vec![BEncoding::Str(tr.clone().into_bytes())])).collect();
+                root.insert("announce-list".to_string(), BEncoding::List(tiers));
+            }
+            root.insert("info".to_string(), info);
+            let mut f = try!(fs::File::create(path));
+            try!(f.write_all(&BEncoding::encode(&BEncoding::Dict(root))));
+            println!("torrent: saved metadata to {}", path.display());
         }
 
-        let piece_size = try!(info.get_int("piece length")) as usize;
-        let pieces = try!(info.get_bytes("pieces"));
+        Ok(torrent)
+    }
+
+    /// Each tracker of a magnet link is a tier of its own
+    fn magnet_tiers(magnet: &Magnet) -> Vec<Vec<String>> {
+        magnet.tr.iter().map(|tr| vec![tr.clone()]).collect()
+    }
+
+    /// Builds the torrent from its info dictionary, v1, v2 (BEP 52) or hybrid.
+    pub fn from_info(info: &BEncoding, tracker_list: Vec<Vec<String>>, piece_layers: HashMap<Hash256, Vec<Hash256>>, config: &Config) -> Result<Torrent, Error> {
+        let dir = config.download_dir.clone();
+        let (info_hash, _) = Self::info_hashes(&BEncoding::encode(info), info);
+        let part_file = format!(".{}.parts", info_hash);
+        Self::from_info_with_storage(info, tracker_list, piece_layers, config, move |files| {
+            Box::new(FsStorage::new(dir, files, &part_file)) as Box<dyn Storage>
+        })
+    }
+
+    /// Builds the torrent from its info dictionary with the storage made
+    /// for its files by `storage`.
+    pub fn from_info_with_storage<F>(info: &BEncoding, tracker_list: Vec<Vec<String>>, piece_layers: HashMap<Hash256, Vec<Hash256>>, config: &Config, storage: F) -> Result<Torrent, Error>
+        where F: FnOnce(&[FileItem]) -> Box<dyn Storage>
+    {
+        let metadata = BEncoding::encode(&info);
+        let (info_hash, info_hash_v2) = Self::info_hashes(&metadata, info);
+
+        let name = try!(info.get_str("name"));
+        try!(Self::check_path_part(&name));
 
-        // Split the pieces into 20 byte sha1 hashes
+        let piece_size = try!(info.get_int("piece length"));
+        if piece_size <= 0 {
+            return Err(Error::DecodeError);
+        }
+        let piece_size = piece_size as usize;
+        if info_hash_v2.is_some() && (piece_size < merkle::MERKLE_BLOCK_SIZE || !piece_size.is_power_of_two()) {
+            return Err(Error::DecodeError);
+        }
+
+        // Split the pieces into 20 byte sha1 hashes, pure v2 torrents have none
+        let pieces = info.get_bytes("pieces").unwrap_or(vec![]);
+        if pieces.len() % 20 != 0 || (pieces.is_empty() && info_hash_v2.is_none()) {
+            return Err(Error::DecodeError);
+        }
         let hashes: Vec<Hash> =
             pieces
             .chunks(20)
             .map(|chunk| { Hash::from_slice(chunk) })
             .collect();
-        let no_of_pieces = hashes.len();
+
+        let mut v2_files = vec![];
+        if info_hash_v2.is_some() {
+            try!(Self::file_tree(try!(info.get_dict("file tree")), vec![], &mut v2_files));
+        }
 
         // Parse files list from the info
         let mut file_items = vec![];
-        let dl_path = PathBuf::from("/tmp"); // FIXME: change this to download directory
-        if let Ok(files) = info.get_list("files") {
-            // Multiple File Mode
-            let dir = name.clone();
+        let mut single_file = false;
+        let dl_path = config.download_dir.clone();
+        if !hashes.is_empty() {
+            if let Ok(files) = info.get_list("files") {
+                // Multiple File Mode
+                let dir = name.clone();
+                let mut offset = 0;
+                for file in files {
+                    let len = try!(Self::file_length(file));
+                    let path = try!(file.get_list("path"));
+                    let mut file_path = dl_path.clone();
+                    file_path.push(dir.clone());
+                    for part in path {
+                        let part = try!(part.to_str());
+                        try!(Self::check_path_part(&part));
+                        file_path.push(part);
+                    }
+                    file_items.push(FileItem {
+                        path: file_path.to_str().unwrap().into(),
+                        length: len,
+                        offset: offset,
+                        pad: file.get_str("attr").map(|attr| attr.contains('p')).unwrap_or(false),
+                    });
+                    offset = try!(offset.checked_add(len).ok_or(Error::DecodeError));
+                }
+            } else {
+                // Single File Mode
+                single_file = true;
+                let file_length = try!(Self::file_length(info));
+                let file_name = name.clone();
+                let mut file_path = dl_path.clone();
+                file_path.push(file_name);
+                file_items.push(FileItem {
+                    path: file_path.to_str().unwrap().into(),
+                    length: file_length,
+                    offset: 0,
+                    pad: false,
+                });
+            }
+        } else {
+            // Pure v2, a single file torrent has one file named after the torrent
+            single_file = v2_files.len() == 1 && v2_files[0].0 == vec![name.clone()];
             let mut offset = 0;
-            for file in files {
-                let len = try!(file.get_int("length")) as usize;
-                let path = try!(file.get_list("path"));
+            for (i, &(ref path, length, _)) in v2_files.iter().enumerate() {
                 let mut file_path = dl_path.clone();
-                file_path.push(dir.clone());
+                if !single_file {
+                    file_path.push(name.clone());
+                }
                 for part in path {
-                    let p = part.to_str().unwrap();
-                    file_path.push(p);
+                    file_path.push(part);
                 }
                 file_items.push(FileItem {
                     path: file_path.to_str().unwrap().into(),
-                    length: len,
+                    length: length,
                     offset: offset,
+                    pad: false,
                 });
-                offset += len;
+                offset = try!(offset.checked_add(length).ok_or(Error::DecodeError));
+
+                // Every file starts at a piece boundary
+                if i + 1 < v2_files.len() && offset % piece_size != 0 {
+                    let pad = piece_size - offset % piece_size;
+                    file_items.push(FileItem {
+                        path: format!(".pad/{}", pad),
+                        length: pad,
+                        offset: offset,
+                        pad: true,
+                    });
+                    offset = try!(offset.checked_add(pad).ok_or(Error::DecodeError));
+                }
             }
-        } else {
-            // Single File Mode
-            let file_length = try!(info.get_int("length")) as usize;
-            let file_name = name.clone();
-            let mut file_path = dl_path.clone();
-            file_path.push(file_name);
-            file_items.push(FileItem {
-                path: file_path.to_str().unwrap().into(),
-                length: file_length,
-                offset: 0,
-            });
         }
 
-        println!("torrent: hash is {}", info_hash);
-        for file in &file_items {
-            println!("torrent: file is {}", file.path);
+        let total_size = try!(file_items.iter().try_fold(0usize, |sum, f| sum.checked_add(f.length)).ok_or(Error::DecodeError));
+        let no_of_pieces = try!(total_size.checked_add(piece_size - 1).ok_or(Error::DecodeError)) / piece_size;
+        if !hashes.is_empty() && hashes.len() != no_of_pieces {
+            return Err(Error::DecodeError);
+        }
+
+        // The files with data have a merkle tree each, in the same order in the v1 and v2 lists
+        let mut merkle_files = vec![];
+        let mut v2_files = v2_files.iter().filter(|&&(_, length, _)| length > 0);
+        for (index, file) in file_items.iter().enumerate().filter(|&(_, f)| info_hash_v2.is_some() && !f.pad && f.length > 0) {
+            let &(_, length, pieces_root) = try!(v2_files.next().ok_or(Error::DecodeError));
+            let pieces_root = try!(pieces_root.ok_or(Error::MissingKey("pieces root".into())));
+            if length != file.length || file.offset % piece_size != 0 {
+                return Err(Error::DecodeError);
+            }
+            let mut merkle_file = MerkleFile::new(index, length, pieces_root, file.offset / piece_size, piece_size);
+            if let Some(layer) = piece_layers.get(&pieces_root) {
+                if !merkle_file.set_piece_layer(layer.clone(), piece_size) {
+                    println!("torrent: invalid piece layer for {}", file.path);
+                }
+            }
+            merkle_files.push(merkle_file);
         }
 
+        println!("torrent: hash is {}", info_hash);
+        if let Some(ref hash) = info_hash_v2 {
+            println!("torrent: v2 hash is {}", hash);
+        }
+        let storage = storage(&file_items);
         let mut t = Torrent {
             name: name,
             info_hash: info_hash.clone(),
-            tracker: Tracker::new(tracker_list, info_hash.clone()),
+            info_hash_v2: info_hash_v2,
+            metadata: metadata,
+            tracker: Tracker::new(tracker_list, info_hash.clone(), config),
             piece_size: piece_size,
             pieces_hashes: hashes,
             files: file_items,
+            single_file: single_file,
+            merkle_files: merkle_files,
             no_of_pieces: no_of_pieces,
+            file_priorities: vec![],
+            piece_priorities: vec![],
             is_piece_downloaded: vec![false; no_of_pieces as usize],
             is_block_downloaded: vec![],
+            block_requests: vec![],
+            active_blocks: vec![0; no_of_pieces],
             peers: HashMap::new(),
             seeders: vec![],
+            known_peers: vec![],
+            resume_file: dl_path.join(format!(".{}.resume", info_hash)),
+            download_dir: dl_path,
+            storage: Arc::from(storage),
+            write_buffers: HashMap::new(),
         };
         for piece in 0..t.no_of_pieces {
             let block_count = t.get_block_count(piece);
             t.is_block_downloaded.push(vec![false; block_count]);
-            t.verify_piece(piece);
+            t.block_requests.push(vec![0; block_count]);
+        }
+        t.file_priorities = vec![Priority::Normal; t.files.len()];
+        t.set_file_priorities(&config.file_priorities);
+        if !t.load_resume() {
+            for piece in 0..t.no_of_pieces {
+                t.verify_piece(piece);
+            }
         }
         Ok(t)
     }
 
-    pub fn write_block(&mut self, piece: usize, block: usize, data: Vec<u8>) {
-        match self.write(piece * self.piece_size + block * BLOCK_SIZE, data) {
-            Ok(_) => {},
-            Err(err) => {
-                println!("torrent: error occured while writing a block {}", err);
-                return;
+    /// Index in `files` of a file as numbered for the user, without the pad files
+    fn file_position(&self, file: usize) -> Option<usize> {
+        self.files.iter().enumerate().filter(|&(_, f)| !f.pad).nth(file).map(|(position, _)| position)
+    }
+
+    /// Offset in the torrent of a byte of a file, numbered from 0 without the pad files.
+    pub fn file_offset(&self, file: usize, offset: usize) -> Option<usize> {
+        self.file_position(file)
+            .map(|position| &self.files[position])
+            .filter(|item| offset < item.length)
+            .map(|item| item.offset + offset)
+    }
+
+    /// Sets the priority of a file, numbered from 0 without the pad files.
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) -> bool {
+        match self.file_position(file) {
+            Some(position) => {
+                println!("torrent: file {} is now {:?}", self.files[position].path, priority);
+                self.file_priorities[position] = priority;
+                self.update_piece_priorities();
+                true
             },
+            None => false,
         }
-        self.is_block_downloaded[piece][block] = true;
+    }
 
-        let block_count = self.get_block_count(piece);
-        let completed_block_count = self.get_completed_block_count(piece);
-        if block_count == completed_block_count {
-            self.verify_piece(piece);
+    /// Applies the priorities of the files matching the selectors in order. A
+    /// selector is a file index or a glob pattern, matched against the path
+    /// in the torrent or only the file name when it has no `/`.
+    pub fn set_file_priorities(&mut self, selectors: &[(String, Priority)]) {
+        let dir = if self.single_file { self.download_dir.clone() } else { self.download_dir.join(&self.name) };
+        for (file, item) in self.files.iter().filter(|f| !f.pad).enumerate() {
+            let path = Path::new(&item.path).strip_prefix(&dir).map(|p| p.to_string_lossy().into_owned()).unwrap_or(item.path.clone());
+            let name = Path::new(&item.path).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            for &(ref selector, priority) in selectors {
+                let matches = match selector.parse::<usize>() {
+                    Ok(index) => index == file,
+                    Err(_) if selector.contains('/') => glob_match(selector, &path),
+                    Err(_) => glob_match(selector, &name),
+                };
+                if matches {
+                    let position = self.files.iter().position(|f| f.path == item.path && !f.pad).unwrap();
+                    self.file_priorities[position] = priority;
+                }
+            }
+        }
+        self.update_piece_priorities();
+        for (file, (item, priority)) in self.files.iter().zip(self.file_priorities.iter()).filter(|&(f, _)| !f.pad).enumerate() {
+            println!("torrent: file {} is {} ({:?})", file, item.path, priority);
         }
     }
 
-    fn write(&self, start: usize, data: Vec<u8>) -> io::Result<bool> {
-        let end = start + data.len();
-        for file in &self.files {
-            if (start < file.offset && end < file.offset) || (start > file.offset + file.length && end > file.offset + file.length) {
+    /// Pieces get the highest priority of the files they overlap, so the
+    /// pieces straddling a skipped and a wanted file are downloaded whole,
+    /// the storage keeps the part of the skipped file apart.
+    fn update_piece_priorities(&mut self) {
+        let mut priorities = vec![Priority::Skip; self.no_of_pieces];
+        for (file, &priority) in self.files.iter().zip(self.file_priorities.iter()) {
+            if file.pad || file.length == 0 {
                 continue;
             }
-            let fstart = cmp::max(0, start as i64 - file.offset as i64) as usize;
-            let fend = cmp::min(end - file.offset, file.length);
-            let flength = fend - fstart;
-            let bstart = cmp::max(0, file.offset as i64 - start as i64) as usize;
-            let bend = bstart + flength;
-
-            // Create directories in the file path if they don't exist
-            if let Some(dirs) = Path::new(&file.path).parent() {
-                try!(fs::create_dir_all(dirs));
+            let first = file.offset / self.piece_size;
+            let last = (file.offset + file.length - 1) / self.piece_size;
+            for piece in first..=last {
+                priorities[piece] = cmp::max(priorities[piece], priority);
             }
-
-            let mut f = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(&file.path));
-            try!(f.seek(SeekFrom::Start(fstart as u64)));
-            try!(f.write(&data[bstart..bend]));
         }
-        Ok(true)
+        self.piece_priorities = priorities;
+        let skipped: Vec<bool> = self.file_priorities.iter().map(|&priority| priority == Priority::Skip).collect();
+        if let Err(err) = self.storage.set_skipped(&skipped) {
+            println!("torrent: error while keeping the skipped files apart {}", err);
+        }
     }
 
-    fn verify_piece(&mut self, piece: usize) {
-        let data = match self.read_piece(piece) {
-            Ok(data) => data,
-            Err(_) => return,
+    pub fn is_piece_wanted(&self, piece: usize) -> bool {
+        self.piece_priorities[piece] != Priority::Skip
+    }
+
+    /// Whether every piece of the files that aren't skipped is downloaded.
+    pub fn is_wanted_complete(&self) -> bool {
+        (0..self.no_of_pieces).all(|piece| self.is_piece_downloaded[piece] || !self.is_piece_wanted(piece))
+    }
+
+    /// Restores the download state from the fast-resume file if the files
+    /// on disk haven't changed since it was saved.
+    fn load_resume(&mut self) -> bool {
+        let resume = match Resume::load(&self.resume_file) {
+            Ok(resume) => resume,
+            Err(_) => return false,
         };
-        let sha1 = sha1(&data);
-        let hash = Hash::from_slice(&sha1);
-        if self.pieces_hashes.get(piece) == Some(&hash) {
-            self.is_piece_downloaded[piece] = true;
-            self.is_block_downloaded[piece] = vec![true; self.get_block_count(piece)];
-            if self.is_complete() {
-                println!("client: torrent download is complete");
+        if !resume.matches(self) {
+            println!("torrent: resume data is stale, verifying pieces");
+            return false;
+        }
+
+        println!("torrent: resuming from {}", self.resume_file.display());
+        for piece in 0..self.no_of_pieces {
+            if resume.pieces[piece] {
+                self.is_piece_downloaded[piece] = true;
+                let blocks = vec![true; self.get_block_count(piece)];
+                self.set_blocks_downloaded(piece, blocks);
             }
-            // send Have messages when a piece is verified
-            for peer in self.peers.values_mut() {
-                peer.send_have(piece);
+        }
+        for (piece, blocks) in resume.partial {
+            if !self.is_piece_downloaded[piece] {
+                self.set_blocks_downloaded(piece, blocks);
+                // The piece was still being written or waiting for its v2 hashes
+                if self.get_completed_block_count(piece) == self.get_block_count(piece) {
+                    self.verify_piece(piece);
+                }
             }
-        } else {
-            self.is_piece_downloaded[piece] = false;
-            self.is_block_downloaded[piece] = vec![false; self.get_block_count(piece)];
         }
+        self.known_peers = resume.peers;
+        true
     }
 
-    pub fn is_block_requested(&self, piece: usize, block: usize) -> bool {
-        for peer in self.peers.values() {
+    /// Writes the fast-resume file for the current download state.
+    pub fn save_resume(&self) {
+        if let Err(err) = self.storage.flush() {
+            println!("torrent: error while flushing the storage {}", err);
+        }
+        match Resume::from_torrent(self).save(&self.resume_file) {
+            Ok(_) => println!("torrent: saved resume data to {}", self.resume_file.display()),
+            Err(err) => println!("torrent: error while saving resume data {:?}", err),
+        }
+    }
+
+    /// Keeps a block in the piece's write buffer. Once all the blocks of the
+    /// piece are in, returns its data along with the result of its verification.
+    pub fn buffer_block(&mut self, piece: usize, block: usize, data: Vec<u8>) -> Option<(Vec<u8>, Option<bool>)> {
+        // Duplicate blocks arrive in endgame mode
+        if self.is_block_downloaded[piece][block] {
+            return None;
+        }
+        let size = self.get_block_size(piece, block);
+        if data.len() != size {
+            println!("torrent: invalid size {} for block {} of piece {}", data.len(), block, piece);
+            return None;
+        }
+
+        // Cancel the block from the other peers it was requested from
+        let mut cancelled = 0;
+        for peer in self.peers.values_mut() {
             if peer.is_block_requested[piece][block] {
-                return true;
+                peer.send_cancel(piece, block * BLOCK_SIZE, size);
+                cancelled += 1;
             }
         }
-        false
+        if self.block_requests[piece][block] == 0 {
+            self.active_blocks[piece] += 1;
+        }
+        self.block_requests[piece][block] -= cmp::min(cancelled, self.block_requests[piece][block]);
+
+        let block_count = self.get_block_count(piece);
+        if !self.write_buffers.contains_key(&piece) {
+            // The blocks of a piece resumed or flushed part way are on disk
+            let mut buffer = PieceBuffer::new(block_count);
+            for saved in (0..block_count).filter(|&b| self.is_block_downloaded[piece][b]) {
+                match self.read_block(piece, saved * BLOCK_SIZE, self.get_block_size(piece, saved)) {
+                    Ok(data) => buffer.add(saved, data),
+                    Err(err) => {
+                        println!("torrent: error while reading the blocks of piece {} {}", piece, err);
+                        self.finish_piece(piece, Some(false));
+                        return None;
+                    },
+                }
+            }
+            self.write_buffers.insert(piece, buffer);
+        }
+        self.is_block_downloaded[piece][block] = true;
+
+        let complete = {
+            let buffer = self.write_buffers.get_mut(&piece).unwrap();
+            buffer.add(block, data);
+            buffer.is_complete()
+        };
+        if !complete {
+            return None;
+        }
+        let (data, hash) = self.write_buffers.remove(&piece).unwrap().finish();
+        let hash = if self.pieces_hashes.is_empty() { None } else { Some(hash) };
+        let verified = self.check_data(piece, hash, Some(&data));
+        Some((data, verified))
     }
 
-    pub fn is_complete(&self) -> bool {
-        self.get_completed_piece_count() == self.no_of_pieces
+    /// Buffers a block and writes its piece to the storage on this thread once complete.
+    pub fn write_block(&mut self, piece: usize, block: usize, data: Vec<u8>) {
+        if let Some((data, verified)) = self.buffer_block(piece, block, data) {
+            if verified != Some(false) {
+                let result = self.storage.write_block(piece * self.piece_size, &data);
+                if let Err(err) = result {
+                    println!("torrent: error occured while writing piece {} {}", piece, err);
+                    self.finish_piece(piece, Some(false));
+                    return;
+                }
+            }
+            self.finish_piece(piece, verified);
+        }
     }
 
-    fn read_piece(&self, piece: usize) -> io::Result<Vec<u8>> {
+    /// Whether some blocks of the piece are only in its write buffer.
+    pub fn is_piece_buffered(&self, piece: usize) -> bool {
+        self.write_buffers.contains_key(&piece)
+    }
+
+    /// Writes the blocks of the pieces that aren't complete yet to the storage,
+    /// so that the resume data keeps them. Blocks that can't be written are
+    /// downloaded again.
+    pub fn flush_write_buffers(&mut self) {
+        let buffers: Vec<(usize, PieceBuffer)> = self.write_buffers.drain().collect();
+        for (piece, buffer) in buffers {
+            for (block, data) in buffer.blocks.into_iter().enumerate() {
+                let data = match data {
+                    Some(data) => data,
+                    None => continue,
+                };
+                if let Err(err) = self.storage.write_block(piece * self.piece_size + block * BLOCK_SIZE, &data) {
+                    println!("torrent: error while writing block {} of piece {} {}", block, piece, err);
+                    let mut blocks = self.is_block_downloaded[piece].clone();
+                    blocks[block] = false;
+                    self.set_blocks_downloaded(piece, blocks);
+                }
+            }
+        }
+    }
+
+    fn verify_piece(&mut self, piece: usize) {
+        match self.check_piece(piece) {
+            Ok(verified) => self.finish_piece(piece, verified),
+            Err(_) => {},
+        }
+    }
+
+    /// Marks a piece as downloaded once it checked out, or to be downloaded again.
+    pub fn finish_piece(&mut self, piece: usize, verified: Option<bool>) {
+        match verified {
+            Some(true) => {
+                self.is_piece_downloaded[piece] = true;
+                let blocks = vec![true; self.get_block_count(piece)];
+                self.set_blocks_downloaded(piece, blocks);
+                if self.is_complete() {
+                    println!("client: torrent download is complete");
+                }
+                // send Have messages when a piece is verified
+                for peer in self.peers.values_mut() {
+                    peer.send_have(piece);
+                }
+            },
+            Some(false) => {
+                self.is_piece_downloaded[piece] = false;
+                let blocks = vec![false; self.get_block_count(piece)];
+                self.set_blocks_downloaded(piece, blocks);
+            },
+            None => println!("torrent: piece {} is waiting for its v2 hashes", piece),
+        }
+    }
+
+    /// Checks a piece on disk against its SHA-1 hash and, for v2 and hybrid
+    /// torrents, the merkle tree of its file.
+    fn check_piece(&self, piece: usize) -> io::Result<Option<bool>> {
         let start = piece * self.piece_size;
-        let end = cmp::min(self.get_total_size(), (piece + 1) * self.piece_size);
-        let mut data = vec![];
+        let hash = if self.pieces_hashes.is_empty() {
+            None
+        } else {
+            Some(try!(self.storage.hash_piece(start, self.get_piece_size(piece))))
+        };
+        let data = match self.merkle_file(piece) {
+            Some(file) if file.has_piece_hash(piece - file.first_piece) => Some(try!(self.read(start, start + self.get_piece_size(piece)))),
+            _ => None,
+        };
+        Ok(self.check_data(piece, hash, data.as_ref().map(|data| &data[..])))
+    }
 
-        for file in &self.files {
-            if (start < file.offset && end < file.offset) || (start > file.offset + file.length && end > file.offset + file.length) {
-                continue;
+    /// Checks the SHA-1 hash and the data of a piece with the hash schemes of
+    /// the torrent. None when neither hash is known yet.
+    fn check_data(&self, piece: usize, hash: Option<Hash>, data: Option<&[u8]>) -> Option<bool> {
+        let v1 = hash.map(|hash| self.pieces_hashes.get(piece) == Some(&hash));
+        let v2 = data.and_then(|data| self.check_piece_v2(piece, data));
+        match (v1, v2) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            _ => None,
+        }
+    }
+
+    fn merkle_file(&self, piece: usize) -> Option<&MerkleFile> {
+        self.merkle_files.iter().find(|f| piece >= f.first_piece && piece < f.first_piece + f.no_of_pieces)
+    }
+
+    fn check_piece_v2(&self, piece: usize, data: &[u8]) -> Option<bool> {
+        let file = match self.merkle_file(piece) {
+            Some(file) => file,
+            None => return None,
+        };
+        let index = piece - file.first_piece;
+        if !file.has_piece_hash(index) {
+            return None;
+        }
+        // Leave out the padding after the end of the file
+        let length = cmp::min(self.piece_size, file.length - index * self.piece_size);
+        if data.len() < length {
+            return Some(false);
+        }
+        Some(file.verify_piece(index, &data[..length], self.piece_size))
+    }
+
+    /// Hashes answering a peer's hash request, None to reject it.
+    pub fn serve_hashes(&self, request: &HashRequest) -> Option<Vec<Hash256>> {
+        self.merkle_files.iter()
+            .find(|f| f.pieces_root == request.pieces_root)
+            .and_then(|f| f.serve_hashes(request, self.piece_size))
+    }
+
+    /// Adds the piece hashes received from a peer, then verifies the pieces
+    /// of the file that were waiting for them.
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash256]) -> bool {
+        let piece_size = self.piece_size;
+        let (first_piece, no_of_pieces) = match self.merkle_files.iter_mut().find(|f| f.pieces_root == request.pieces_root) {
+            Some(file) => {
+                if !file.add_hashes(request, hashes, piece_size) {
+                    return false;
+                }
+                (file.first_piece, file.no_of_pieces)
+            },
+            None => return false,
+        };
+        for piece in first_piece..first_piece + no_of_pieces {
+            if !self.is_piece_downloaded[piece] && self.get_completed_block_count(piece) == self.get_block_count(piece) {
+                self.verify_piece(piece);
             }
+        }
+        true
+    }
+
+    /// Next hash request for the piece layers we're missing, leaving out the
+    /// requests that are already in flight.
+    pub fn next_hash_request(&self, in_flight: &[HashRequest]) -> Option<HashRequest> {
+        self.merkle_files.iter()
+            .filter_map(|f| f.next_hash_request(self.piece_size, in_flight))
+            .next()
+    }
+
+    pub fn is_block_requested(&self, piece: usize, block: usize) -> bool {
+        self.block_requests[piece][block] > 0
+    }
+
+    /// Requests a block from a peer, unless it was already asked for it.
+    pub fn request_block(&mut self, addr: &SocketAddr, piece: usize, block: usize) {
+        let size = self.get_block_size(piece, block);
+        match self.peers.get_mut(addr) {
+            Some(peer) if !peer.is_block_requested[piece][block] => peer.send_request(piece, block * BLOCK_SIZE, size),
+            _ => return,
+        }
+        if self.block_requests[piece][block] == 0 && !self.is_block_downloaded[piece][block] {
+            self.active_blocks[piece] += 1;
+        }
+        self.block_requests[piece][block] += 1;
+    }
+
+    /// Counts off the request a peer answered with the block.
+    pub fn block_received(&mut self, piece: usize, block: usize) {
+        self.remove_block_request(piece, block);
+    }
+
+    /// Removes a peer along with the requests it didn't answer.
+    pub fn remove_peer(&mut self, addr: &SocketAddr) -> Option<Peer> {
+        let peer = self.peers.remove(addr);
+        if let Some(ref peer) = peer {
+            for (piece, blocks) in peer.is_block_requested.iter().enumerate() {
+                for (block, _) in blocks.iter().enumerate().filter(|&(_, &requested)| requested) {
+                    self.remove_block_request(piece, block);
+                }
+            }
+        }
+        peer
+    }
+
+    fn remove_block_request(&mut self, piece: usize, block: usize) {
+        if self.block_requests[piece][block] == 0 {
+            return;
+        }
+        self.block_requests[piece][block] -= 1;
+        if self.block_requests[piece][block] == 0 && !self.is_block_downloaded[piece][block] {
+            self.active_blocks[piece] -= 1;
+        }
+    }
+
+    /// Replaces the downloaded blocks of a piece and counts its active blocks again.
+    fn set_blocks_downloaded(&mut self, piece: usize, blocks: Vec<bool>) {
+        self.active_blocks[piece] = blocks.iter().zip(&self.block_requests[piece])
+            .filter(|&(&downloaded, &requests)| downloaded || requests > 0)
+            .count();
+        self.is_block_downloaded[piece] = blocks;
+    }
+
+    /// Endgame mode starts once every missing block has been requested from some peer.
+    pub fn is_endgame(&self) -> bool {
+        if self.is_wanted_complete() {
+            return false;
+        }
+        (0..self.no_of_pieces)
+            .filter(|&piece| !self.is_piece_downloaded[piece] && self.is_piece_wanted(piece))
+            .all(|piece| self.active_blocks[piece] == self.get_block_count(piece))
+    }
+
+    /// Whether some blocks of the piece are downloaded or requested but not all of it.
+    pub fn is_piece_partial(&self, piece: usize) -> bool {
+        !self.is_piece_downloaded[piece] && self.active_blocks[piece] > 0
+    }
 
-            let fstart = cmp::max(0, start as i64 - file.offset as i64) as usize;
-            let fend = cmp::min(end - file.offset, file.length);
-            let flength = fend - fstart;
-            let mut buffer = vec![0; flength];
+    pub fn is_complete(&self) -> bool {
+        self.get_completed_piece_count() == self.no_of_pieces
+    }
 
-            let mut f = try!(fs::OpenOptions::new().read(true).open(&file.path));
-            try!(f.seek(SeekFrom::Start(fstart as u64)));
-            try!(f.read_exact(&mut buffer));
+    /// Reads a block of a downloaded piece to be uploaded to a peer.
+    pub fn read_block(&self, piece: usize, begin: usize, length: usize) -> io::Result<Vec<u8>> {
+        if piece >= self.no_of_pieces || begin + length > self.get_piece_size(piece) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block is out of the piece bounds"));
+        }
+        let start = piece * self.piece_size + begin;
+        self.read(start, start + length)
+    }
 
-            data.extend_from_slice(&buffer);
+    fn read(&self, start: usize, end: usize) -> io::Result<Vec<u8>> {
+        self.storage.read_block(start, end - start)
+    }
+
+    /// Moves the downloaded files and the resume data to another directory.
+    pub fn move_storage(&mut self, dir: &Path) -> io::Result<()> {
+        try!(self.storage.move_to(dir));
+        for file in self.files.iter_mut().filter(|f| !f.pad) {
+            if let Ok(relative) = Path::new(&file.path).strip_prefix(&self.download_dir).map(|p| p.to_path_buf()) {
+                file.path = dir.join(relative).to_str().unwrap().into();
+            }
         }
+        let resume_file = dir.join(self.resume_file.file_name().unwrap());
+        let _ = fs::rename(&self.resume_file, &resume_file);
+        self.resume_file = resume_file;
+        self.download_dir = dir.to_path_buf();
+        println!("torrent: moved to {}", dir.display());
+        Ok(())
+    }
 
-        Ok(data)
+    /// Deletes the downloaded files along with the resume data.
+    pub fn delete_files(&mut self) -> io::Result<()> {
+        try!(self.storage.delete());
+        let _ = fs::remove_file(&self.resume_file);
+        self.write_buffers.clear();
+        for piece in 0..self.no_of_pieces {
+            self.is_piece_downloaded[piece] = false;
+            let blocks = vec![false; self.get_block_count(piece)];
+            self.set_blocks_downloaded(piece, blocks);
+        }
+        println!("torrent: deleted the files of {}", self.name);
+        Ok(())
     }
 
     pub fn get_block_count(&self, piece: usize) -> usize {
@@ -277,4 +946,308 @@ impl Torrent {
         self.files.iter().fold(0, |sum, ref f| sum + f.length)
     }
 
+    /// Bytes of the pieces we still have to download.
+    pub fn get_bytes_left(&self) -> usize {
+        (0..self.no_of_pieces)
+            .filter(|&piece| !self.is_piece_downloaded[piece])
+            .fold(0, |sum, piece| sum + self.get_piece_size(piece))
+    }
+
+}
+
+#[cfg(test)]
+impl Torrent {
+    /// Torrent of the info with its data kept in memory
+    pub fn from_info_in_memory(info: &BEncoding, config: &Config) -> Result<Torrent, Error> {
+        Torrent::from_info_with_storage(info, vec![], HashMap::new(), config, |files| {
+            Box::new(::storage::MemoryStorage::new(files)) as Box<dyn Storage>
+        })
+    }
+
+    /// Single file torrent of the data, none of it downloaded yet
+    pub fn in_memory(data: &[u8], piece_length: usize, config: &Config) -> Torrent {
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("length".to_string(), BEncoding::Int(data.len() as i64));
+        info.insert("piece length".to_string(), BEncoding::Int(piece_length as i64));
+        let pieces = data.chunks(piece_length).flat_map(|piece| sha1(&piece.to_vec())).collect();
+        info.insert("pieces".to_string(), BEncoding::Str(pieces));
+        Torrent::from_info_in_memory(&BEncoding::Dict(info), config).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use merkle::{block_hashes, pad_hash, root};
+
+    /// Piece layer and pieces root of a file of a v2 torrent
+    fn merkle_tree(data: &[u8], piece_size: usize) -> (Vec<Hash256>, Hash256) {
+        let blocks_per_piece = piece_size / merkle::MERKLE_BLOCK_SIZE;
+        let layer: Vec<_> = data.chunks(piece_size)
+            .map(|piece| root(&block_hashes(piece), blocks_per_piece, Hash256::default()))
+            .collect();
+        let pieces_root = root(&layer, layer.len().next_power_of_two(), pad_hash(blocks_per_piece));
+        (layer, pieces_root)
+    }
+
+    #[test]
+    fn test_unsafe_paths() {
+        for name in &["..", ".", "", "/etc", "a/../..", "..\\x"] {
+            let mut file = BTreeMap::new();
+            file.insert("length".to_string(), BEncoding::Int(10));
+            file.insert("pieces root".to_string(), BEncoding::Str(vec![1; 32]));
+            let mut node = BTreeMap::new();
+            node.insert(String::new(), BEncoding::Dict(file));
+            let mut dir = BTreeMap::new();
+            dir.insert(name.to_string(), BEncoding::Dict(node));
+            let mut file_tree = BTreeMap::new();
+            file_tree.insert("ok".to_string(), BEncoding::Dict(dir));
+            let mut info = BTreeMap::new();
+            info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+            info.insert("piece length".to_string(), BEncoding::Int(16384));
+            info.insert("meta version".to_string(), BEncoding::Int(2));
+            info.insert("file tree".to_string(), BEncoding::Dict(file_tree));
+            assert!(Torrent::from_info(&BEncoding::Dict(info), vec![], HashMap::new(), &Config::default()).is_err(), "{}", name);
+        }
+
+        let mut file = BTreeMap::new();
+        file.insert("length".to_string(), BEncoding::Int(10));
+        file.insert("path".to_string(), BEncoding::List(vec![BEncoding::Str(b"..".to_vec()), BEncoding::Str(b"x".to_vec())]));
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("files".to_string(), BEncoding::List(vec![BEncoding::Dict(file)]));
+        info.insert("piece length".to_string(), BEncoding::Int(16384));
+        info.insert("pieces".to_string(), BEncoding::Str(vec![0; 20]));
+        assert!(Torrent::from_info(&BEncoding::Dict(info), vec![], HashMap::new(), &Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_pieces() {
+        for &(length, piece_length, ref pieces) in &[(10, -16384, vec![0; 20]), (10, 0, vec![0; 20]), (10, 16384, vec![0; 19]), (10, 16384, vec![0; 40]), (10, 16384, vec![]), (-1, 16384, vec![0; 20])] {
+            let mut info = BTreeMap::new();
+            info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+            info.insert("length".to_string(), BEncoding::Int(length));
+            info.insert("piece length".to_string(), BEncoding::Int(piece_length));
+            info.insert("pieces".to_string(), BEncoding::Str(pieces.clone()));
+            assert!(Torrent::from_info_in_memory(&BEncoding::Dict(info), &Config::default()).is_err(), "{} {} {}", length, piece_length, pieces.len());
+        }
+
+        // Lengths that add up past the address space
+        for &(a, b) in &[(-1, 10), (i64::max_value(), i64::max_value())] {
+            let files = [a, b].iter().enumerate().map(|(i, &length)| {
+                let mut file = BTreeMap::new();
+                file.insert("length".to_string(), BEncoding::Int(length));
+                file.insert("path".to_string(), BEncoding::List(vec![BEncoding::Str(format!("{}", i).into_bytes())]));
+                BEncoding::Dict(file)
+            }).collect();
+            let mut info = BTreeMap::new();
+            info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+            info.insert("files".to_string(), BEncoding::List(files));
+            info.insert("piece length".to_string(), BEncoding::Int(16384));
+            info.insert("pieces".to_string(), BEncoding::Str(vec![0; 20]));
+            assert!(Torrent::from_info_in_memory(&BEncoding::Dict(info), &Config::default()).is_err(), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_v2_torrent() {
+        let temp = TempDir::new("v2");
+        let dir = temp.path();
+        let piece_size = 32768;
+        let a = vec![1; 40000];
+        let b: Vec<u8> = (0..70000).map(|i| (i % 251) as u8).collect();
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::File::create(dir.join("data").join("a.bin")).unwrap().write_all(&a).unwrap();
+        fs::File::create(dir.join("data").join("b.bin")).unwrap().write_all(&b).unwrap();
+
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = HashMap::new();
+        for &(name, ref data) in &[("a.bin", &a), ("b.bin", &b)] {
+            let (layer, pieces_root) = merkle_tree(data, piece_size);
+            let mut file = BTreeMap::new();
+            file.insert("length".to_string(), BEncoding::Int(data.len() as i64));
+            file.insert("pieces root".to_string(), BEncoding::Str(pieces_root.0.to_vec()));
+            let mut node = BTreeMap::new();
+            node.insert(String::new(), BEncoding::Dict(file));
+            file_tree.insert(name.to_string(), BEncoding::Dict(node));
+            piece_layers.insert(pieces_root, layer);
+        }
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("piece length".to_string(), BEncoding::Int(piece_size as i64));
+        info.insert("meta version".to_string(), BEncoding::Int(2));
+        info.insert("file tree".to_string(), BEncoding::Dict(file_tree));
+        let info = BEncoding::Dict(info);
+
+        let mut config = Config::default();
+        config.download_dir = dir.to_path_buf();
+        let torrent = Torrent::from_info(&info, vec![], piece_layers, &config).unwrap();
+        assert_eq!(Some(torrent.info_hash), torrent.info_hash_v2.map(|hash| hash.truncated()));
+        assert_eq!(torrent.files.len(), 3);
+        assert!(torrent.files[1].pad);
+        assert_eq!(torrent.files[2].offset, 2 * piece_size);
+        assert_eq!(torrent.no_of_pieces, 5);
+        assert!(torrent.is_complete());
+
+        // Without the piece layers the pieces wait for the hashes of a peer
+        let mut other = Torrent::from_info(&info, vec![], HashMap::new(), &config).unwrap();
+        assert!(!other.is_complete());
+        let mut in_flight = vec![];
+        while let Some(request) = other.next_hash_request(&in_flight) {
+            in_flight.push(request);
+            let hashes = torrent.serve_hashes(&request).unwrap();
+            for piece in 0..other.no_of_pieces {
+                other.is_block_downloaded[piece] = vec![true; other.get_block_count(piece)];
+            }
+            assert!(other.add_hashes(&request, &hashes));
+        }
+        assert_eq!(in_flight.len(), 2);
+        assert!(other.is_complete());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let mut torrent = Torrent::in_memory(&data, 32768, &Config::default());
+        assert!(!torrent.is_complete());
+        for piece in 0..torrent.no_of_pieces {
+            for block in 0..torrent.get_block_count(piece) {
+                let start = piece * torrent.piece_size + block * BLOCK_SIZE;
+                let end = start + torrent.get_block_size(piece, block);
+                torrent.write_block(piece, block, data[start..end].to_vec());
+            }
+        }
+        assert!(torrent.is_complete());
+        assert_eq!(torrent.read_block(1, 100, 10).unwrap(), &data[32868..32878]);
+    }
+
+    #[test]
+    fn test_endgame() {
+        use std::sync::mpsc::channel;
+        use extension::ExtendedHandshake;
+        use peer::Message;
+
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let mut torrent = Torrent::in_memory(&data, 2 * BLOCK_SIZE, &Config::default());
+
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap());
+        let (tx, rx) = channel();
+        let (tpieces, rpieces) = channel();
+        for &addr in &[a, b] {
+            let mut peer = Peer::new(addr, &torrent, ExtendedHandshake::default(), tx.clone(), tpieces.clone());
+            peer.is_handshake_received = true;
+            torrent.peers.insert(addr, peer);
+        }
+
+        torrent.request_block(&a, 0, 0);
+        assert!(torrent.is_piece_partial(0));
+        assert!(!torrent.is_endgame());
+        torrent.request_block(&a, 0, 1);
+        assert!(torrent.is_endgame());
+
+        // Every block is asked of the other seeder too
+        torrent.request_block(&b, 0, 0);
+        torrent.request_block(&b, 0, 1);
+        torrent.request_block(&b, 0, 1);
+        assert_eq!(torrent.peers[&b].no_of_blocks_requested(), 2);
+        while rx.try_recv().is_ok() {}
+
+        // The first copy cancels the request to the other seeder
+        let mut message = u32_to_byte_slice(BLOCK_SIZE as u32 + 9);
+        message.push(7);
+        message.extend_from_slice(&u32_to_byte_slice(0));
+        message.extend_from_slice(&u32_to_byte_slice(0));
+        message.extend_from_slice(&data[..BLOCK_SIZE]);
+        {
+            let peer = torrent.peers.get_mut(&a).unwrap();
+            peer.read(message);
+            peer.process_data();
+        }
+        let (piece, block, block_data) = rpieces.try_recv().unwrap();
+        torrent.block_received(piece, block);
+        assert!(torrent.buffer_block(piece, block, block_data).is_none());
+        match rx.try_recv() {
+            Ok(Message::Data(addr, data)) => {
+                assert_eq!(addr, b);
+                assert_eq!(data[4], 8);
+            },
+            _ => panic!("no cancel sent"),
+        }
+        assert!(!torrent.peers[&b].is_block_requested[0][0]);
+        assert!(!torrent.is_block_requested(0, 0));
+        assert!(torrent.is_endgame());
+
+        // The requests of disconnected peers are forgotten
+        torrent.remove_peer(&a);
+        assert!(torrent.is_block_requested(0, 1));
+        torrent.remove_peer(&b);
+        assert!(!torrent.is_block_requested(0, 1));
+        assert!(!torrent.is_endgame());
+        assert!(torrent.is_piece_partial(0));
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        // Pieces of 16384: 0 is in a.txt, 1 straddles a.txt and b.bin, 2 is in
+        // b.bin, 3 straddles b.bin and c.txt and 4 is in c.txt
+        let data: Vec<u8> = (0..70000).map(|i| (i % 251) as u8).collect();
+        let files = [("a.txt", 20000), ("b.bin", 40000), ("c.txt", 10000)].iter().map(|&(name, length)| {
+            let mut file = BTreeMap::new();
+            file.insert("length".to_string(), BEncoding::Int(length));
+            file.insert("path".to_string(), BEncoding::List(vec![BEncoding::Str(name.as_bytes().to_vec())]));
+            BEncoding::Dict(file)
+        }).collect();
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("files".to_string(), BEncoding::List(files));
+        info.insert("piece length".to_string(), BEncoding::Int(16384));
+        let pieces = data.chunks(16384).flat_map(|piece| sha1(&piece.to_vec())).collect();
+        info.insert("pieces".to_string(), BEncoding::Str(pieces));
+
+        let mut config = Config::default();
+        config.file_priorities = vec![
+            ("*".to_string(), Priority::Skip),
+            ("*.txt".to_string(), Priority::Normal),
+            ("2".to_string(), Priority::High),
+        ];
+        let mut torrent = Torrent::from_info_in_memory(&BEncoding::Dict(info), &config).unwrap();
+        assert_eq!(torrent.file_priorities, vec![Priority::Normal, Priority::Skip, Priority::High]);
+        assert_eq!(torrent.piece_priorities, vec![Priority::Normal, Priority::Normal, Priority::Skip, Priority::High, Priority::High]);
+
+        let wanted: Vec<usize> = (0..torrent.no_of_pieces).filter(|&piece| torrent.is_piece_wanted(piece)).collect();
+        for piece in wanted {
+            for block in 0..torrent.get_block_count(piece) {
+                let start = piece * torrent.piece_size + block * BLOCK_SIZE;
+                let end = start + torrent.get_block_size(piece, block);
+                torrent.write_block(piece, block, data[start..end].to_vec());
+            }
+        }
+        assert!(torrent.is_wanted_complete());
+        assert!(!torrent.is_complete());
+        assert!(!torrent.is_endgame());
+
+        assert!(torrent.set_file_priority(1, Priority::Low));
+        assert_eq!(torrent.piece_priorities[2], Priority::Low);
+        assert!(!torrent.is_wanted_complete());
+        assert!(!torrent.set_file_priority(3, Priority::High));
+    }
+
+    #[test]
+    fn test_file_priorities_of_one_file_in_a_directory() {
+        let mut file = BTreeMap::new();
+        file.insert("length".to_string(), BEncoding::Int(10));
+        file.insert("path".to_string(), BEncoding::List(vec![BEncoding::Str(b"sub".to_vec()), BEncoding::Str(b"a.txt".to_vec())]));
+        let mut info = BTreeMap::new();
+        info.insert("name".to_string(), BEncoding::Str(b"data".to_vec()));
+        info.insert("files".to_string(), BEncoding::List(vec![BEncoding::Dict(file)]));
+        info.insert("piece length".to_string(), BEncoding::Int(16384));
+        info.insert("pieces".to_string(), BEncoding::Str(vec![0; 20]));
+
+        // Paths are relative to the directory named after the torrent
+        let mut config = Config::default();
+        config.file_priorities = vec![("sub/*".to_string(), Priority::Skip)];
+        let torrent = Torrent::from_info_in_memory(&BEncoding::Dict(info), &config).unwrap();
+        assert_eq!(torrent.file_priorities, vec![Priority::Skip]);
+    }
 }
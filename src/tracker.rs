@@ -1,175 +1,1100 @@
-use std::io::Read;
+use std::io::{self, Read};
+use std::cmp;
+use std::convert::TryFrom;
 use std::fmt;
+use std::thread;
 use std::string::String;
-use std::str::FromStr;
 use std::net::{
     IpAddr,
+    Ipv6Addr,
     SocketAddr,
     ToSocketAddrs,
     UdpSocket,
 };
-use std::mem;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use hyper::client::Client;
 
 use bencoding::*;
 use utils::*;
 use error::Result;
+use config::Config;
 
-fn parse_peers(peers: &[u8]) -> Vec<SocketAddr> {
-    peers.chunks(6).filter_map(|peer| {
-        let addr = format!("{}.{}.{}.{}", peer[0], peer[1], peer[2], peer[3]);
-        let port = unsafe { mem::transmute::<[u8; 2], u16>([peer[5], peer[4]]) };
-        let ip = IpAddr::from_str(&addr).unwrap();
-        if port != 56789 { //FIXME: check both your ip and port
-            Some(SocketAddr::new(ip, port))
+fn parse_peers(peers: &[u8], listen_port: u16, ipv6: bool) -> Vec<SocketAddr> {
+    parse_compact_addrs(peers, ipv6).into_iter().filter(|addr| {
+        addr.port() != listen_port //FIXME: check both your ip and port
+    }).collect()
+}
+
+/// Default seconds between announces when the tracker doesn't say
+pub const DEFAULT_INTERVAL: u64 = 30 * 60;
+
+/// Fewest seconds between announces, whatever the tracker says
+pub const MIN_INTERVAL: u64 = 60;
+
+/// Announce events
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn name(&self) -> Option<&'static str> {
+        match *self {
+            Event::None => None,
+            Event::Started => Some("started"),
+            Event::Completed => Some("completed"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+
+    fn udp_id(&self) -> u32 {
+        match *self {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+/// Transfer totals reported to the trackers
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TransferStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// Result of a successful announce
+#[derive(Default, Clone, Debug)]
+pub struct AnnounceResponse {
+    pub interval: Option<u64>,
+    pub min_interval: Option<u64>,
+    pub peers: Vec<SocketAddr>,
+    /// Warning the tracker sent along with a successful response
+    pub warning: Option<String>,
+    /// Id the tracker wants back in our next announces, kept as bytes
+    /// since it is only echoed back
+    pub tracker_id: Option<Vec<u8>>,
+    /// Number of seeders
+    pub complete: Option<u64>,
+    /// Number of leechers
+    pub incomplete: Option<u64>,
+}
+
+impl AnnounceResponse {
+    /// Seconds until the next announce, the tracker's interval but never less
+    /// than its min interval or `MIN_INTERVAL`.
+    pub fn next_interval(&self) -> u64 {
+        let interval = cmp::max(self.interval.unwrap_or(DEFAULT_INTERVAL), self.min_interval.unwrap_or(0));
+        cmp::max(interval, MIN_INTERVAL)
+    }
+}
+
+/// Swarm counts a tracker reports for a torrent
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent
+    pub complete: u64,
+    /// Times the torrent was downloaded
+    pub downloaded: u64,
+    /// Peers still downloading
+    pub incomplete: u64,
+}
+
+/// Derives the scrape URL of an HTTP tracker from its announce URL.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let slash = announce.rfind('/')?;
+    let (base, last) = announce.split_at(slash + 1);
+    if !last.starts_with("announce") {
+        return None;
+    }
+    Some(format!("{}scrape{}", base, &last["announce".len()..]))
+}
+
+/// Parses the `files` dictionary of a scrape response, keyed by info hash.
+fn parse_scrape_files(data: Vec<u8>) -> Result<HashMap<Hash, ScrapeStats>> {
+    let root = try!(BEncoding::decode(data).ok_or(Error::DecodeError));
+    if let Ok(reason) = root.get_bytes("failure reason") {
+        return Err(::error::Error::TrackerFailure(String::from_utf8_lossy(&reason).into_owned()));
+    }
+
+    let mut files = HashMap::new();
+    for (info_hash, value) in try!(try!(root.get_dict("files")).to_binary_dict()) {
+        if info_hash.len() != 20 {
+            continue;
+        }
+        // Negative counts are taken as unknown
+        let count = |key| value.get_int(key).ok().and_then(|i| u64::try_from(i).ok()).unwrap_or(0);
+        files.insert(Hash::from_slice(&info_hash), ScrapeStats {
+            complete: count("complete"),
+            downloaded: count("downloaded"),
+            incomplete: count("incomplete"),
+        });
+    }
+    Ok(files)
+}
+
+/// State of one tracker url, for diagnosing trackers that don't answer
+#[derive(Clone, Debug)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: usize,
+    /// When the last announce finished
+    pub last_announce: Option<Instant>,
+    /// When the tracker expects the next announce
+    pub next_announce: Option<Instant>,
+    /// Error of the last announce, if it failed
+    pub last_error: Option<String>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Peers received in the last announce
+    pub peers_received: usize,
+    /// Whether an announce is in progress
+    pub updating: bool,
+}
+
+impl TrackerStatus {
+    fn new(url: &str, tier: usize) -> TrackerStatus {
+        TrackerStatus {
+            url: url.to_string(),
+            tier: tier,
+            last_announce: None,
+            next_announce: None,
+            last_error: None,
+            seeders: None,
+            leechers: None,
+            peers_received: 0,
+            updating: false,
+        }
+    }
+}
+
+impl fmt::Display for TrackerStatus {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let count = |count: Option<u64>| count.map(|c| c.to_string()).unwrap_or("-".to_string());
+        let state = if self.updating {
+            "updating".to_string()
+        } else if let Some(ref err) = self.last_error {
+            format!("error: {}", err)
+        } else if self.last_announce.is_some() {
+            "working".to_string()
         } else {
-            None
+            "not contacted".to_string()
+        };
+        try!(write!(f, "[{}] {} {} seeders {} leechers {} peers {}",
+                    self.tier, self.url, state, count(self.seeders), count(self.leechers), self.peers_received));
+        if let Some(last) = self.last_announce {
+            try!(write!(f, " last {}s ago", last.elapsed().as_secs()));
+        }
+        if let Some(next) = self.next_announce {
+            let now = Instant::now();
+            let secs = if next > now { (next - now).as_secs() } else { 0 };
+            try!(write!(f, " next in {}s", secs));
+        }
+        Ok(())
+    }
+}
+
+/// Parses the peers of an HTTP announce response, either a compact string or a
+/// list of dictionaries with `ip` and `port`.
+fn parse_peer_list(peers: &BEncoding, listen_port: u16) -> Vec<SocketAddr> {
+    if let Ok(peers) = peers.to_bytes() {
+        return parse_peers(&peers, listen_port, false);
+    }
+    let list = match peers.to_list() {
+        Ok(list) => list,
+        Err(_) => return vec![],
+    };
+    list.iter().filter_map(|peer| {
+        // Peers given by DNS name are skipped rather than resolved one by one
+        let ip = peer.get_str("ip").ok()?.parse::<IpAddr>().ok()?;
+        let port = u16::try_from(peer.get_int("port").ok()?).ok()?;
+        if port == 0 || port == listen_port {
+            return None;
         }
+        Some(SocketAddr::new(ip, port))
     }).collect()
 }
 
+/// Parses the bencoded response of an HTTP announce.
+fn parse_announce(data: Vec<u8>, listen_port: u16) -> Result<AnnounceResponse> {
+    let root = try!(BEncoding::decode(data).ok_or(Error::DecodeError));
+    if let Ok(reason) = root.get_bytes("failure reason") {
+        return Err(::error::Error::TrackerFailure(String::from_utf8_lossy(&reason).into_owned()));
+    }
+
+    let mut peers = parse_peer_list(try!(root.get_dict("peers")), listen_port);
+    if let Ok(peers6) = root.get_bytes("peers6") {
+        peers.extend(parse_peers(&peers6, listen_port, true));
+    }
+    Ok(AnnounceResponse {
+        // Negative intervals are ignored
+        interval: root.get_int("interval").ok().and_then(|i| u64::try_from(i).ok()),
+        min_interval: root.get_int("min interval").ok().and_then(|i| u64::try_from(i).ok()),
+        peers: peers,
+        warning: root.get_bytes("warning message").ok().map(|warning| String::from_utf8_lossy(&warning).into_owned()),
+        tracker_id: root.get_bytes("tracker id").ok(),
+        complete: root.get_int("complete").ok().and_then(|i| u64::try_from(i).ok()),
+        incomplete: root.get_int("incomplete").ok().and_then(|i| u64::try_from(i).ok()),
+    })
+}
+
 /// HTTP Tracker
 struct HTTPTracker {}
 
 impl HTTPTracker {
-    fn get_peers_addresses(url: &String, info_hash: &Hash) -> Result<Vec<SocketAddr>> {
-        let client = Client::new();
-        let url = format!("{tracker}?info_hash={hash}&peer_id={peer_id}&port=56789&uploaded=0&downloaded=0&left=0&event=started&compact=1",
+    fn announce(url: &String, info_hash: &Hash, event: Event, stats: &TransferStats, key: u32, tracker_id: Option<Vec<u8>>, config: &Config) -> Result<AnnounceResponse> {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(15)));
+        client.set_write_timeout(Some(Duration::from_secs(15)));
+        let url = Self::announce_url(url, info_hash, event, stats, key, tracker_id, global_ipv6(), config);
+        let mut buf = vec![];
+        let mut response = try!(client.get(&url).send());
+        try!(response.read_to_end(&mut buf));
+        parse_announce(buf, config.listen_port)
+    }
+
+    /// Url of an announce with its query.
+    fn announce_url(url: &String, info_hash: &Hash, event: Event, stats: &TransferStats, key: u32, tracker_id: Option<Vec<u8>>, ipv6: Option<Ipv6Addr>, config: &Config) -> String {
+        let mut url = format!("{tracker}{sep}info_hash={hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&left={left}&key={key:08x}&compact=1",
                     tracker = url,
+                    sep = if url.contains('?') { "&" } else { "?" },
                     hash = info_hash.url_encoded(),
-                    peer_id = MY_PEER_ID.url_encoded());
+                    peer_id = MY_PEER_ID.url_encoded(),
+                    port = config.listen_port,
+                    uploaded = stats.uploaded,
+                    downloaded = stats.downloaded,
+                    left = stats.left,
+                    key = key);
+        if let Some(name) = event.name() {
+            url.push_str(&format!("&event={}", name));
+        }
+        // Lets the tracker hand out our IPv6 address even when announcing over IPv4 (BEP 7)
+        if let Some(ip) = ipv6 {
+            url.push_str(&format!("&ipv6={}", percent_encode(ip.to_string().as_bytes())));
+        }
+        if let Some(tracker_id) = tracker_id {
+            url.push_str(&format!("&trackerid={}", percent_encode(&tracker_id)));
+        }
+        url
+    }
+
+    fn scrape(url: &String, info_hashes: &[Hash]) -> Result<HashMap<Hash, ScrapeStats>> {
+        let url = try!(scrape_url(url).ok_or(::error::Error::Tracker(format!("{} doesn't support scrape", url))));
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(15)));
+        client.set_write_timeout(Some(Duration::from_secs(15)));
+        let query = info_hashes.iter()
+            .map(|hash| format!("info_hash={}", hash.url_encoded()))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = format!("{}{}{}", url, if url.contains('?') { "&" } else { "?" }, query);
+
         let mut buf = vec![];
         let mut response = try!(client.get(&url).send());
         try!(response.read_to_end(&mut buf));
-
-        let root = try!(BEncoding::decode(buf).ok_or(Error::DecodeError));
-        let peers = try!(root.get_bytes("peers"));
-        Ok(parse_peers(&peers))
+        parse_scrape_files(buf)
     }
 }
 
-/// UDP Tracker
-struct UDPTracker {}
+/// Magic connection id of the UDP tracker connect request
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Most retransmissions of a UDP tracker request (BEP 15)
+pub const MAX_UDP_RETRANSMISSIONS: u32 = 8;
+
+/// Seconds a UDP tracker connection id can be used for
+const UDP_CONNECTION_TTL: u64 = 60;
+
+/// UDP Tracker (BEP 15)
+struct UDPTracker {
+    socket: UdpSocket,
+    ipv6: bool,
+    connection: Option<(u64, Instant)>,
+    retransmissions: u32,
+    /// Set when the request should be given up, checked while waiting
+    cancel: Option<Arc<AtomicBool>>,
+}
 
 impl UDPTracker {
     fn get_addr_from_url(url: &String) -> Result<SocketAddr> {
-        let parts: Vec<_> = url.split("/").collect();
-        let addr = parts[2];
-        let addrs: Vec<_> = try!(addr.to_socket_addrs()).collect();
-        Ok(addrs[0])
+        let host = try!(url.split("/").nth(2).ok_or(::error::Error::Tracker(format!("invalid url {}", url))));
+        let mut addrs = try!(host.to_socket_addrs());
+        addrs.next().ok_or(::error::Error::Tracker(format!("unable to resolve {}", host)))
     }
 
-    fn get_peers_addresses(url: &String, info_hash: &Hash) -> Result<Vec<SocketAddr>> {
+    fn new(url: &String, config: &Config) -> Result<UDPTracker> {
         let addr = try!(Self::get_addr_from_url(url));
-        let mut socket = try!(UdpSocket::bind("0.0.0.0:55555"));
+        let local = if addr.is_ipv6() { "::" } else { "0.0.0.0" };
+        // Only one tracker at a time gets the configured port, the others take any free one
+        let socket = match UdpSocket::bind((local, config.tracker_port)) {
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse && config.tracker_port != 0 => {
+                try!(UdpSocket::bind((local, 0)))
+            },
+            result => try!(result),
+        };
         try!(socket.connect(addr));
-
-        try!(socket.set_read_timeout(Some(Duration::from_secs(1))));
-        try!(socket.set_write_timeout(Some(Duration::from_secs(1))));
-
-        let _ = try!(Self::send_connect(&mut socket));
-        let connection_id = try!(Self::recv_connect(&mut socket));
-        let _ = try!(Self::send_announce(&mut socket, connection_id, info_hash));
-        let peers = try!(Self::recv_announce(&mut socket));
-        Ok(peers)
+        try!(socket.set_write_timeout(Some(Duration::from_secs(15))));
+        Ok(UDPTracker {
+            socket: socket,
+            ipv6: addr.is_ipv6(),
+            connection: None,
+            retransmissions: config.udp_retransmissions,
+            cancel: None,
+        })
     }
 
-    fn send_connect(socket: &mut UdpSocket) -> Result<usize> {
-        let connection_id = u64_to_byte_slice(0x41727101980);
-        let action = u32_to_byte_slice(0);
-        let transaction_id = u32_to_byte_slice(0x1337);
+    /// Sends a request until a response with its transaction id arrives,
+    /// returning the response.
+    fn transact(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; 2048];
+        for n in 0..self.retransmissions + 1 {
+            let connection_id = if action == ACTION_CONNECT {
+                UDP_PROTOCOL_ID
+            } else {
+                try!(self.connection_id())
+            };
+            let transaction_id = random() as u32;
 
-        let mut buffer = vec![];
-        buffer.extend_from_slice(&connection_id);
-        buffer.extend_from_slice(&action);
-        buffer.extend_from_slice(&transaction_id);
+            let mut request = vec![];
+            request.extend_from_slice(&u64_to_byte_slice(connection_id));
+            request.extend_from_slice(&u32_to_byte_slice(action));
+            request.extend_from_slice(&u32_to_byte_slice(transaction_id));
+            request.extend_from_slice(body);
+            try!(self.socket.send(&request));
 
-        let len = try!(socket.send(&buffer));
-        Ok(len)
+            let timeout = Duration::from_secs(15 * (1 << n));
+            let sent = Instant::now();
+            loop {
+                if self.cancel.as_ref().map_or(false, |cancel| cancel.load(Ordering::SeqCst)) {
+                    return Err(::error::Error::Tracker("request cancelled".into()));
+                }
+                let remaining = match timeout.checked_sub(sent.elapsed()) {
+                    Some(remaining) if remaining > Duration::from_millis(0) => remaining,
+                    _ => break,
+                };
+                // Wake up every second to notice a cancellation
+                try!(self.socket.set_read_timeout(Some(cmp::min(remaining, Duration::from_secs(1)))));
+                let len = match self.socket.recv(&mut buffer) {
+                    Ok(len) => len,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                                 || err.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                if len < 8 || byte_slice_to_u32(&buffer[4..8]) != transaction_id {
+                    continue;
+                }
+                match byte_slice_to_u32(&buffer[0..4]) {
+                    ACTION_ERROR => {
+                        let message = String::from_utf8_lossy(&buffer[8..len]).into_owned();
+                        return Err(::error::Error::TrackerFailure(message));
+                    },
+                    received if received == action => return Ok(buffer[..len].to_vec()),
+                    received => return Err(::error::Error::Tracker(format!("unexpected action {}", received))),
+                }
+            }
+            println!("tracker: no response to action {}, retrying", action);
+        }
+        Err(::error::Error::Tracker("tracker timed out".into()))
     }
 
-    fn recv_connect(socket: &mut UdpSocket) -> Result<u64> {
-        let mut buffer = [0; 1024];
-        let _ = try!(socket.recv(&mut buffer));
-        let _ = byte_slice_to_u32(&buffer[4..8]); // FIXME: validate len, transaction_id, action
-        let connection_id = byte_slice_to_u64(&buffer[8..16]);
+    /// Returns the connection id, connecting again once it expired.
+    fn connection_id(&mut self) -> Result<u64> {
+        if let Some((connection_id, since)) = self.connection {
+            if since.elapsed() < Duration::from_secs(UDP_CONNECTION_TTL) {
+                return Ok(connection_id);
+            }
+        }
+        let response = try!(self.transact(ACTION_CONNECT, &[]));
+        if response.len() < 16 {
+            return Err(::error::Error::Tracker("connect response is too short".into()));
+        }
+        let connection_id = byte_slice_to_u64(&response[8..16]);
+        self.connection = Some((connection_id, Instant::now()));
         Ok(connection_id)
     }
 
-    fn send_announce(socket: &mut UdpSocket, connection_id: u64, info_hash: &Hash) -> Result<usize> {
-        let connection_id = u64_to_byte_slice(connection_id);
-        let action = u32_to_byte_slice(1);
-        let transaction_id = u32_to_byte_slice(0x1337);
-        let event = u32_to_byte_slice(2);
-
-        let mut buffer = vec![];
-        buffer.extend_from_slice(&connection_id);
-        buffer.extend_from_slice(&action);
-        buffer.extend_from_slice(&transaction_id);
-        buffer.extend_from_slice(&info_hash.0);
-        buffer.extend_from_slice(&MY_PEER_ID.0);
-        buffer.extend_from_slice(&[0; 8]); // downloaded
-        buffer.extend_from_slice(&[0; 8]); // left
-        buffer.extend_from_slice(&[0; 8]); // uploaded
-        buffer.extend_from_slice(&event);
-        buffer.extend_from_slice(&[0; 8]); // ip address
-        buffer.extend_from_slice(&[0; 4]); // key
-        buffer.extend_from_slice(&[0; 4]); // num want
-        buffer.extend_from_slice(&[0; 4]); // port
+    fn announce(&mut self, info_hash: &Hash, event: Event, stats: &TransferStats, key: u32, config: &Config) -> Result<AnnounceResponse> {
+        let mut body = vec![];
+        body.extend_from_slice(&info_hash.0);
+        body.extend_from_slice(&MY_PEER_ID.0);
+        body.extend_from_slice(&u64_to_byte_slice(stats.downloaded));
+        body.extend_from_slice(&u64_to_byte_slice(stats.left));
+        body.extend_from_slice(&u64_to_byte_slice(stats.uploaded));
+        body.extend_from_slice(&u32_to_byte_slice(event.udp_id()));
+        body.extend_from_slice(&[0; 4]); // ip address, the sender's
+        body.extend_from_slice(&u32_to_byte_slice(key));
+        body.extend_from_slice(&u32_to_byte_slice(config.max_peers as u32)); // num want
+        body.extend_from_slice(&[(config.listen_port >> 8) as u8, config.listen_port as u8]);
 
-        let len = try!(socket.send(&buffer));
-        Ok(len)
+        let response = try!(self.transact(ACTION_ANNOUNCE, &body));
+        if response.len() < 20 {
+            return Err(::error::Error::Tracker("announce response is too short".into()));
+        }
+        Ok(AnnounceResponse {
+            interval: Some(byte_slice_to_u32(&response[8..12]) as u64),
+            incomplete: Some(byte_slice_to_u32(&response[12..16]) as u64),
+            complete: Some(byte_slice_to_u32(&response[16..20]) as u64),
+            // Announces over IPv6 are answered with IPv6 peers (BEP 15)
+            peers: parse_peers(&response[20..], config.listen_port, self.ipv6),
+            ..Default::default()
+        })
     }
 
-    fn recv_announce(socket: &mut UdpSocket) -> Result<Vec<SocketAddr>> {
-        let mut buffer = vec![0; 1024];
-        let len = try!(socket.recv(&mut buffer));
-        Ok(parse_peers(&buffer[20..len]))
+    fn scrape(&mut self, info_hashes: &[Hash]) -> Result<HashMap<Hash, ScrapeStats>> {
+        let mut files = HashMap::new();
+        // A scrape packet holds at most 74 hashes
+        for chunk in info_hashes.chunks(74) {
+            let mut body = vec![];
+            for info_hash in chunk {
+                body.extend_from_slice(&info_hash.0);
+            }
+            let response = try!(self.transact(ACTION_SCRAPE, &body));
+            for (info_hash, stats) in chunk.iter().zip(response[8..].chunks(12)) {
+                if stats.len() < 12 {
+                    break;
+                }
+                files.insert(*info_hash, ScrapeStats {
+                    complete: byte_slice_to_u32(&stats[0..4]) as u64,
+                    downloaded: byte_slice_to_u32(&stats[4..8]) as u64,
+                    incomplete: byte_slice_to_u32(&stats[8..12]) as u64,
+                });
+            }
+        }
+        Ok(files)
     }
 }
 
 #[derive(Default, Clone)]
 pub struct Tracker {
-    urls: Vec<String>,
+    /// Tiers of tracker urls, the ones that answered first within their tier (BEP 12)
+    tiers: Arc<Mutex<Vec<Vec<String>>>>,
     info_hash: Hash,
+    config: Config,
+    /// Identifies us to the trackers if our address changes
+    key: u32,
+    /// Tracker ids the HTTP trackers asked us to send back
+    tracker_ids: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Connection ids of the UDP trackers and when they were received
+    udp_connections: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+    /// Status of every tracker url
+    status: Arc<Mutex<HashMap<String, TrackerStatus>>>,
+    /// Set on shutdown so that the pending announces give up
+    stopping: Arc<AtomicBool>,
 }
 
 impl Tracker {
-    pub fn new(urls: Vec<String>, info_hash: Hash) -> Tracker {
+    /// Creates the tracker from the tiers of a torrent's `announce-list`.
+    pub fn new(mut tiers: Vec<Vec<String>>, info_hash: Hash, config: &Config) -> Tracker {
+        tiers.retain(|tier| !tier.is_empty());
+        let mut status = HashMap::new();
+        for (i, tier) in tiers.iter_mut().enumerate() {
+            shuffle(tier);
+            for url in tier.iter() {
+                status.entry(url.clone()).or_insert(TrackerStatus::new(url, i));
+            }
+        }
         Tracker {
-            urls: urls,
+            tiers: Arc::new(Mutex::new(tiers)),
             info_hash: info_hash,
+            config: config.clone(),
+            key: random() as u32,
+            tracker_ids: Arc::new(Mutex::new(HashMap::new())),
+            udp_connections: Arc::new(Mutex::new(HashMap::new())),
+            status: Arc::new(Mutex::new(status)),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Makes the pending announces give up, only the `stopped` one is still sent.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    fn is_stopping(&self, event: Event) -> bool {
+        event != Event::Stopped && self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Status of every tracker, tier by tier
+    pub fn status(&self) -> Vec<TrackerStatus> {
+        let status = self.status.lock().unwrap();
+        self.urls().iter().filter_map(|url| status.get(url).cloned()).collect()
+    }
+
+    /// Scrapes every tracker at once, regardless of tiers, and returns their status.
+    /// Announcing would register us in swarms we may not be part of.
+    pub fn probe(&self) -> Vec<TrackerStatus> {
+        let handles: Vec<_> = self.urls().into_iter().map(|url| {
+            let tracker = self.clone();
+            thread::spawn(move || {
+                let info_hash = tracker.info_hash;
+                let result = tracker.scrape_tracker(&url, &[info_hash]);
+                tracker.update_status(&url, |status| {
+                    status.last_announce = Some(Instant::now());
+                    match result {
+                        Ok(files) => {
+                            let stats = files.get(&info_hash).cloned().unwrap_or_default();
+                            status.last_error = None;
+                            status.seeders = Some(stats.complete);
+                            status.leechers = Some(stats.incomplete);
+                        },
+                        Err(err) => status.last_error = Some(format!("{:?}", err)),
+                    }
+                });
+            })
+        }).collect();
+        for handle in handles {
+            let _ = handle.join();
         }
+        self.status()
+    }
+
+    /// All tracker urls, tier by tier
+    pub fn urls(&self) -> Vec<String> {
+        self.tiers.lock().unwrap().iter().flat_map(|tier| tier.clone()).collect()
+    }
+
+    /// Runs a request on the UDP tracker of the url, reusing its connection id.
+    fn with_udp_tracker<T, F>(&self, url: &String, f: F) -> Result<T>
+        where F: FnOnce(&mut UDPTracker) -> Result<T> {
+        let mut tracker = try!(UDPTracker::new(url, &self.config));
+        tracker.connection = self.udp_connections.lock().unwrap().get(url).cloned();
+        let result = f(&mut tracker);
+        if let Some(connection) = tracker.connection {
+            self.udp_connections.lock().unwrap().insert(url.clone(), connection);
+        }
+        result
     }
 
     pub fn get_peers_addresses(&self) -> Vec<SocketAddr> {
-        for url in &self.urls {
-            let result = if url.starts_with("udp") {
-                UDPTracker::get_peers_addresses(url, &self.info_hash)
-            } else {
-                HTTPTracker::get_peers_addresses(url, &self.info_hash)
+        match self.announce(Event::Started, &TransferStats::default()) {
+            Some(response) => response.peers,
+            None => vec![],
+        }
+    }
+
+    /// Announces to the first tracker that answers, trying the tiers in order,
+    /// or to every tier at once if `announce_all_tiers` is set.
+    pub fn announce(&self, event: Event, stats: &TransferStats) -> Option<AnnounceResponse> {
+        self.announce_with(event, stats, |_| {})
+    }
+
+    /// Same as `announce`, also calling `on_response` with each response as
+    /// soon as it arrives. The result merges the responses of all the tiers.
+    pub fn announce_with<F: FnMut(&AnnounceResponse)>(&self, event: Event, stats: &TransferStats, mut on_response: F) -> Option<AnnounceResponse> {
+        let no_of_tiers = self.tiers.lock().unwrap().len();
+        if !self.config.announce_all_tiers {
+            let response = (0..no_of_tiers).filter_map(|tier| self.announce_tier(tier, event, stats)).next();
+            if let Some(ref response) = response {
+                on_response(response);
+            }
+            return response;
+        }
+
+        // A hanging tracker only holds up its own tier, the others are handed
+        // over as they answer
+        let (tx, rx) = channel();
+        for tier in 0..no_of_tiers {
+            let tracker = self.clone();
+            let stats = *stats;
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _ = tx.send(tracker.announce_tier(tier, event, &stats));
+            });
+        }
+        drop(tx);
+        let mut result: Option<AnnounceResponse> = None;
+        for response in rx {
+            let response = match response {
+                Some(response) => response,
+                None => continue,
             };
-            match result {
-                Ok(ref list) if list.len() > 0 => {
-                    return list.clone();
+            on_response(&response);
+            result = Some(match result {
+                None => response,
+                Some(mut merged) => {
+                    for peer in response.peers {
+                        if !merged.peers.contains(&peer) {
+                            merged.peers.push(peer);
+                        }
+                    }
+                    merged.interval = min_option(merged.interval, response.interval);
+                    merged.min_interval = min_option(merged.min_interval, response.min_interval);
+                    merged
                 },
-                Ok(_) => {
+            });
+        }
+        result
+    }
+
+    /// Announces to the trackers of a tier in order, moving the first one that
+    /// answers to the front of the tier.
+    fn announce_tier(&self, tier: usize, event: Event, stats: &TransferStats) -> Option<AnnounceResponse> {
+        let urls = self.tiers.lock().unwrap()[tier].clone();
+        for url in &urls {
+            if self.is_stopping(event) {
+                return None;
+            }
+            if let Ok(response) = self.announce_url(url, event, stats) {
+                let mut tiers = self.tiers.lock().unwrap();
+                if let Some(pos) = tiers[tier].iter().position(|u| u == url) {
+                    let url = tiers[tier].remove(pos);
+                    tiers[tier].insert(0, url);
+                }
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    /// Announces to one tracker url and records its status.
+    fn announce_url(&self, url: &String, event: Event, stats: &TransferStats) -> Result<AnnounceResponse> {
+        self.update_status(url, |status| status.updating = true);
+        let response = if url.starts_with("udp") {
+            self.with_udp_tracker(url, |udp| {
+                if event == Event::Stopped {
+                    // Sent once on the way out, nobody waits for the answer
+                    udp.retransmissions = 0;
+                } else {
+                    udp.cancel = Some(self.stopping.clone());
+                }
+                udp.announce(&self.info_hash, event, stats, self.key, &self.config)
+            })
+        } else {
+            let tracker_id = self.tracker_ids.lock().unwrap().get(url).cloned();
+            HTTPTracker::announce(url, &self.info_hash, event, stats, self.key, tracker_id, &self.config)
+        };
+
+        match response {
+            Ok(ref response) => {
+                if let Some(ref warning) = response.warning {
+                    println!("tracker: warning from url({}): {}", url, warning);
+                }
+                if let Some(ref tracker_id) = response.tracker_id {
+                    self.tracker_ids.lock().unwrap().insert(url.clone(), tracker_id.clone());
+                }
+                if response.peers.is_empty() && event != Event::Stopped {
                     println!("tracker: no peers found from url({})", url);
-                    continue;
-                },
-                Err(err) => {
-                    println!("tracker: error while requesting url({}): {:?}", url, err);
-                    continue;
                 }
-            };
+                self.update_status(url, |status| {
+                    let interval = response.next_interval();
+                    status.last_error = None;
+                    status.seeders = response.complete;
+                    status.leechers = response.incomplete;
+                    status.peers_received = response.peers.len();
+                    // Absurd intervals would overflow the instant, the tracker just isn't due then
+                    status.next_announce = if event == Event::Stopped {
+                        None
+                    } else {
+                        Instant::now().checked_add(Duration::from_secs(interval))
+                    };
+                });
+            },
+            Err(ref err) => {
+                let message = match *err {
+                    ::error::Error::TrackerFailure(ref reason) => {
+                        println!("tracker: url({}) refused the announce: {}", url, reason);
+                        reason.clone()
+                    },
+                    ref err => {
+                        println!("tracker: error while requesting url({}): {:?}", url, err);
+                        format!("{:?}", err)
+                    },
+                };
+                self.update_status(url, |status| {
+                    status.last_error = Some(message);
+                    status.next_announce = None;
+                });
+            },
+        }
+        self.update_status(url, |status| {
+            status.updating = false;
+            status.last_announce = Some(Instant::now());
+        });
+        response
+    }
+
+    fn update_status<F: FnOnce(&mut TrackerStatus)>(&self, url: &String, f: F) {
+        if let Some(status) = self.status.lock().unwrap().get_mut(url) {
+            f(status);
+        }
+    }
+}
+
+fn min_option(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Tracker {
+    /// Scrapes the swarm counts of our torrent.
+    pub fn scrape(&self) -> Option<ScrapeStats> {
+        let info_hash = self.info_hash;
+        self.scrape_hashes(&[info_hash]).and_then(|files| files.get(&info_hash).cloned())
+    }
+
+    /// Scrapes several torrents at once from the first tracker that answers.
+    pub fn scrape_hashes(&self, info_hashes: &[Hash]) -> Option<HashMap<Hash, ScrapeStats>> {
+        for url in &self.urls() {
+            match self.scrape_tracker(url, info_hashes) {
+                Ok(files) => return Some(files),
+                Err(err) => println!("tracker: error while scraping url({}): {:?}", url, err),
+            }
+        }
+        None
+    }
+
+    fn scrape_tracker(&self, url: &String, info_hashes: &[Hash]) -> Result<HashMap<Hash, ScrapeStats>> {
+        if url.starts_with("udp") {
+            self.with_udp_tracker(url, |udp| {
+                udp.cancel = Some(self.stopping.clone());
+                udp.scrape(info_hashes)
+            })
+        } else {
+            HTTPTracker::scrape(url, info_hashes)
         }
-        vec![]
     }
 }
 
 impl fmt::Display for Tracker {
     fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.urls)
+        write!(f, "{:?}", *self.tiers.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(scrape_url("http://example.com/announce"), Some("http://example.com/scrape".to_string()));
+        assert_eq!(scrape_url("http://example.com/x/announce.php?k=1"), Some("http://example.com/x/scrape.php?k=1".to_string()));
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_parse_scrape_files() {
+        let mut data = b"d5:filesd20:".to_vec();
+        data.extend_from_slice(&[7; 20]);
+        data.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let files = parse_scrape_files(data).unwrap();
+        assert_eq!(files[&Hash([7; 20])], ScrapeStats { complete: 5, downloaded: 50, incomplete: 10 });
+
+        // A string that looks like the files dictionary isn't mistaken for it
+        let mut data = b"d1:a12:5:filesd20:x5:filesd20:".to_vec();
+        data.extend_from_slice(&[8; 20]);
+        data.extend_from_slice(b"d8:completei1eeee");
+        let files = parse_scrape_files(data).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[&Hash([8; 20])].complete, 1);
+        assert!(parse_scrape_files(b"d1:a8:5:filesde".to_vec()).is_err());
+
+        let mut data = b"d5:filesd20:".to_vec();
+        data.extend_from_slice(&[9; 20]);
+        data.extend_from_slice(b"d8:completei-1e10:downloadedi-2e10:incompletei3eeee");
+        let files = parse_scrape_files(data).unwrap();
+        assert_eq!(files[&Hash([9; 20])], ScrapeStats { complete: 0, downloaded: 0, incomplete: 3 });
+    }
+
+    #[test]
+    fn test_parse_announce() {
+        let data = b"d8:completei3e10:incompletei4e8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eee10:tracker id3:abc15:warning message4:slowe".to_vec();
+        let response = parse_announce(data, 56789).unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(response.interval, Some(900));
+        assert_eq!(response.tracker_id, Some(b"abc".to_vec()));
+        assert_eq!(response.warning, Some("slow".to_string()));
+        assert_eq!((response.complete, response.incomplete), (Some(3), Some(4)));
+
+        let response = parse_announce(b"d8:completei-3e10:incompletei-4e5:peers0:e".to_vec(), 56789).unwrap();
+        assert_eq!((response.complete, response.incomplete), (None, None));
+
+        let mut data = b"d8:intervali900e5:peers6:".to_vec();
+        data.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        data.extend_from_slice(b"6:peers618:");
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+        data.extend_from_slice(b"e");
+        let response = parse_announce(data, 56789).unwrap();
+        assert_eq!(response.peers, vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                                        "[::1]:6881".parse::<SocketAddr>().unwrap()]);
+
+        // Out of range ports and DNS names are dropped
+        let data = b"d8:intervali900e5:peersld2:ip8:10.0.0.14:porti65537eed2:ip9:localhost4:porti6881eed2:ip3:::14:porti6882eeee".to_vec();
+        let response = parse_announce(data, 56789).unwrap();
+        assert_eq!(response.peers, vec!["[::1]:6882".parse::<SocketAddr>().unwrap()]);
+
+        match parse_announce(b"d14:failure reason9:not founde".to_vec(), 56789) {
+            Err(::error::Error::TrackerFailure(reason)) => assert_eq!(reason, "not found"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Strings that aren't UTF-8 don't bring down the announce thread
+        let data = b"d8:intervali900e5:peersld2:ip2:\xff\xfe4:porti6881eee10:tracker id2:\xff\x0015:warning message1:\xffe".to_vec();
+        let response = parse_announce(data, 56789).unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.tracker_id, Some(vec![0xff, 0]));
+        assert_eq!(response.warning, Some("\u{fffd}".to_string()));
+        match parse_announce(b"d14:failure reason2:\xff!e".to_vec(), 56789) {
+            Err(::error::Error::TrackerFailure(reason)) => assert_eq!(reason, "\u{fffd}!"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// Runs a UDP tracker that answers one connect and refuses one announce.
+    fn refusing_tracker() -> String {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", server.local_addr().unwrap());
+        ::std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            // Connect
+            let (_, from) = server.recv_from(&mut buffer).unwrap();
+            let mut response = u32_to_byte_slice(ACTION_CONNECT);
+            response.extend_from_slice(&buffer[12..16]);
+            response.extend_from_slice(&u64_to_byte_slice(42));
+            server.send_to(&response, from).unwrap();
+            // Announce
+            let (len, from) = server.recv_from(&mut buffer).unwrap();
+            assert_eq!(len, 98);
+            assert_eq!(byte_slice_to_u64(&buffer[0..8]), 42);
+            let mut response = u32_to_byte_slice(ACTION_ERROR);
+            response.extend_from_slice(&buffer[12..16]);
+            response.extend_from_slice(b"go away");
+            server.send_to(&response, from).unwrap();
+        });
+        url
+    }
+
+    /// Runs an HTTP tracker that answers every request with the body.
+    fn http_tracker(body: &'static [u8]) -> String {
+        let server = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", server.local_addr().unwrap());
+        ::std::thread::spawn(move || {
+            for stream in server.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 4096];
+                let _ = stream.read(&mut buffer);
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(body));
+            }
+        });
+        url
+    }
+
+    /// Runs a UDP tracker that answers every announce with one peer.
+    fn answering_tracker(peer: SocketAddr) -> String {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", server.local_addr().unwrap());
+        ::std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            loop {
+                let (_, from) = server.recv_from(&mut buffer).unwrap();
+                let action = byte_slice_to_u32(&buffer[8..12]);
+                let mut response = u32_to_byte_slice(action);
+                response.extend_from_slice(&buffer[12..16]);
+                if action == ACTION_CONNECT {
+                    response.extend_from_slice(&u64_to_byte_slice(42));
+                } else {
+                    response.extend_from_slice(&u32_to_byte_slice(900));
+                    response.extend_from_slice(&[0; 8]);
+                    response.extend_from_slice(&compact_addr(&peer));
+                }
+                server.send_to(&response, from).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_parse_ipv6_peers() {
+        let mut data = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1];
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1, 0x1a, 0xe2]);
+        // Our own port and a truncated entry are dropped
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xdd, 0xd5]);
+        data.extend_from_slice(&[0; 17]);
+        let peers = parse_peers(&data, 56789, true);
+        assert_eq!(peers, vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap(),
+                               "[::ffff:10.0.0.1]:6882".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(canonical_addr(peers[1]), "10.0.0.1:6882".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_canonical_addr() {
+        let mapped = "[::ffff:10.0.0.1]:6881".parse::<SocketAddr>().unwrap();
+        assert_eq!(canonical_addr(mapped), "10.0.0.1:6881".parse::<SocketAddr>().unwrap());
+        let ipv6 = "[2001:db8::1]:6881".parse::<SocketAddr>().unwrap();
+        assert_eq!(canonical_addr(ipv6), ipv6);
+        let ipv4 = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        assert_eq!(canonical_addr(ipv4), ipv4);
+    }
+
+    #[test]
+    fn test_http_announce_url() {
+        let mut config = Config::default();
+        config.listen_port = 6881;
+        let stats = TransferStats { uploaded: 1, downloaded: 2, left: 3 };
+        let url = HTTPTracker::announce_url(&"http://tracker/announce?passkey=x".to_string(), &Hash([0x41; 20]), Event::Started, &stats, 0xbeef,
+                                            Some(b"i d".to_vec()), Some("2001:db8::1".parse().unwrap()), &config);
+        let info_hash = "%41".repeat(20);
+        assert!(url.starts_with(&format!("http://tracker/announce?passkey=x&info_hash={}&peer_id=", info_hash)));
+        assert!(url.contains("&port=6881&uploaded=1&downloaded=2&left=3&key=0000beef&compact=1&event=started"));
+        assert!(url.ends_with("&ipv6=2001%3Adb8%3A%3A1&trackerid=i%20d"));
+
+        let url = HTTPTracker::announce_url(&"http://tracker/announce".to_string(), &Hash([0x41; 20]), Event::None, &stats, 1, None, None, &config);
+        assert!(url.starts_with("http://tracker/announce?info_hash="));
+        assert!(!url.contains("event=") && !url.contains("ipv6=") && !url.contains("trackerid="));
+    }
+
+    #[test]
+    fn test_next_interval() {
+        let response = AnnounceResponse { interval: Some(60), min_interval: Some(300), ..Default::default() };
+        assert_eq!(response.next_interval(), 300);
+        let response = AnnounceResponse { interval: Some(1800), min_interval: Some(300), ..Default::default() };
+        assert_eq!(response.next_interval(), 1800);
+        let response = AnnounceResponse { min_interval: Some(60), ..Default::default() };
+        assert_eq!(response.next_interval(), DEFAULT_INTERVAL);
+        let response = AnnounceResponse { interval: Some(0), ..Default::default() };
+        assert_eq!(response.next_interval(), MIN_INTERVAL);
+
+        let response = parse_announce(b"d8:intervali-1e12:min intervali-5e5:peers0:e".to_vec(), 0).unwrap();
+        assert_eq!((response.interval, response.min_interval), (None, None));
+        assert_eq!(response.next_interval(), DEFAULT_INTERVAL);
+    }
+
+    #[test]
+    fn test_udp_error_action() {
+        let url = refusing_tracker();
+        let config = Config::default();
+        let mut tracker = UDPTracker::new(&url, &config).unwrap();
+        match tracker.announce(&Hash([1; 20]), Event::Started, &TransferStats::default(), 7, &config) {
+            Err(::error::Error::TrackerFailure(message)) => assert_eq!(message, "go away"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(tracker.connection.map(|(id, _)| id), Some(42));
+    }
+
+    #[test]
+    fn test_fixed_tracker_port_is_shared() {
+        let mut config = Config::default();
+        config.tracker_port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = "udp://127.0.0.1:6969/announce".to_string();
+        let first = UDPTracker::new(&url, &config).unwrap();
+        let second = UDPTracker::new(&url, &config).unwrap();
+        assert_eq!(first.socket.local_addr().unwrap().port(), config.tracker_port);
+        assert!(second.socket.local_addr().unwrap().port() != config.tracker_port);
+    }
+
+    #[test]
+    fn test_tracker_status() {
+        let url = refusing_tracker();
+        let tracker = Tracker::new(vec![vec![url.clone()]], Hash([1; 20]), &Config::default());
+        assert!(tracker.announce(Event::Started, &TransferStats::default()).is_none());
+
+        let status = tracker.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].url, url);
+        assert_eq!(status[0].last_error, Some("go away".to_string()));
+        assert!(status[0].last_announce.is_some() && !status[0].updating);
+    }
+
+    #[test]
+    fn test_tiers_are_shuffled() {
+        let tier: Vec<String> = (0..20).map(|i| format!("http://tracker{}/announce", i)).collect();
+        let tiers = vec![tier.clone(), vec!["http://backup/announce".to_string()]];
+        let mut shuffled = false;
+        for _ in 0..5 {
+            let tracker = Tracker::new(tiers.clone(), Hash([1; 20]), &Config::default());
+            let urls = tracker.urls();
+            let mut first: Vec<String> = urls[..20].to_vec();
+            shuffled |= first != tier;
+            first.sort();
+            let mut sorted = tier.clone();
+            sorted.sort();
+            // Shuffled within the tier, the tiers keep their order
+            assert_eq!(first, sorted);
+            assert_eq!(urls[20], "http://backup/announce");
+        }
+        assert!(shuffled);
+    }
+
+    #[test]
+    fn test_huge_interval() {
+        let url = http_tracker(b"d8:intervali9223372036854775807e5:peers0:e");
+        let tracker = Tracker::new(vec![vec![url]], Hash([1; 20]), &Config::default());
+        assert!(tracker.announce(Event::Started, &TransferStats::default()).is_some());
+        let status = tracker.status();
+        assert!(status[0].last_error.is_none());
+        assert!(status[0].next_announce.is_none());
+    }
+
+    #[test]
+    fn test_answering_tracker_moves_to_front() {
+        let peer = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        let refusing = refusing_tracker();
+        let answering = answering_tracker(peer);
+        let tracker = Tracker::new(vec![], Hash([1; 20]), &Config::default());
+        *tracker.tiers.lock().unwrap() = vec![vec![refusing.clone(), answering.clone()]];
+
+        let response = tracker.announce(Event::Started, &TransferStats::default()).unwrap();
+        assert_eq!(response.peers, vec![peer]);
+        assert_eq!(tracker.urls(), vec![answering, refusing]);
+    }
+
+    #[test]
+    fn test_all_tiers_hand_over_peers_early() {
+        let peer = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("udp://{}/announce", silent.local_addr().unwrap());
+        let mut config = Config::default();
+        config.announce_all_tiers = true;
+        let tracker = Tracker::new(vec![vec![silent_url], vec![answering_tracker(peer)]], Hash([1; 20]), &config);
+
+        let (tx, rx) = channel();
+        let announcer = tracker.clone();
+        let handle = ::std::thread::spawn(move || {
+            announcer.announce_with(Event::Started, &TransferStats::default(), |response| {
+                let _ = tx.send(response.peers.clone());
+            })
+        });
+        // The answering tier doesn't wait for the silent one
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![peer]);
+        tracker.stop();
+        assert_eq!(handle.join().unwrap().unwrap().peers, vec![peer]);
+        drop(silent);
+    }
+
+    #[test]
+    fn test_stop_cancels_announce() {
+        // A tracker that never answers
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", server.local_addr().unwrap());
+        let tracker = Tracker::new(vec![vec![url]], Hash([1; 20]), &Config::default());
+        let stopper = tracker.clone();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(200));
+            stopper.stop();
+        });
+
+        let started = Instant::now();
+        assert!(tracker.announce(Event::Started, &TransferStats::default()).is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(server);
     }
 }
@@ -0,0 +1,681 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::server::{self, Request, Response, Server};
+use hyper::uri::RequestUri;
+use rustc_serialize::hex::FromHex;
+
+use bencoding::*;
+use tracker::{Event, ScrapeStats};
+use utils::*;
+
+/// Seconds peers are asked to wait between announces
+pub const ANNOUNCE_INTERVAL: u64 = 30 * 60;
+
+/// Peers are dropped from the swarm when they haven't announced for this many intervals
+const EXPIRY_INTERVALS: u32 = 2;
+
+/// Most peers returned in one announce
+const MAX_NUM_WANT: usize = 200;
+
+/// Peers returned when the announce doesn't say how many it wants
+const DEFAULT_NUM_WANT: usize = 50;
+
+/// Most torrents tracked when there is no whitelist
+const MAX_SWARMS: usize = 10000;
+
+/// Most peers of a swarm behind one IP, the ones that announced the longest ago make room
+const MAX_PEERS_PER_IP: usize = 8;
+
+/// Seconds UDP connection ids are handed out for, the ids of the current and
+/// the previous period are accepted so that they stay valid for a minute (BEP 15)
+const UDP_CONNECTION_PERIOD: u64 = 60;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+/// A peer of a swarm
+struct SwarmPeer {
+    peer_id: Hash,
+    left: u64,
+    last_seen: Instant,
+}
+
+/// Peers of one torrent
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    /// Completed downloads, since the swarm was last empty
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+        ScrapeStats {
+            complete: complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+}
+
+/// An announce, as received over HTTP or UDP
+pub struct AnnounceRequest {
+    pub info_hash: Hash,
+    pub peer_id: Hash,
+    pub addr: SocketAddr,
+    pub event: Event,
+    pub left: u64,
+    pub num_want: Option<usize>,
+}
+
+/// What the tracker answers to an announce
+pub struct AnnounceReply {
+    pub interval: u64,
+    pub stats: ScrapeStats,
+    /// Peers with their ids
+    pub peers: Vec<(SocketAddr, Hash)>,
+}
+
+/// Tracker answering announces and scrapes over HTTP and UDP (BEP 3, BEP 15)
+#[derive(Clone)]
+pub struct TrackerServer {
+    swarms: Arc<Mutex<HashMap<Hash, Swarm>>>,
+    whitelist: Option<HashSet<Hash>>,
+    interval: u64,
+}
+
+impl TrackerServer {
+    pub fn new() -> TrackerServer {
+        TrackerServer {
+            swarms: Arc::new(Mutex::new(HashMap::new())),
+            whitelist: None,
+            interval: ANNOUNCE_INTERVAL,
+        }
+    }
+
+    /// Reads a whitelist of hex encoded info hashes, one per line.
+    pub fn load_whitelist(path: &Path) -> io::Result<Vec<Hash>> {
+        let mut text = String::new();
+        try!(try!(fs::File::open(path)).read_to_string(&mut text));
+        let mut info_hashes = vec![];
+        for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            match line.from_hex() {
+                Ok(ref hash) if hash.len() == 20 => info_hashes.push(Hash::from_slice(hash)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid info hash {}", line))),
+            }
+        }
+        Ok(info_hashes)
+    }
+
+    /// Only tracks the given torrents.
+    pub fn set_whitelist(&mut self, info_hashes: Vec<Hash>) {
+        self.whitelist = Some(info_hashes.into_iter().collect());
+    }
+
+    /// Adds, updates or removes the announcing peer and picks peers for it.
+    pub fn announce(&self, request: &AnnounceRequest) -> Result<AnnounceReply, String> {
+        if let Some(ref whitelist) = self.whitelist {
+            if !whitelist.contains(&request.info_hash) {
+                return Err("torrent is not tracked".into());
+            }
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        if self.whitelist.is_none() && swarms.len() >= MAX_SWARMS && !swarms.contains_key(&request.info_hash) {
+            return Err("too many torrents".into());
+        }
+        let reply = Self::update_swarm(swarms.entry(request.info_hash).or_insert(Swarm::default()), request, self.interval);
+        if swarms[&request.info_hash].peers.is_empty() {
+            swarms.remove(&request.info_hash);
+        }
+        Ok(reply)
+    }
+
+    fn update_swarm(swarm: &mut Swarm, request: &AnnounceRequest, interval: u64) -> AnnounceReply {
+        if request.event == Event::Stopped {
+            swarm.peers.remove(&request.addr);
+        } else {
+            if request.event == Event::Completed {
+                swarm.downloaded += 1;
+            }
+            if !swarm.peers.contains_key(&request.addr) {
+                let same_ip = swarm.peers.iter().filter(|&(addr, _)| addr.ip() == request.addr.ip());
+                if same_ip.clone().count() >= MAX_PEERS_PER_IP {
+                    let oldest = same_ip.min_by_key(|&(_, peer)| peer.last_seen).map(|(addr, _)| *addr);
+                    if let Some(addr) = oldest {
+                        swarm.peers.remove(&addr);
+                    }
+                }
+            }
+            swarm.peers.insert(request.addr, SwarmPeer {
+                peer_id: request.peer_id,
+                left: request.left,
+                last_seen: Instant::now(),
+            });
+        }
+
+        // Seeders don't need other seeders
+        let is_seeder = request.left == 0;
+        let mut peers: Vec<_> = swarm.peers.iter()
+            .filter(|&(addr, peer)| *addr != request.addr && !(is_seeder && peer.left == 0))
+            .map(|(addr, peer)| (*addr, peer.peer_id))
+            .collect();
+        shuffle(&mut peers);
+        let num_want = request.num_want.unwrap_or(DEFAULT_NUM_WANT);
+        peers.truncate(::std::cmp::min(num_want, MAX_NUM_WANT));
+
+        AnnounceReply {
+            interval: interval,
+            stats: swarm.stats(),
+            peers: peers,
+        }
+    }
+
+    /// Swarm counts of the torrents, or of every tracked torrent if none is given.
+    pub fn scrape(&self, info_hashes: &[Hash]) -> Vec<(Hash, ScrapeStats)> {
+        let swarms = self.swarms.lock().unwrap();
+        if info_hashes.is_empty() {
+            return swarms.iter().map(|(hash, swarm)| (*hash, swarm.stats())).collect();
+        }
+        info_hashes.iter().map(|hash| {
+            (*hash, swarms.get(hash).map(|swarm| swarm.stats()).unwrap_or_default())
+        }).collect()
+    }
+
+    /// Drops the peers that stopped announcing.
+    pub fn expire(&self) {
+        let timeout = Duration::from_secs(self.interval) * EXPIRY_INTERVALS;
+        let mut swarms = self.swarms.lock().unwrap();
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| peer.last_seen.elapsed() < timeout);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty());
+    }
+
+    /// Serves HTTP on the TCP port and BEP 15 on the UDP port of the same number.
+    pub fn run(self, port: u16) -> io::Result<()> {
+        try!(self.bind(port)).serve()
+    }
+
+    /// Binds the TCP and UDP ports, HTTP requests are answered from now on.
+    /// With port 0 each gets a port of its own.
+    pub fn bind(self, port: u16) -> io::Result<Listening> {
+        let handler = self.clone();
+        let http = try!(Server::http(("0.0.0.0", port))
+            .and_then(|server| server.handle(move |req: Request, res: Response| handler.handle_http(req, res)))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{}", err))));
+        println!("tracker_server: listening for http on port {}", http.socket.port());
+
+        let udp = try!(UdpSocket::bind(SocketAddr::from(([0u16; 8], port)))
+                       .or_else(|_| UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))));
+        println!("tracker_server: listening for udp on port {}", try!(udp.local_addr()).port());
+
+        Ok(Listening {
+            server: self,
+            http: http,
+            udp: udp,
+        })
+    }
+
+    fn handle_http(&self, req: Request, res: Response) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+        let (path, query) = match uri.find('?') {
+            Some(pos) => (&uri[..pos], &uri[pos + 1..]),
+            None => (&uri[..], ""),
+        };
+        let params = parse_query(query);
+        let body = if path.ends_with("/announce") {
+            self.http_announce(&params, canonical_addr(req.remote_addr))
+        } else if path.ends_with("/scrape") {
+            let info_hashes: Vec<_> = params.iter()
+                .filter(|&&(ref key, ref value)| key == "info_hash" && value.len() == 20)
+                .map(|&(_, ref value)| Hash::from_slice(value))
+                .collect();
+            encode_scrape(&self.scrape(&info_hashes))
+        } else {
+            failure("unknown request")
+        };
+        if let Err(err) = res.send(&body) {
+            println!("tracker_server: error while responding to {}: {}", req.remote_addr, err);
+        }
+    }
+
+    fn http_announce(&self, params: &[(String, Vec<u8>)], remote: SocketAddr) -> Vec<u8> {
+        let param = |key: &str| params.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v.clone());
+        let number = |key: &str| param(key).and_then(|v| String::from_utf8(v).ok()).and_then(|v| v.parse::<u64>().ok());
+
+        let info_hash = match param("info_hash") {
+            Some(ref hash) if hash.len() == 20 => Hash::from_slice(hash),
+            _ => return failure("invalid info_hash"),
+        };
+        let peer_id = match param("peer_id") {
+            Some(ref id) if id.len() == 20 => Hash::from_slice(id),
+            _ => return failure("invalid peer_id"),
+        };
+        let port = match number("port") {
+            Some(port) if port > 0 && port <= 0xffff => port as u16,
+            _ => return failure("invalid port"),
+        };
+        let left = match number("left") {
+            Some(left) => left,
+            None => return failure("invalid left"),
+        };
+        let event = match param("event").as_ref().map(|v| &v[..]) {
+            Some(b"started") => Event::Started,
+            Some(b"completed") => Event::Completed,
+            Some(b"stopped") => Event::Stopped,
+            _ => Event::None,
+        };
+        let request = AnnounceRequest {
+            info_hash: info_hash,
+            peer_id: peer_id,
+            addr: SocketAddr::new(remote.ip(), port),
+            event: event,
+            left: left,
+            num_want: number("numwant").map(|n| n as usize),
+        };
+        let reply = match self.announce(&request) {
+            Ok(reply) => reply,
+            Err(reason) => return failure(&reason),
+        };
+
+        let mut root = BTreeMap::new();
+        root.insert("interval".to_string(), BEncoding::Int(reply.interval as i64));
+        root.insert("complete".to_string(), BEncoding::Int(reply.stats.complete as i64));
+        root.insert("incomplete".to_string(), BEncoding::Int(reply.stats.incomplete as i64));
+        if param("compact").as_ref().map(|v| &v[..]) == Some(b"0") {
+            let peers = reply.peers.iter().map(|&(addr, peer_id)| {
+                let mut peer = BTreeMap::new();
+                peer.insert("peer id".to_string(), BEncoding::Str(peer_id.0.to_vec()));
+                peer.insert("ip".to_string(), BEncoding::Str(addr.ip().to_string().into_bytes()));
+                peer.insert("port".to_string(), BEncoding::Int(addr.port() as i64));
+                BEncoding::Dict(peer)
+            }).collect();
+            root.insert("peers".to_string(), BEncoding::List(peers));
+        } else {
+            let (mut peers, mut peers6) = (vec![], vec![]);
+            for &(addr, _) in &reply.peers {
+                if addr.is_ipv4() {
+                    peers.extend(compact_addr(&addr));
+                } else {
+                    peers6.extend(compact_addr(&addr));
+                }
+            }
+            root.insert("peers".to_string(), BEncoding::Str(peers));
+            root.insert("peers6".to_string(), BEncoding::Str(peers6));
+        }
+        BEncoding::encode(&BEncoding::Dict(root))
+    }
+
+    fn serve_udp(&self, socket: UdpSocket) {
+        let secret = u64_to_byte_slice(random());
+        let mut buffer = [0; 2048];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(err) => {
+                    println!("tracker_server: error while receiving {}", err);
+                    continue;
+                },
+            };
+            let remote = canonical_addr(from);
+            if let Some(response) = self.handle_udp(&buffer[..len], remote, &secret) {
+                if let Err(err) = socket.send_to(&response, from) {
+                    println!("tracker_server: error while responding to {}: {}", from, err);
+                }
+            }
+        }
+    }
+
+    fn handle_udp(&self, data: &[u8], remote: SocketAddr, secret: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 16 {
+            return None;
+        }
+        let connection_id = byte_slice_to_u64(&data[0..8]);
+        let action = byte_slice_to_u32(&data[8..12]);
+        let transaction_id = &data[12..16];
+
+        let period = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) / UDP_CONNECTION_PERIOD;
+        let mut response = vec![];
+        if action == ACTION_CONNECT {
+            if connection_id != UDP_PROTOCOL_ID {
+                return None;
+            }
+            response.extend_from_slice(&u32_to_byte_slice(ACTION_CONNECT));
+            response.extend_from_slice(transaction_id);
+            response.extend_from_slice(&u64_to_byte_slice(udp_connection_id(secret, remote.ip(), period)));
+            return Some(response);
+        }
+
+        if connection_id != udp_connection_id(secret, remote.ip(), period)
+            && connection_id != udp_connection_id(secret, remote.ip(), period.saturating_sub(1)) {
+            return Some(udp_error(transaction_id, "invalid connection id"));
+        }
+        match action {
+            ACTION_ANNOUNCE if data.len() >= 98 => {
+                let num_want = byte_slice_to_u32(&data[92..96]) as i32;
+                let port = (data[96] as u16) << 8 | data[97] as u16;
+                let request = AnnounceRequest {
+                    info_hash: Hash::from_slice(&data[16..36]),
+                    peer_id: Hash::from_slice(&data[36..56]),
+                    addr: SocketAddr::new(remote.ip(), port),
+                    event: match byte_slice_to_u32(&data[80..84]) {
+                        1 => Event::Completed,
+                        2 => Event::Started,
+                        3 => Event::Stopped,
+                        _ => Event::None,
+                    },
+                    left: byte_slice_to_u64(&data[64..72]),
+                    num_want: if num_want < 0 { None } else { Some(num_want as usize) },
+                };
+                let reply = match self.announce(&request) {
+                    Ok(reply) => reply,
+                    Err(reason) => return Some(udp_error(transaction_id, &reason)),
+                };
+                response.extend_from_slice(&u32_to_byte_slice(ACTION_ANNOUNCE));
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(&u32_to_byte_slice(reply.interval as u32));
+                response.extend_from_slice(&u32_to_byte_slice(reply.stats.incomplete as u32));
+                response.extend_from_slice(&u32_to_byte_slice(reply.stats.complete as u32));
+                // Peers of the address family the announce came from (BEP 15)
+                let ipv6 = remote.is_ipv6();
+                for &(addr, _) in &reply.peers {
+                    if addr.is_ipv6() == ipv6 {
+                        response.extend(compact_addr(&addr));
+                    }
+                }
+            },
+            ACTION_SCRAPE => {
+                let info_hashes: Vec<_> = data[16..].chunks(20)
+                    .filter(|chunk| chunk.len() == 20)
+                    .take(74)
+                    .map(Hash::from_slice)
+                    .collect();
+                response.extend_from_slice(&u32_to_byte_slice(ACTION_SCRAPE));
+                response.extend_from_slice(transaction_id);
+                for (_, stats) in self.scrape(&info_hashes) {
+                    response.extend_from_slice(&u32_to_byte_slice(stats.complete as u32));
+                    response.extend_from_slice(&u32_to_byte_slice(stats.downloaded as u32));
+                    response.extend_from_slice(&u32_to_byte_slice(stats.incomplete as u32));
+                }
+            },
+            _ => return Some(udp_error(transaction_id, "invalid request")),
+        }
+        Some(response)
+    }
+}
+
+/// Tracker bound to its ports
+pub struct Listening {
+    server: TrackerServer,
+    http: server::Listening,
+    udp: UdpSocket,
+}
+
+impl Listening {
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http.socket
+    }
+
+    pub fn udp_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// Serves UDP and expires the peers, never returns.
+    pub fn serve(self) -> io::Result<()> {
+        let Listening { server, http: _http, udp } = self;
+        let handler = server.clone();
+        thread::spawn(move || handler.serve_udp(udp));
+        loop {
+            thread::sleep(Duration::from_secs(60));
+            server.expire();
+        }
+    }
+}
+
+/// Splits a query string into its percent-decoded keys and values
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query.split('&').filter(|part| !part.is_empty()).map(|part| {
+        let mut kv = part.splitn(2, '=');
+        let key = String::from_utf8_lossy(&url_decode(kv.next().unwrap_or(""))).into_owned();
+        (key, url_decode(kv.next().unwrap_or("")))
+    }).collect()
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut root = BTreeMap::new();
+    root.insert("failure reason".to_string(), BEncoding::Str(reason.as_bytes().to_vec()));
+    BEncoding::encode(&BEncoding::Dict(root))
+}
+
+fn encode_scrape(files: &[(Hash, ScrapeStats)]) -> Vec<u8> {
+    let mut dicts = BTreeMap::new();
+    for &(info_hash, stats) in files {
+        let mut dict = BTreeMap::new();
+        dict.insert("complete".to_string(), BEncoding::Int(stats.complete as i64));
+        dict.insert("downloaded".to_string(), BEncoding::Int(stats.downloaded as i64));
+        dict.insert("incomplete".to_string(), BEncoding::Int(stats.incomplete as i64));
+        dicts.insert(info_hash.0.to_vec(), BEncoding::Dict(dict));
+    }
+    let mut root = BTreeMap::new();
+    root.insert("files".to_string(), BEncoding::BinaryDict(dicts));
+    BEncoding::encode(&BEncoding::Dict(root))
+}
+
+/// Connection id of a UDP client for a period. It is derived from the address
+/// of the client so that the server keeps no state, the clients behind a NAT
+/// share it.
+fn udp_connection_id(secret: &[u8], ip: IpAddr, period: u64) -> u64 {
+    let mut data = secret.to_vec();
+    data.extend_from_slice(ip.to_string().as_bytes());
+    data.extend_from_slice(&u64_to_byte_slice(period));
+    byte_slice_to_u64(&sha1(&data)[0..8])
+}
+
+fn udp_error(transaction_id: &[u8], message: &str) -> Vec<u8> {
+    let mut response = u32_to_byte_slice(ACTION_ERROR);
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use tracker::{Tracker, TransferStats};
+
+    #[test]
+    fn test_udp_announce_and_scrape() {
+        let server = TrackerServer::new();
+        let info_hash = Hash([3; 20]);
+        let seeder = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        server.announce(&AnnounceRequest {
+            info_hash: info_hash,
+            peer_id: Hash([4; 20]),
+            addr: seeder,
+            event: Event::Started,
+            left: 0,
+            num_want: None,
+        }).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let udp = server.clone();
+        thread::spawn(move || udp.serve_udp(socket));
+
+        let tracker = Tracker::new(vec![vec![url]], info_hash, &Config::default());
+        let stats = TransferStats { uploaded: 0, downloaded: 0, left: 100 };
+        let response = tracker.announce(Event::Started, &stats).unwrap();
+        assert_eq!(response.peers, vec![seeder]);
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+
+        let stats = tracker.scrape().unwrap();
+        assert_eq!((stats.complete, stats.incomplete), (1, 1));
+    }
+
+    /// Connect packet of a UDP client
+    fn connect_request() -> Vec<u8> {
+        let mut data = u64_to_byte_slice(UDP_PROTOCOL_ID);
+        data.extend_from_slice(&u32_to_byte_slice(ACTION_CONNECT));
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn test_udp_clients_behind_nat() {
+        let server = TrackerServer::new();
+        let secret = [1; 8];
+        let first = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        let second = "10.0.0.1:6882".parse::<SocketAddr>().unwrap();
+        let first_id = server.handle_udp(&connect_request(), first, &secret).unwrap()[8..16].to_vec();
+        server.handle_udp(&connect_request(), second, &secret).unwrap();
+
+        // The second client doesn't invalidate the id of the first
+        let mut scrape = first_id.clone();
+        scrape.extend_from_slice(&u32_to_byte_slice(ACTION_SCRAPE));
+        scrape.extend_from_slice(&[0; 4]);
+        scrape.extend_from_slice(&[3; 20]);
+        for &remote in &[first, second] {
+            let response = server.handle_udp(&scrape, remote, &secret).unwrap();
+            assert_eq!(byte_slice_to_u32(&response[0..4]), ACTION_SCRAPE);
+        }
+        let response = server.handle_udp(&scrape, "10.0.0.2:6881".parse().unwrap(), &secret).unwrap();
+        assert_eq!(byte_slice_to_u32(&response[0..4]), ACTION_ERROR);
+        let response = server.handle_udp(&scrape, first, &[2; 8]).unwrap();
+        assert_eq!(byte_slice_to_u32(&response[0..4]), ACTION_ERROR);
+    }
+
+    #[test]
+    fn test_http_announce_and_scrape() {
+        let server = TrackerServer::new();
+        let info_hash = Hash([b'+'; 20]);
+        let seeder = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        server.announce(&AnnounceRequest {
+            info_hash: info_hash,
+            peer_id: Hash([4; 20]),
+            addr: seeder,
+            event: Event::Started,
+            left: 0,
+            num_want: None,
+        }).unwrap();
+
+        let listening = server.clone().bind(0).unwrap();
+        let url = format!("http://127.0.0.1:{}/announce", listening.http_addr().port());
+        thread::spawn(move || listening.serve());
+
+        let tracker = Tracker::new(vec![vec![url]], info_hash, &Config::default());
+        let stats = TransferStats { uploaded: 0, downloaded: 0, left: 100 };
+        let response = tracker.announce(Event::Started, &stats).unwrap();
+        assert_eq!(response.peers, vec![seeder]);
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+
+        let stats = tracker.scrape().unwrap();
+        assert_eq!((stats.complete, stats.incomplete), (1, 1));
+    }
+
+    #[test]
+    fn test_probe_scrapes() {
+        let server = TrackerServer::new();
+        let info_hash = Hash([5; 20]);
+        server.announce(&AnnounceRequest {
+            info_hash: info_hash,
+            peer_id: Hash([4; 20]),
+            addr: "10.0.0.1:6881".parse().unwrap(),
+            event: Event::Started,
+            left: 0,
+            num_want: None,
+        }).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let udp = server.clone();
+        thread::spawn(move || udp.serve_udp(socket));
+
+        let tracker = Tracker::new(vec![vec![url]], info_hash, &Config::default());
+        let status = tracker.probe();
+        assert!(status[0].last_error.is_none());
+        assert_eq!((status[0].seeders, status[0].leechers), (Some(1), Some(0)));
+        // We didn't join the swarm
+        assert_eq!(server.scrape(&[info_hash])[0].1, ScrapeStats { complete: 1, downloaded: 0, incomplete: 0 });
+    }
+
+    #[test]
+    fn test_swarm_limits() {
+        let server = TrackerServer::new();
+        let info_hash = Hash([6; 20]);
+        let request = |port: u16, event: Event| AnnounceRequest {
+            info_hash: info_hash,
+            peer_id: Hash([4; 20]),
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            event: event,
+            left: 100,
+            num_want: None,
+        };
+        for port in 0..(MAX_PEERS_PER_IP as u16 + 2) {
+            server.announce(&request(port, Event::Started)).unwrap();
+        }
+        let reply = server.announce(&request(0, Event::None)).unwrap();
+        assert_eq!(reply.stats.incomplete, MAX_PEERS_PER_IP as u64);
+        assert!(reply.peers.iter().all(|&(addr, _)| addr.port() >= 2));
+
+        // Empty swarms are dropped
+        for port in 0..(MAX_PEERS_PER_IP as u16 + 2) {
+            server.announce(&request(port, Event::Stopped)).unwrap();
+        }
+        assert!(server.scrape(&[]).is_empty());
+
+        for i in 0..MAX_SWARMS {
+            let mut hash = [0; 20];
+            hash[0] = (i >> 8) as u8;
+            hash[1] = i as u8;
+            server.announce(&AnnounceRequest { info_hash: Hash(hash), ..request(1, Event::Started) }).unwrap();
+        }
+        assert!(server.announce(&request(1, Event::Started)).is_err());
+    }
+
+    #[test]
+    fn test_http_announce_without_left() {
+        let server = TrackerServer::new();
+        let params = parse_query("info_hash=%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b%2b&peer_id=aaaaaaaaaaaaaaaaaaaa&port=6881");
+        let remote = "10.0.0.1:6881".parse().unwrap();
+        assert_eq!(server.http_announce(&params, remote), failure("invalid left"));
+        assert!(server.scrape(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("info_hash=%2b%2B+a&port=6881&&event");
+        assert_eq!(params, vec![("info_hash".to_string(), b"+++a".to_vec()),
+                                ("port".to_string(), b"6881".to_vec()),
+                                ("event".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let mut server = TrackerServer::new();
+        server.set_whitelist(vec![Hash([1; 20])]);
+        let request = AnnounceRequest {
+            info_hash: Hash([2; 20]),
+            peer_id: Hash([4; 20]),
+            addr: "10.0.0.1:6881".parse().unwrap(),
+            event: Event::Started,
+            left: 0,
+            num_want: None,
+        };
+        assert!(server.announce(&request).is_err());
+    }
+}
@@ -1,11 +1,15 @@
 use std::fmt;
 use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 
 use sha1;
+use sha2::{Digest, Sha256};
 use rustc_serialize::hex::ToHex;
 
 /// Contains the SHA1 hash of the decoded value.
-#[derive(Default, Clone, Copy, PartialEq)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Hash(pub [u8; 20]);
 
 impl Hash {
@@ -35,6 +39,29 @@ impl fmt::Display for Hash {
     }
 }
 
+/// SHA-256 hash identifying v2 torrents and the nodes of their merkle trees (BEP 52)
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub fn from_slice(h: &[u8]) -> Hash256 {
+        let mut bhash = [0; 32];
+        bhash.copy_from_slice(h);
+        Hash256(bhash)
+    }
+
+    /// The first 20 bytes, which stand for a v2 info hash on the wire
+    pub fn truncated(&self) -> Hash {
+        Hash::from_slice(&self.0[..20])
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
 /// Transmutes u32 to byte slice
 pub fn u32_to_byte_slice(input: u32) -> Vec<u8> {
     let data: [u8; 4] = unsafe { mem::transmute::<u32, [u8; 4]>(input.to_be()) };
@@ -70,6 +97,11 @@ pub fn sha1(data: &Vec<u8>) -> Vec<u8> {
     m.digest().bytes().to_vec()
 }
 
+/// Calculate sha256 of a slice, used by the merkle trees of v2 torrents
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
 /// Get bits from byte slice
 pub fn to_bits(list: &[u8]) -> Vec<u8> {
     fn get_bits(n: &u8) -> Vec<u8> {
@@ -103,8 +135,167 @@ pub fn from_bits(bits: &[u8]) -> Vec<u8> {
     }).collect()
 }
 
+/// Encodes the address in compact form (4 or 16 bytes of ip followed by the port)
+pub fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    data.push((addr.port() >> 8) as u8);
+    data.push(addr.port() as u8);
+    data
+}
+
+/// Decodes a list of compact IPv4 (6 bytes) or IPv6 (18 bytes) addresses
+pub fn parse_compact_addrs(data: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let size = if ipv6 { 18 } else { 6 };
+    data.chunks(size).filter(|chunk| chunk.len() == size).map(|chunk| {
+        let ip = if ipv6 {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&chunk[0..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        } else {
+            IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+        };
+        let port = (chunk[size - 2] as u16) << 8 | chunk[size - 1] as u16;
+        SocketAddr::new(ip, port)
+    }).collect()
+}
+
+/// Percent-encodes everything but the unreserved characters of a URL
+pub fn percent_encode(data: &[u8]) -> String {
+    data.iter().map(|&b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// Decodes the %XX escapes of a URL component. Bytes that aren't escaped,
+/// including `+`, are kept as they are since the binary values of announces
+/// may contain them.
+pub fn url_decode(input: &str) -> Vec<u8> {
+    fn hex(byte: u8) -> Option<u8> {
+        (byte as char).to_digit(16).map(|digit| digit as u8)
+    }
+
+    let bytes = input.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            if let (Some(high), Some(low)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                result.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    result
+}
+
+/// Directory for the files of a test, removed with them when dropped so that
+/// failing tests clean up too
+#[cfg(test)]
+pub struct TempDir(::std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = ::std::env::temp_dir().join(format!("leech-{}-{:x}", name, random()));
+        ::std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &::std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address, as accepted on a dual-stack socket, back into IPv4
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    if let IpAddr::V6(ip) = addr.ip() {
+        let octets = ip.octets();
+        if octets[0..10].iter().all(|&b| b == 0) && octets[10] == 0xff && octets[11] == 0xff {
+            let ip = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            return SocketAddr::new(IpAddr::V4(ip), addr.port());
+        }
+    }
+    addr
+}
+
+/// Our global IPv6 address, if the host has one
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    // Connecting a UDP socket picks the source address without sending anything
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Skip link-local (fe80::/10) and unique local (fc00::/7) addresses
+            if ip.is_loopback() || ip.is_unspecified() || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00 {
+                None
+            } else {
+                Some(ip)
+            }
+        },
+        IpAddr::V4(_) => None,
+    }
+}
+
+/// Random number from the per-process seeded hasher keys
+pub fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+/// Shuffles the slice in place (Fisher-Yates)
+pub fn shuffle<T>(list: &mut [T]) {
+    for i in (1..list.len()).rev() {
+        let j = (random() % (i as u64 + 1)) as usize;
+        list.swap(i, j);
+    }
+}
+
 // Peer ID used in messages (FIXME: simpler init)
 pub const MY_PEER_ID: Hash = Hash([b'3', b'1', b'4', b'1', b'5', b'9', b'2', b'6', b'5', b'3', b'5', b'8', b'9', b'7', b'9', b'3', b'2', b'3', b'8', b'5']);
 
 /// Block size of each piece (2^14)
 pub const BLOCK_SIZE: usize = 16384;
+
+/// Largest block a peer is allowed to request from us (2^17)
+pub const MAX_REQUEST_SIZE: usize = 131072;
+
+/// Matches text against a glob pattern where `*` matches any characters and `?` one character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last star match one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}